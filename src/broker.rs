@@ -0,0 +1,72 @@
+/// Represents a known STOMP server implementation, used to work around its quirks.
+///
+/// | Preset     | Lenient content-length | Durable subscription header(s)                    | Prefetch header         | Composite destinations | Subscription TTL header | Regex destinations |
+/// |------------|------------------------|---------------------------------------------------|-------------------------|------------------------|-------------------------|--------------------|
+/// | `ActiveMQ` | No                     | `activemq.subscriptionName`                       | `activemq.prefetchSize` | Yes                    | None                    | No                 |
+/// | `Artemis`  | No                     | `durable-subscription-name`                       | `consumer-window-size`  | No                     | None                    | `/regex/pattern`   |
+/// | `RabbitMQ` | No                     | `x-queue-name`, `durable:true`, `auto-delete:false` | `prefetch-count`        | No                     | `x-message-ttl`         | No                 |
+/// | `HornetQ`  | Yes                    | `durable-subscriber-name`                         | None                    | No                     | None                    | No                 |
+///
+/// ActiveMQ, Artemis & HornetQ also require a `client-id` header in the CONNECT frame for durable subscriptions.
+/// RabbitMQ only applies the subscription TTL when the subscription creates the queue, and fails it if an existing queue
+/// was declared with a different TTL. The others only expire messages by their own `expires` header, or server-side policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Broker {
+	ActiveMQ,
+	Artemis,
+	RabbitMQ,
+	HornetQ,
+}
+
+impl Broker {
+	/// Checks if frames should still be accepted when the content-length header doesn't line up with the NUL terminator.
+	pub fn lenient_content_length(&self) -> bool {
+		matches!(self, Broker::HornetQ)
+	}
+
+	/// Gets the headers to include in a SUBSCRIBE frame to make it durable under the given name.
+	pub fn durable_subscription_headers(&self, name: &str) -> Vec<(&'static str, String)> {
+		match self {
+			Broker::ActiveMQ => vec![("activemq.subscriptionName", name.to_string())],
+			Broker::Artemis => vec![("durable-subscription-name", name.to_string())],
+			Broker::RabbitMQ => vec![
+				("x-queue-name", name.to_string()),
+				("durable", "true".to_string()),
+				("auto-delete", "false".to_string()),
+			],
+			Broker::HornetQ => vec![("durable-subscriber-name", name.to_string())],
+		}
+	}
+
+	/// Checks if a single SEND frame can go to several destinations, by separating them with commas.
+	pub fn supports_composite_destinations(&self) -> bool {
+		matches!(self, Broker::ActiveMQ)
+	}
+
+	/// Gets the name of the SUBSCRIBE header that sets how many milliseconds messages live for, when they don't set their own.
+	pub fn subscription_ttl_header(&self) -> Option<&'static str> {
+		match self {
+			Broker::RabbitMQ => Some("x-message-ttl"),
+			Broker::ActiveMQ | Broker::Artemis | Broker::HornetQ => None,
+		}
+	}
+
+	/// Gets the destination that subscribes to every destination matching a regular expression, if regexes are supported.
+	pub fn regex_destination(&self, pattern: &str) -> Option<String> {
+		match self {
+			Broker::Artemis => Some(format!("/regex/{}", pattern)),
+			Broker::ActiveMQ | Broker::RabbitMQ | Broker::HornetQ => None,
+		}
+	}
+
+	/// Gets the name of the SUBSCRIBE header that limits how many unacknowledged messages are sent at once.
+	/// HornetQ has no such header, so prefetching can only be limited on the server.
+	pub fn prefetch_header(&self) -> Option<&'static str> {
+		match self {
+			Broker::ActiveMQ => Some("activemq.prefetchSize"),
+			Broker::Artemis => Some("consumer-window-size"),
+			Broker::RabbitMQ => Some("prefetch-count"),
+			Broker::HornetQ => None,
+		}
+	}
+}
@@ -0,0 +1,584 @@
+use std::{
+	io,
+	net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs},
+	sync::{mpsc::channel, Arc, Mutex},
+	thread,
+	time::{Duration, Instant},
+};
+
+use crate::{
+	broker::Broker,
+	error::StompError,
+	flow_control::FlowControl,
+	heart_beat::HeartBeat,
+	rate_limit::{RateLimit, TokenBucket},
+	receive::receive_bytes,
+	receiver::FrameReceiver,
+	serializer::{DefaultSerializer, FrameSerializer},
+	shared::{lock, Shared},
+	streaming::{BodyStreaming, SinkFactory},
+	Connection, DEFAULT_TIMEOUT,
+};
+
+/// The largest frame the STOMP server can send by default, in bytes.
+const DEFAULT_MAX_FRAME_SIZE: usize = 8 * 1024 * 1024; // 8 MiB
+
+/// The most headers a frame from the STOMP server can have by default.
+const DEFAULT_MAX_HEADERS: usize = 1024;
+
+/// The longest command or header line a frame from the STOMP server can have by default, in bytes.
+const DEFAULT_MAX_HEADER_LINE_LENGTH: usize = 64 * 1024; // 64 KiB
+
+/// Sets options on the TCP stream once it has connected, before anything is sent on it.
+pub type ConnectedSocketConfigurer = Box<dyn Fn(&ConnectedSocketOptions) -> io::Result<()> + Send>;
+
+/// The options that can still be changed on a TCP stream once it has connected.
+///
+/// Anything that has to happen before connecting, such as binding to a local address, sizing the buffers
+/// for the TCP handshake or setting the type of service, isn't possible, as the standard library only creates
+/// sockets by connecting them. Reading, writing & shutting down are left out too, as they would corrupt the connection.
+pub struct ConnectedSocketOptions<'a> {
+	tcp_stream: &'a TcpStream,
+}
+
+impl ConnectedSocketOptions<'_> {
+	/// Sets whether small writes are sent straight away, rather than being combined (Nagle's algorithm).
+	pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+		self.tcp_stream.set_nodelay(nodelay)
+	}
+
+	/// Sets the time-to-live of packets sent on this stream.
+	pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+		self.tcp_stream.set_ttl(ttl)
+	}
+
+	/// Sets how long the receive thread waits for bytes before checking if it should stop.
+	pub fn set_read_timeout(&self, read_timeout: Option<Duration>) -> io::Result<()> {
+		self.tcp_stream.set_read_timeout(read_timeout)
+	}
+
+	/// Sets how long a write can take before it fails.
+	pub fn set_write_timeout(&self, write_timeout: Option<Duration>) -> io::Result<()> {
+		self.tcp_stream.set_write_timeout(write_timeout)
+	}
+
+	/// Gets the address of the STOMP server that was connected to.
+	pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+		self.tcp_stream.peer_addr()
+	}
+
+	/// Gets the local address the operating system picked for this stream.
+	pub fn local_addr(&self) -> io::Result<SocketAddr> {
+		self.tcp_stream.local_addr()
+	}
+}
+
+/// Configures a connection to a STOMP server before it is established.
+pub struct ConnectionBuilder {
+	host: String,
+	port: u16,
+	virtual_host: Option<String>,
+	timeout: Duration,
+	read_timeout: Option<Duration>,
+	broker: Option<Broker>,
+	serializer: Box<dyn FrameSerializer>,
+	auto_nack: bool,
+	max_outstanding: Option<usize>,
+	body_streaming: Option<BodyStreaming>,
+	accept_version: String,
+	flow_control: Option<FlowControl>,
+	track_state: bool,
+	rate_limit: Option<RateLimit>,
+	heart_beat: HeartBeat,
+	max_frame_size: usize,
+	max_headers: usize,
+	max_header_line_length: usize,
+	lossy_body_utf8: bool,
+	decompress_bodies: bool,
+	strict_commands: bool,
+	allowed_commands: Vec<String>,
+	thread_name_prefix: String,
+	operation_timeout: Duration,
+	socket_configurer: Option<ConnectedSocketConfigurer>,
+	connect_deadline: Option<Duration>,
+}
+
+impl ConnectionBuilder {
+	/// Starts configuring a connection to a STOMP server.
+	pub fn new(host: &str, port: u16) -> ConnectionBuilder {
+		ConnectionBuilder {
+			host: host.to_string(),
+			port,
+			virtual_host: None,
+			timeout: DEFAULT_TIMEOUT,
+			read_timeout: None,
+			broker: None,
+			serializer: Box::new(DefaultSerializer),
+			auto_nack: false,
+			max_outstanding: None,
+			body_streaming: None,
+			accept_version: "1.2".to_string(),
+			flow_control: None,
+			track_state: false,
+			rate_limit: None,
+			heart_beat: HeartBeat::default(),
+			max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+			max_headers: DEFAULT_MAX_HEADERS,
+			max_header_line_length: DEFAULT_MAX_HEADER_LINE_LENGTH,
+			lossy_body_utf8: false,
+			decompress_bodies: true,
+			strict_commands: false,
+			allowed_commands: Vec::new(),
+			thread_name_prefix: "stomp-recv".to_string(),
+			operation_timeout: DEFAULT_TIMEOUT,
+			socket_configurer: None,
+			connect_deadline: None,
+		}
+	}
+
+	/// Sets the virtual host to ask for in the CONNECT frame, instead of the host name being connected to.
+	/// This is needed for servers that host several virtual hosts, such as RabbitMQ.
+	pub fn virtual_host(mut self, virtual_host: String) -> ConnectionBuilder {
+		self.virtual_host = Some(virtual_host);
+		self
+	}
+
+	/// Sets which protocol versions to offer in the CONNECT frame, such as `1.0,1.1,1.2`. Defaults to `1.2`.
+	/// The server picks the highest one it also supports, which then decides how frames are escaped.
+	pub fn accept_version(mut self, accept_version: &str) -> ConnectionBuilder {
+		self.accept_version = accept_version.to_string();
+		self
+	}
+
+	/// Sets how long to wait when connecting & writing.
+	pub fn timeout(mut self, timeout: Duration) -> ConnectionBuilder {
+		self.timeout = timeout;
+		self
+	}
+
+	/// Sets how long to wait for the STOMP server to reply, such as with a RECEIPT or CONNECTED frame,
+	/// when a method isn't given a timeout. This is separate to the socket timeouts.
+	pub fn operation_timeout(mut self, operation_timeout: Duration) -> ConnectionBuilder {
+		self.operation_timeout = operation_timeout;
+		self
+	}
+
+	/// Sets how long each read waits for data before checking in, rather than blocking indefinitely.
+	/// Running out of time isn't an error, the receive thread just tries again.
+	/// Once the server agrees to send heart-beats, reads wait no longer than its interval, so a silent server is noticed.
+	pub fn read_timeout(mut self, read_timeout: Duration) -> ConnectionBuilder {
+		self.read_timeout = Some(read_timeout);
+		self
+	}
+
+	/// Works around the quirks of a specific STOMP server implementation.
+	pub fn broker(mut self, broker: Broker) -> ConnectionBuilder {
+		self.broker = Some(broker);
+		self
+	}
+
+	/// Sets how outgoing frames are converted into bytes.
+	pub fn serializer(mut self, serializer: Box<dyn FrameSerializer>) -> ConnectionBuilder {
+		self.serializer = serializer;
+		self
+	}
+
+	/// Makes received messages send a NACK when dropped without being acknowledged.
+	/// This is off by default, as messages are often acknowledged later from elsewhere.
+	pub fn auto_nack(mut self, auto_nack: bool) -> ConnectionBuilder {
+		self.auto_nack = auto_nack;
+		self
+	}
+
+	/// Stops handing over messages once this many are waiting to be acknowledged, until some are.
+	/// Only messages on subscriptions using client or client-individual acknowledgement count.
+	///
+	/// The receive thread stops reading while paused, so nothing else arrives either, including receipts.
+	/// Acknowledge with `Message::ack()` rather than waiting for a receipt, such as with `send_with_receipt()`,
+	/// as that would wait for the operation timeout while at the limit.
+	pub fn max_outstanding_acknowledgements(mut self, count: usize) -> ConnectionBuilder {
+		self.max_outstanding = Some(count);
+		self
+	}
+
+	/// Stops reading from the server while too many received frames are waiting to be taken from the connection.
+	/// Without this, frames queue up in memory for as long as the application falls behind.
+	pub fn flow_control(mut self, flow_control: FlowControl) -> ConnectionBuilder {
+		self.flow_control = Some(flow_control);
+		self
+	}
+
+	/// Treats frames the server shouldn't send yet as a protocol error, stopping the connection.
+	/// Until the CONNECTED frame arrives, only CONNECTED & ERROR frames are expected, and it is only expected once.
+	pub fn track_state(mut self, track_state: bool) -> ConnectionBuilder {
+		self.track_state = track_state;
+		self
+	}
+
+	/// Limits how quickly frames are sent, such as to stop a runaway producer overwhelming the server.
+	pub fn rate_limit(mut self, rate_limit: RateLimit) -> ConnectionBuilder {
+		self.rate_limit = Some(rate_limit);
+		self
+	}
+
+	/// Sets how often to send heart-beats to the STOMP server, & how often to ask it to send them, where zero means never.
+	/// Either direction can be on by itself, such as to satisfy a server that requires them without wanting any back.
+	/// Defaults to never, and the server can make either interval slower, see `HeartBeat::negotiate`.
+	pub fn heart_beat(mut self, send: Duration, receive: Duration) -> ConnectionBuilder {
+		self.heart_beat = HeartBeat::new(send, receive);
+		self
+	}
+
+	/// Sets the largest frame the STOMP server can send, in bytes, before the connection is closed. Defaults to 8 MiB.
+	/// This stops a misbehaving server using up all our memory. Streamed bodies don't count towards it.
+	pub fn max_frame_size(mut self, max_frame_size: usize) -> ConnectionBuilder {
+		self.max_frame_size = max_frame_size;
+		self
+	}
+
+	/// Sets the most headers a frame from the STOMP server can have, before it is skipped. Defaults to 1024.
+	pub fn max_headers(mut self, max_headers: usize) -> ConnectionBuilder {
+		self.max_headers = max_headers;
+		self
+	}
+
+	/// Sets the longest command or header line a frame from the STOMP server can have, in bytes, before it is skipped.
+	/// Defaults to 64 KiB.
+	pub fn max_header_line_length(mut self, max_header_line_length: usize) -> ConnectionBuilder {
+		self.max_header_line_length = max_header_line_length;
+		self
+	}
+
+	/// Replaces invalid UTF-8 in received bodies with U+FFFD, so `Frame::body_text()` never fails.
+	/// This is off by default, but is useful for displaying or logging bodies from servers that mislabel their encoding.
+	pub fn lossy_body_utf8(mut self, lossy_body_utf8: bool) -> ConnectionBuilder {
+		self.lossy_body_utf8 = lossy_body_utf8;
+		self
+	}
+
+	/// Decompresses received bodies that have a gzip content-encoding header. This is on by default.
+	/// Turning it off leaves them compressed, such as for forwarding them on as they are.
+	pub fn decompress_bodies(mut self, decompress_bodies: bool) -> ConnectionBuilder {
+		self.decompress_bodies = decompress_bodies;
+		self
+	}
+
+	/// Skips frames from the STOMP server with commands it shouldn't send, such as from a corrupted stream.
+	/// Only CONNECTED, MESSAGE, RECEIPT & ERROR frames are accepted, along with any commands from `allow_command`.
+	pub fn strict_commands(mut self, strict_commands: bool) -> ConnectionBuilder {
+		self.strict_commands = strict_commands;
+		self
+	}
+
+	/// Accepts another command when strict, such as a vendor extension.
+	pub fn allow_command(mut self, command: &str) -> ConnectionBuilder {
+		self.allowed_commands.push(command.to_string());
+		self
+	}
+
+	/// Sets the start of the receive thread's name, which ends with the server's address. Defaults to `stomp-recv`.
+	/// The heart-beat thread, if there is one, is named this followed by `-heart-beat`.
+	/// This makes it easier to tell connections apart in thread dumps & profilers.
+	pub fn thread_name_prefix(mut self, thread_name_prefix: &str) -> ConnectionBuilder {
+		self.thread_name_prefix = thread_name_prefix.to_string();
+		self
+	}
+
+	/// Writes bodies of at least the given length to a sink as they arrive, rather than buffering them in memory.
+	/// The sink factory is called with the command & headers as soon as they arrive, then the frame is delivered
+	/// without a body once all of it has been written. Streamed bodies are written exactly as received.
+	pub fn stream_bodies(
+		mut self,
+		min_length: usize,
+		sink_factory: SinkFactory,
+	) -> ConnectionBuilder {
+		self.body_streaming = Some(BodyStreaming {
+			min_length,
+			sink_factory,
+		});
+		self
+	}
+
+	/// Sets the longest connecting can take in total, across every address the host name resolves to.
+	/// Each address is given the connect timeout, or whatever is left of this if that is less.
+	pub fn connect_deadline(mut self, connect_deadline: Duration) -> ConnectionBuilder {
+		self.connect_deadline = Some(connect_deadline);
+		self
+	}
+
+	/// Sets options on the TCP stream once it has connected, before the receive thread starts or anything is sent.
+	/// Anything set here overrides the builder, such as calling `set_nodelay(false)` or `set_read_timeout()`.
+	/// Only options that can change after connecting are available, see `ConnectedSocketOptions`, so this can't
+	/// bind to a local address or set buffer sizes. An error fails `open()`.
+	pub fn configure_connected_socket(
+		mut self,
+		socket_configurer: ConnectedSocketConfigurer,
+	) -> ConnectionBuilder {
+		self.socket_configurer = Some(socket_configurer);
+		self
+	}
+
+	/// Establishes the connection to the STOMP server.
+	pub fn open(self) -> Result<Connection, StompError> {
+		// Convert the host name & port number into usable socket addresses
+		let address = socket_address(&self.host, self.port);
+		let socket_addresses = address
+			.to_socket_addrs()
+			.ok()
+			.map(|addresses| addresses.collect::<Vec<SocketAddr>>())
+			.filter(|addresses| !addresses.is_empty())
+			.ok_or_else(|| StompError::DnsResolution {
+				address: address.clone(),
+			})?;
+
+		// Open a TCP stream to the first address that accepts us
+		let deadline = self
+			.connect_deadline
+			.map(|connect_deadline| Instant::now() + connect_deadline);
+		let tcp_stream = connect_first(socket_addresses, self.timeout, deadline)?;
+
+		// Configure this stream
+		tcp_stream.set_nodelay(true)?;
+		tcp_stream.set_write_timeout(Some(self.timeout))?;
+		tcp_stream.set_read_timeout(self.read_timeout)?;
+		if let Some(socket_configurer) = &self.socket_configurer {
+			socket_configurer(&ConnectedSocketOptions {
+				tcp_stream: &tcp_stream,
+			})?;
+		}
+
+		let (frame_sender, frame_receiver) = channel();
+
+		// Share the writing half with anything that needs to send frames
+		let tcp_stream_clone = tcp_stream.try_clone()?;
+		let raw_tcp_stream = tcp_stream.try_clone()?;
+		let mut shared = Shared::new(tcp_stream, self.serializer);
+		shared.auto_nack = self.auto_nack;
+		shared.max_outstanding = self.max_outstanding;
+		shared.flow_control = self.flow_control;
+		shared.track_state = self.track_state;
+		shared.thread_name_prefix = self.thread_name_prefix.clone();
+		shared.accept_version = self.accept_version;
+		shared.heart_beat = self.heart_beat;
+		shared.rate_limiter = self
+			.rate_limit
+			.map(|rate_limit| Mutex::new(TokenBucket::new(rate_limit, Instant::now())));
+		let shared = Arc::new(shared);
+		let mut parse_options = lock(&shared.parse_options);
+		parse_options.max_frame_size = Some(self.max_frame_size);
+		parse_options.max_headers = Some(self.max_headers);
+		parse_options.max_header_line_length = Some(self.max_header_line_length);
+		parse_options.lossy_body_utf8 = self.lossy_body_utf8;
+		parse_options.skip_decompression = !self.decompress_bodies;
+		parse_options.strict_commands = self.strict_commands;
+		parse_options.allowed_commands = self.allowed_commands;
+		drop(parse_options);
+		if let Some(broker) = self.broker {
+			lock(&shared.parse_options).lenient_content_length = broker.lenient_content_length();
+		}
+
+		// Spawn a thread to listen for incoming bytes
+		let shared_clone = shared.clone();
+		let frame_sender_clone = frame_sender.clone();
+		let thread_name = format!(
+			"{}-{}",
+			self.thread_name_prefix,
+			tcp_stream_clone.peer_addr()?
+		);
+		let receive_thread = thread::Builder::new().name(thread_name).spawn(move || {
+			let result = receive_bytes(
+				tcp_stream_clone,
+				frame_sender_clone,
+				shared_clone,
+				self.body_streaming,
+			); // Blocks until the TCP stream is closed
+
+			// Nobody is left to tell if the connection has already been dropped
+			if let Err(error) = result {
+				let _ = frame_sender.send(Err(error));
+			}
+		})?;
+
+		// Give the caller a handle to this connection
+		Ok(Connection {
+			shared: shared.clone(),
+			receive_thread: Some(receive_thread),
+			tcp_stream: raw_tcp_stream,
+			host_header: self.virtual_host.unwrap_or(self.host),
+			broker: self.broker,
+			temp_queues: Vec::new(),
+			operation_timeout: self.operation_timeout,
+			frame_receiver: FrameReceiver::new(frame_receiver, shared),
+		})
+	}
+}
+
+/// Joins a host name & port number into an address to resolve, bracketing IPv6 literals whether or not they already are.
+fn socket_address(host: &str, port: u16) -> String {
+	let literal = host.trim_start_matches('[').trim_end_matches(']');
+
+	match literal.parse::<IpAddr>() {
+		Ok(ip_address) => SocketAddr::new(ip_address, port).to_string(),
+		Err(_) => format!("{}:{}", host, port),
+	}
+}
+
+/// Opens a TCP stream to the first of the addresses that accepts us, trying each for up to the timeout.
+/// Addresses left over once the deadline has passed fail without being tried.
+fn connect_first(
+	socket_addresses: Vec<SocketAddr>,
+	timeout: Duration,
+	deadline: Option<Instant>,
+) -> Result<TcpStream, StompError> {
+	let mut connect_errors = Vec::new();
+	for socket_address in socket_addresses {
+		// Give up on the rest of the addresses once the deadline has passed
+		let timeout = match deadline {
+			Some(deadline) => timeout.min(deadline.saturating_duration_since(Instant::now())),
+			None => timeout,
+		};
+		if timeout.is_zero() {
+			connect_errors.push((
+				socket_address,
+				io::Error::new(io::ErrorKind::TimedOut, "Connect deadline passed"),
+			));
+			continue;
+		}
+
+		match TcpStream::connect_timeout(&socket_address, timeout) {
+			Ok(stream) => return Ok(stream),
+			Err(error) => connect_errors.push((socket_address, error)),
+		}
+	}
+
+	Err(StompError::AllAddressesFailed {
+		errors: connect_errors,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{io, net::TcpListener};
+
+	use super::*;
+	use crate::test_support::FakeServer;
+
+	#[test]
+	fn brackets_ipv6_literals() {
+		assert_eq!(socket_address("::1", 61613), "[::1]:61613");
+		assert_eq!(socket_address("[::1]", 61613), "[::1]:61613");
+		assert_eq!(socket_address("fe80::1", 1), "[fe80::1]:1");
+		assert_eq!(socket_address("127.0.0.1", 61613), "127.0.0.1:61613");
+		assert_eq!(socket_address("localhost", 61613), "localhost:61613");
+	}
+
+	#[test]
+	fn resolves_ipv6_literals() {
+		for host in ["::1", "[::1]"] {
+			let addresses = socket_address(host, 61613)
+				.to_socket_addrs()
+				.unwrap()
+				.collect::<Vec<SocketAddr>>();
+
+			assert_eq!(addresses, ["[::1]:61613".parse().unwrap()]);
+		}
+	}
+
+	#[test]
+	fn connects_over_ipv6() {
+		let server = FakeServer::start_ipv6(Box::new(|_| Vec::new()));
+		if server.is_none() {
+			return; // IPv6 is unavailable here
+		}
+		let server = server.unwrap();
+
+		let mut connection = ConnectionBuilder::new("::1", server.address().port())
+			.open()
+			.unwrap();
+
+		assert_eq!(
+			connection.tcp_stream_ref().peer_addr().unwrap(),
+			server.address()
+		);
+		connection.send("/queue/a", "Hello").unwrap();
+		assert_eq!(
+			server.next_frame(Duration::from_secs(1)).unwrap().command,
+			"SEND"
+		);
+	}
+
+	#[test]
+	fn falls_back_to_a_reachable_address_within_the_deadline() {
+		let closed_address = TcpListener::bind("127.0.0.1:0")
+			.unwrap()
+			.local_addr()
+			.unwrap(); // Nothing listens once it is dropped
+		let server = FakeServer::silent();
+		let server_address = server.address();
+		let started = Instant::now();
+		let deadline = started + Duration::from_secs(2);
+
+		let tcp_stream = connect_first(
+			vec![closed_address, server_address],
+			Duration::from_secs(5),
+			Some(deadline),
+		)
+		.unwrap();
+
+		assert_eq!(tcp_stream.peer_addr().unwrap(), server_address);
+		assert!(Instant::now() < deadline);
+	}
+
+	#[test]
+	fn addresses_after_the_deadline_are_not_tried() {
+		let server = FakeServer::silent();
+		let server_address = server.address();
+
+		let result = connect_first(
+			vec![server_address],
+			Duration::from_secs(5),
+			Some(Instant::now()),
+		);
+
+		assert!(
+			matches!(&result, Err(StompError::AllAddressesFailed { errors })
+				if errors.len() == 1 && errors[0].1.kind() == io::ErrorKind::TimedOut),
+			"{:?}",
+			result.err()
+		);
+	}
+
+	#[test]
+	fn configures_socket_after_connecting() {
+		let server = FakeServer::silent();
+
+		let connection = server
+			.builder()
+			.configure_connected_socket(Box::new(|socket| {
+				assert!(socket.peer_addr()?.ip().is_loopback());
+				socket.set_nodelay(false)?;
+				socket.set_ttl(42)
+			}))
+			.open()
+			.unwrap();
+
+		assert!(!connection.tcp_stream_ref().nodelay().unwrap());
+		assert_eq!(connection.tcp_stream_ref().ttl().unwrap(), 42);
+	}
+
+	#[test]
+	fn socket_configurer_error_fails_open() {
+		let server = FakeServer::silent();
+
+		let result = server
+			.builder()
+			.configure_connected_socket(Box::new(|_| Err(io::Error::other("Refused"))))
+			.open();
+
+		assert!(
+			matches!(&result, Err(StompError::Io(error)) if error.to_string() == "Refused"),
+			"{:?}",
+			result.err()
+		);
+	}
+}
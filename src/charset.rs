@@ -0,0 +1,128 @@
+use std::{borrow::Cow, str::from_utf8};
+
+use crate::error::StompError;
+
+/// The character set bodies use when the content-type header doesn't name one.
+pub const UTF_8: &str = "utf-8";
+
+/// Names that ISO-8859-1 is also known by.
+const LATIN_1_NAMES: [&str; 7] = [
+	"iso-8859-1",
+	"iso8859-1",
+	"iso_8859-1",
+	"latin1",
+	"latin-1",
+	"l1",
+	"cp819",
+];
+
+/// Names that US-ASCII is also known by.
+const ASCII_NAMES: [&str; 3] = ["us-ascii", "ascii", "iso646-us"];
+
+/// Gets the charset parameter of a content-type header value, such as `iso-8859-1` from `text/plain;charset=iso-8859-1`.
+pub fn from_content_type(content_type: &str) -> Option<&str> {
+	content_type
+		.split(';')
+		.skip(1) // The MIME type itself
+		.filter_map(|parameter| parameter.split_once('='))
+		.find(|(name, _)| name.trim().eq_ignore_ascii_case("charset"))
+		.map(|(_, value)| value.trim().trim_matches('"'))
+		.filter(|value| !value.is_empty())
+}
+
+/// Decodes text in the given character set, which can be UTF-8, ISO-8859-1 or US-ASCII under any of their usual names.
+/// Fails with the position of the first byte that the character set can't decode.
+pub fn decode<'a>(bytes: &'a [u8], charset: &str) -> Result<Cow<'a, str>, StompError> {
+	let unmappable = |position| StompError::UnmappableBody {
+		charset: charset.to_string(),
+		position,
+	};
+
+	if charset.eq_ignore_ascii_case(UTF_8) || charset.eq_ignore_ascii_case("utf8") {
+		return from_utf8(bytes)
+			.map(Cow::Borrowed)
+			.map_err(|error| unmappable(error.valid_up_to()));
+	}
+
+	if is_named(charset, &ASCII_NAMES) {
+		if let Some(position) = bytes.iter().position(|byte| !byte.is_ascii()) {
+			return Err(unmappable(position));
+		}
+
+		return Ok(Cow::Borrowed(from_utf8(bytes).unwrap())); // ASCII is always valid UTF-8
+	}
+
+	// Every byte is the code point of the same value
+	if is_named(charset, &LATIN_1_NAMES) {
+		return Ok(Cow::Owned(
+			bytes.iter().map(|&byte| char::from(byte)).collect(),
+		));
+	}
+
+	Err(StompError::UnsupportedCharset(charset.to_string()))
+}
+
+/// Checks if a character set has one of the given names, ignoring case.
+fn is_named(charset: &str, names: &[&str]) -> bool {
+	names.iter().any(|name| charset.eq_ignore_ascii_case(name))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn decodes_utf8() {
+		assert_eq!(decode("héllo".as_bytes(), "UTF-8").unwrap(), "héllo");
+		assert_eq!(decode(b"hello", "utf8").unwrap(), "hello");
+	}
+
+	#[test]
+	fn decodes_latin1() {
+		assert_eq!(decode(b"h\xe9llo \xff", "ISO-8859-1").unwrap(), "héllo ÿ");
+		assert_eq!(decode(b"\xe9", "latin1").unwrap(), "é");
+	}
+
+	#[test]
+	fn decodes_ascii() {
+		assert_eq!(decode(b"hello", "US-ASCII").unwrap(), "hello");
+	}
+
+	#[test]
+	fn rejects_unmappable_bytes() {
+		let result = decode(b"ab\xff", "ascii");
+		assert!(
+			matches!(
+				&result,
+				Err(StompError::UnmappableBody { charset, position: 2 }) if charset == "ascii"
+			),
+			"{:?}",
+			result
+		);
+
+		let result = decode(b"a\xc3(", "utf-8");
+		assert!(
+			matches!(result, Err(StompError::UnmappableBody { position: 1, .. })),
+			"{:?}",
+			result
+		);
+	}
+
+	#[test]
+	fn rejects_unsupported_charsets() {
+		assert!(matches!(
+			decode(b"hello", "shift_jis"),
+			Err(StompError::UnsupportedCharset(charset)) if charset == "shift_jis"
+		));
+	}
+
+	#[test]
+	fn finds_charset_in_content_type() {
+		assert_eq!(
+			from_content_type("text/plain; Charset=\"ISO-8859-1\""),
+			Some("ISO-8859-1")
+		);
+		assert_eq!(from_content_type("text/plain"), None);
+		assert_eq!(from_content_type("text/plain;charset="), None);
+	}
+}
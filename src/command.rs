@@ -0,0 +1,32 @@
+/// Represents a command the STOMP server can send.
+/// Frames keep their command as a string too, so vendor extensions can still be handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ServerCommand {
+	Connected,
+	Message,
+	Receipt,
+	Error,
+}
+
+impl ServerCommand {
+	/// Converts the command to how it appears in frames.
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			ServerCommand::Connected => "CONNECTED",
+			ServerCommand::Message => "MESSAGE",
+			ServerCommand::Receipt => "RECEIPT",
+			ServerCommand::Error => "ERROR",
+		}
+	}
+
+	/// Recognises a command from how it appears in frames, if it is one the specification defines.
+	pub fn from_command(command: &str) -> Option<ServerCommand> {
+		match command {
+			"CONNECTED" => Some(ServerCommand::Connected),
+			"MESSAGE" => Some(ServerCommand::Message),
+			"RECEIPT" => Some(ServerCommand::Receipt),
+			"ERROR" => Some(ServerCommand::Error),
+			_ => None,
+		}
+	}
+}
@@ -0,0 +1,46 @@
+use crate::{
+	error::StompError, frame::Frame, header::Headers, heart_beat::HeartBeat, version::StompVersion,
+};
+
+/// Represents a CONNECTED frame, which the STOMP server sends once it has accepted the connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectedInfo {
+	/// The protocol version the server agreed to.
+	pub version: StompVersion,
+
+	/// The identifier of this session, from the session header.
+	pub session: Option<String>,
+
+	/// The name & version of the server, from the server header.
+	pub server: Option<String>,
+
+	/// The heart-beat intervals the server asked for, rather than those agreed.
+	pub heart_beat: HeartBeat,
+}
+
+impl TryFrom<&Frame> for ConnectedInfo {
+	type Error = StompError;
+
+	/// Extracts the details of a CONNECTED frame.
+	fn try_from(frame: &Frame) -> Result<ConnectedInfo, StompError> {
+		if frame.command != "CONNECTED" {
+			return Err(StompError::UnexpectedCommand {
+				expected: "CONNECTED".to_string(),
+				received: frame.command.clone(),
+			});
+		}
+
+		let version = frame.get_header("version");
+
+		Ok(ConnectedInfo {
+			version: StompVersion::from_header(version).ok_or_else(|| {
+				StompError::UnknownVersion(version.unwrap_or_default().to_string())
+			})?,
+			session: frame.get_header("session").map(str::to_string),
+			server: frame.get_header("server").map(str::to_string),
+			heart_beat: frame
+				.get_header(Headers::HeartBeat.as_str())
+				.map_or(HeartBeat::default(), HeartBeat::from_header_value),
+		})
+	}
+}
@@ -0,0 +1,106 @@
+use crate::{
+	destination::Destination, error::StompError, frame::Frame, subscription::AckMode, Connection,
+};
+
+/// The subscription identifier used for dead letter queues, chosen to be unlikely to clash with any others.
+pub const DEAD_LETTER_SUBSCRIPTION_ID: u32 = u32::MAX;
+
+/// Represents where a STOMP server puts messages that could not be delivered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DlqPolicy {
+	/// ActiveMQ with an individual dead letter strategy, which uses a `DLQ.` prefixed queue for each destination.
+	ActiveMQ { original_destination: String },
+
+	/// RabbitMQ, where the dead letter exchange routes messages to a queue of your choosing.
+	RabbitMQ { queue: String },
+}
+
+impl DlqPolicy {
+	/// Gets the dead letter queue to subscribe to.
+	pub fn destination(&self) -> Destination {
+		match self {
+			DlqPolicy::ActiveMQ {
+				original_destination,
+			} => {
+				let name = original_destination
+					.trim_start_matches("/queue/")
+					.trim_start_matches("/topic/");
+
+				Destination::Queue(format!("DLQ.{}", name))
+			}
+			DlqPolicy::RabbitMQ { queue } => Destination::Queue(queue.clone()),
+		}
+	}
+
+	/// Gets the headers the server uses to say where a message came from & why it was dead lettered.
+	fn header_names(&self) -> (&'static str, &'static str) {
+		match self {
+			DlqPolicy::ActiveMQ { .. } => ("originalDestination", "dlqDeliveryFailureCause"),
+			DlqPolicy::RabbitMQ { .. } => ("x-first-death-queue", "x-first-death-reason"),
+		}
+	}
+}
+
+/// Represents a message that the STOMP server could not deliver.
+#[derive(Debug, Clone)]
+pub struct DeadLetter {
+	/// Where the message was originally sent, if the server said.
+	pub original_destination: Option<String>,
+
+	/// Why the message could not be delivered, if the server said.
+	pub reason: Option<String>,
+
+	/// The message itself.
+	pub frame: Frame,
+}
+
+/// Consumes messages from a dead letter queue.
+///
+/// Frames for other subscriptions on the same connection are skipped, so this is best used on its own connection.
+pub struct DeadLetterConsumer<'a> {
+	connection: &'a Connection,
+	policy: DlqPolicy,
+}
+
+impl<'a> DeadLetterConsumer<'a> {
+	/// Subscribes to the dead letter queue for the given policy.
+	pub fn new(
+		connection: &'a mut Connection,
+		policy: DlqPolicy,
+	) -> Result<DeadLetterConsumer<'a>, StompError> {
+		connection.subscribe_with_headers(
+			DEAD_LETTER_SUBSCRIPTION_ID,
+			policy.destination(),
+			AckMode::Auto,
+			Vec::new(),
+		)?;
+
+		Ok(DeadLetterConsumer { connection, policy })
+	}
+
+	/// Waits for the next message on the dead letter queue.
+	pub fn next_dead_letter(&self) -> Result<DeadLetter, StompError> {
+		let subscription_id = DEAD_LETTER_SUBSCRIPTION_ID.to_string();
+		let (destination_header, reason_header) = self.policy.header_names();
+
+		loop {
+			let frame = self
+				.connection
+				.frame_receiver
+				.recv()
+				.map_err(|_| StompError::ConnectionClosed)??;
+
+			// Skip anything that isn't from the dead letter queue
+			if frame.command != "MESSAGE" || frame.subscription() != Some(subscription_id.as_str())
+			{
+				continue;
+			}
+
+			return Ok(DeadLetter {
+				original_destination: frame.get_header(destination_header).map(str::to_string),
+				reason: frame.get_header(reason_header).map(str::to_string),
+				frame,
+			});
+		}
+	}
+}
@@ -0,0 +1,288 @@
+use std::fmt;
+
+/// Represents a location on the STOMP server that messages are sent to & received from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Destination {
+	/// A queue, where each message is delivered to a single subscriber. Prefixed with `/queue/`.
+	Queue(String),
+
+	/// A topic, where each message is delivered to every subscriber. Prefixed with `/topic/`.
+	Topic(String),
+
+	/// A temporary queue, which the server deletes once the connection closes. Prefixed with `/temp-queue/`.
+	TempQueue(String),
+
+	/// Any other destination, used exactly as given.
+	Custom(String),
+}
+
+impl Destination {
+	/// Converts the destination into the value of the destination header.
+	pub fn to_header_value(&self) -> String {
+		match self {
+			Destination::Queue(name) => with_prefix("/queue/", name),
+			Destination::Topic(name) => with_prefix("/topic/", name),
+			Destination::TempQueue(name) => with_prefix("/temp-queue/", name),
+			Destination::Custom(name) => name.clone(),
+		}
+	}
+}
+
+impl fmt::Display for Destination {
+	fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+		write!(formatter, "{}", self.to_header_value())
+	}
+}
+
+impl From<&str> for Destination {
+	fn from(name: &str) -> Destination {
+		Destination::Custom(name.to_string())
+	}
+}
+
+impl From<String> for Destination {
+	fn from(name: String) -> Destination {
+		Destination::Custom(name)
+	}
+}
+
+/// Represents a destination written into the code, which has been checked when compiling.
+/// Create these with the `stomp_destination!` macro.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StaticDestination(&'static str);
+
+impl StaticDestination {
+	/// Checks a destination, panicking if it is empty, doesn't start with a slash, or has a character that would break the frame.
+	/// Panicking while compiling, such as from `stomp_destination!`, is a compile error instead.
+	pub const fn new(destination: &'static str) -> StaticDestination {
+		let bytes = destination.as_bytes();
+		assert!(!bytes.is_empty(), "Destination is empty");
+		assert!(bytes[0] == b'/', "Destination doesn't start with a slash");
+
+		let mut index = 0;
+		while index < bytes.len() {
+			assert!(
+				!matches!(bytes[index], b'\r' | b'\n' | 0x00),
+				"Destination contains a line break or NUL"
+			);
+			index += 1;
+		}
+
+		StaticDestination(destination)
+	}
+
+	/// Gets the destination as it appears in the destination header.
+	pub fn as_str(&self) -> &'static str {
+		self.0
+	}
+}
+
+impl fmt::Display for StaticDestination {
+	fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+		write!(formatter, "{}", self.0)
+	}
+}
+
+impl From<StaticDestination> for Destination {
+	fn from(destination: StaticDestination) -> Destination {
+		Destination::Custom(destination.0.to_string())
+	}
+}
+
+/// Creates a `StaticDestination`, checking it when compiling rather than when it is used.
+///
+/// ```
+/// let orders = viral32111_stomp::stomp_destination!("/queue/orders");
+/// assert_eq!(orders.as_str(), "/queue/orders");
+/// ```
+///
+/// Destinations that are empty, don't start with a slash, or contain a line break or NUL fail to compile.
+///
+/// ```compile_fail
+/// let orders = viral32111_stomp::stomp_destination!("queue/orders");
+/// ```
+#[macro_export]
+macro_rules! stomp_destination {
+	($destination:literal) => {{
+		const DESTINATION: $crate::destination::StaticDestination =
+			$crate::destination::StaticDestination::new($destination);
+		DESTINATION
+	}};
+}
+
+/// Controls how a message is sent to several destinations at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FanOut {
+	/// Send a separate frame to each destination, which every server supports.
+	Separate,
+
+	/// Send one frame with every destination in the header, separated by commas.
+	/// Only ActiveMQ understands this, other servers treat it as one oddly named destination.
+	Composite,
+}
+
+/// Prepends a prefix to a name, unless it is already there.
+fn with_prefix(prefix: &str, name: &str) -> String {
+	if name.starts_with(prefix) {
+		return name.to_string();
+	}
+
+	format!("{}{}", prefix, name.trim_start_matches('/'))
+}
+
+/// Checks a regular expression for the mistakes that stop it being parsed at all, such as unbalanced brackets,
+/// a trailing backslash, or a repetition with nothing to repeat. This doesn't check every part of the syntax.
+pub fn is_valid_regex(pattern: &str) -> bool {
+	let mut open_groups = 0;
+	let mut can_repeat = false; // There is something before a repetition for it to repeat
+	let mut characters = pattern.chars().peekable();
+
+	while let Some(character) = characters.next() {
+		match character {
+			'\\' => {
+				if characters.next().is_none() {
+					return false; // Nothing to escape
+				}
+				can_repeat = true;
+			}
+			'(' => {
+				open_groups += 1;
+				can_repeat = false;
+
+				// Skip past the start of a non-capturing group, or flags
+				if characters.peek() == Some(&'?') {
+					characters.next();
+				}
+			}
+			')' => {
+				if open_groups == 0 {
+					return false;
+				}
+				open_groups -= 1;
+				can_repeat = true;
+			}
+			'[' => {
+				// A closing bracket straight away is part of the class, rather than ending it
+				if characters.peek() == Some(&'^') {
+					characters.next();
+				}
+				if characters.peek() == Some(&']') {
+					characters.next();
+				}
+
+				loop {
+					match characters.next() {
+						None => return false, // Never closed
+						Some('\\') if characters.next().is_none() => return false,
+						Some(']') => break,
+						_ => {}
+					}
+				}
+				can_repeat = true;
+			}
+			'*' | '+' | '?' | '{' => {
+				if !can_repeat {
+					return false;
+				}
+
+				// Counted repetitions are a minimum, optionally followed by a comma & maximum
+				if character == '{' {
+					let mut count = String::new();
+					loop {
+						match characters.next() {
+							None => return false, // Never closed
+							Some('}') => break,
+							Some(character) => count.push(character),
+						}
+					}
+
+					let (minimum, maximum) = count.split_once(',').unwrap_or((&count, "0"));
+					if minimum.parse::<u32>().is_err()
+						|| (!maximum.is_empty() && maximum.parse::<u32>().is_err())
+					{
+						return false;
+					}
+				}
+
+				// Skip past the marker of a lazy repetition
+				if characters.peek() == Some(&'?') {
+					characters.next();
+				}
+				can_repeat = false;
+			}
+			'|' | '^' | '$' => can_repeat = false,
+			_ => can_repeat = true,
+		}
+	}
+
+	open_groups == 0
+}
+
+/// Represents an ActiveMQ advisory topic, which the server publishes its own events to.
+/// Advisories about a destination use its type & name, or a `Custom` destination for wildcards such as `Queue.>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdvisoryType {
+	/// Clients connecting & disconnecting.
+	ConnectionAdvisory,
+
+	/// Queues being created & destroyed.
+	QueueAdvisory,
+
+	/// Topics being created & destroyed.
+	TopicAdvisory,
+
+	/// Consumers starting & stopping on a destination.
+	ConsumerAdvisory(Destination),
+
+	/// Producers starting & stopping on a destination.
+	ProducerAdvisory(Destination),
+
+	/// Messages expiring on a destination.
+	ExpiredAdvisory(Destination),
+
+	/// Messages on a destination being moved to the dead letter queue.
+	DeadLetterAdvisory(Destination),
+
+	/// Messages on a destination not being consumed by anyone.
+	NoConsumerAdvisory(Destination),
+}
+
+impl AdvisoryType {
+	/// Converts the advisory into the topic it is published on.
+	pub fn to_destination(&self) -> Destination {
+		let name = match self {
+			AdvisoryType::ConnectionAdvisory => "Connection".to_string(),
+			AdvisoryType::QueueAdvisory => "Queue".to_string(),
+			AdvisoryType::TopicAdvisory => "Topic".to_string(),
+			AdvisoryType::ConsumerAdvisory(destination) => {
+				format!("Consumer.{}", advised_name(destination))
+			}
+			AdvisoryType::ProducerAdvisory(destination) => {
+				format!("Producer.{}", advised_name(destination))
+			}
+			AdvisoryType::ExpiredAdvisory(destination) => {
+				format!("Expired.{}", advised_name(destination))
+			}
+			AdvisoryType::DeadLetterAdvisory(destination) => {
+				format!("MessageDLQd.{}", advised_name(destination))
+			}
+			AdvisoryType::NoConsumerAdvisory(destination) => {
+				format!("NoConsumer.{}", advised_name(destination))
+			}
+		};
+
+		Destination::Topic(format!("ActiveMQ.Advisory.{}", name))
+	}
+}
+
+/// Converts a destination into how advisory topic names refer to it, such as `Queue.orders`.
+fn advised_name(destination: &Destination) -> String {
+	match destination {
+		Destination::Queue(name) => format!("Queue.{}", name.trim_start_matches("/queue/")),
+		Destination::Topic(name) => format!("Topic.{}", name.trim_start_matches("/topic/")),
+		Destination::TempQueue(name) => {
+			format!("TempQueue.{}", name.trim_start_matches("/temp-queue/"))
+		}
+		Destination::Custom(name) => name.clone(),
+	}
+}
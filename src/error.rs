@@ -0,0 +1,656 @@
+use std::{error::Error, fmt, io, net::SocketAddr, str::Utf8Error, time::Duration};
+
+use crate::{broker::Broker, frame::Frame, version::StompVersion};
+
+/// Represents an ERROR frame, which the STOMP server sends right before closing the connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerError {
+	/// A short description of what went wrong, from the message header.
+	pub message: Option<String>,
+
+	/// The receipt of the frame that caused this, if it asked for one.
+	pub receipt_id: Option<String>,
+
+	/// The MIME type of the body.
+	pub content_type: Option<String>,
+
+	/// A longer description of what went wrong, if there is one.
+	pub body: Option<String>,
+}
+
+impl TryFrom<&Frame> for ServerError {
+	type Error = StompError;
+
+	/// Extracts the details of an ERROR frame.
+	fn try_from(frame: &Frame) -> Result<ServerError, StompError> {
+		if frame.command != "ERROR" {
+			return Err(StompError::UnexpectedCommand {
+				expected: "ERROR".to_string(),
+				received: frame.command.clone(),
+			});
+		}
+
+		Ok(ServerError {
+			message: frame.get_header("message").map(str::to_string),
+			receipt_id: frame.receipt_id().map(str::to_string),
+			content_type: frame.content_type().map(str::to_string),
+			body: frame
+				.body
+				.as_deref()
+				.filter(|body| !body.is_empty())
+				.map(|body| String::from_utf8_lossy(body).into_owned()),
+		})
+	}
+}
+
+impl fmt::Display for ServerError {
+	fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+		write!(
+			formatter,
+			"{}",
+			self.message.as_deref().unwrap_or("No message")
+		)?;
+
+		if let Some(body) = &self.body {
+			write!(formatter, " ({})", body.trim())?;
+		}
+
+		Ok(())
+	}
+}
+
+impl Error for ServerError {}
+
+/// Represents an error that occurred while talking to a STOMP server.
+#[derive(Debug)]
+pub enum StompError {
+	/// The host name & port number could not be resolved to any socket address.
+	DnsResolution { address: String },
+
+	/// Every resolved socket address was tried, but none of them could be connected to.
+	AllAddressesFailed {
+		errors: Vec<(SocketAddr, io::Error)>,
+	},
+
+	/// A frame had a different command to what was expected.
+	UnexpectedCommand { expected: String, received: String },
+
+	/// A frame from the STOMP server couldn't be parsed.
+	Parse {
+		command: Option<String>,
+		reason: String,
+	},
+
+	/// A frame from the STOMP server wasn't followed by a NUL octet where its content-length says it ends, so it was skipped.
+	NullTermination { command: String },
+
+	/// A frame from the STOMP server had a command or headers that aren't valid UTF-8, or a body being read as text isn't.
+	Utf8(Utf8Error),
+
+	/// A frame from the STOMP server had a command that isn't allowed, so it was skipped.
+	UnknownCommand(String),
+
+	/// A frame from the STOMP server was larger than the maximum frame size, so the connection was closed.
+	FrameTooLarge { size: usize, max_frame_size: usize },
+
+	/// A header from the STOMP server used an escape sequence that isn't defined, so the connection was closed.
+	InvalidEscape { name: String, sequence: String },
+
+	/// A frame couldn't be sent, as it would corrupt the stream, so nothing was written.
+	InvalidOutgoing { command: String, reason: String },
+
+	/// The STOMP server sent an ERROR frame.
+	Server(ServerError),
+
+	/// The STOMP server refused to connect without credentials.
+	AuthRequired(ServerError),
+
+	/// The STOMP server rejected the credentials in the CONNECT frame with an ERROR frame.
+	AuthFailed(ServerError),
+
+	/// A frame is missing a header that is required for its command.
+	MissingHeader { command: String, name: String },
+
+	/// A header has a value that couldn't be understood.
+	InvalidHeader { name: String, value: String },
+
+	/// A body was declared with a character set that can't be decoded.
+	UnsupportedCharset(String),
+
+	/// A body has a byte that its character set can't decode, at the given position.
+	UnmappableBody { charset: String, position: usize },
+
+	/// Reading from or writing to the TCP stream failed.
+	Io(io::Error),
+
+	/// The connection to the STOMP server has been closed.
+	ConnectionClosed,
+
+	/// A frame arrived for a destination that no handler is registered for.
+	NoHandler { destination: String },
+
+	/// Nothing arrived from the STOMP server in time.
+	Timeout,
+
+	/// The receive thread panicked, so couldn't be waited for.
+	ReceiveThreadPanicked,
+
+	/// Something was used that needs a broker to be configured, as each one does it differently.
+	BrokerRequired { feature: String },
+
+	/// Something was used that the configured broker doesn't support.
+	UnsupportedByBroker { feature: String, broker: Broker },
+
+	/// A frame was used that the protocol version agreed with the server doesn't support.
+	VersionMismatch {
+		command: String,
+		required: StompVersion,
+		negotiated: StompVersion,
+	},
+
+	/// The STOMP server doesn't support any of the protocol versions offered in the CONNECT frame.
+	UnsupportedVersion {
+		offered: Vec<String>,
+		supported: Vec<String>,
+	},
+
+	/// The STOMP server agreed to a protocol version that wasn't offered in the CONNECT frame.
+	VersionNotOffered {
+		offered: Vec<String>,
+		agreed: String,
+	},
+
+	/// A protocol version couldn't be recognised.
+	UnknownVersion(String),
+
+	/// A message was acknowledged that isn't waiting to be acknowledged.
+	UnknownMessage(String),
+
+	/// A transaction was used that hasn't begun, or has already been committed or aborted.
+	UnknownTransaction(String),
+
+	/// A regular expression couldn't be parsed.
+	InvalidRegex(String),
+
+	/// A message priority was outside of the 0 to 9 range.
+	InvalidPriority(u8),
+
+	/// A message was given an expiry time that has already passed.
+	ExpiryInPast,
+
+	/// Sending a frame now would go over the rate limit.
+	WouldExceedRate,
+
+	/// Reconnecting to the STOMP server failed too many times in a row.
+	ReconnectExhausted { attempts: u32 },
+
+	/// The connection didn't finish closing in time, such as the server still sending frames after a DISCONNECT.
+	DrainTimeout,
+
+	/// Nothing arrived from the STOMP server for too long after it agreed to send heart-beats at this interval.
+	HeartbeatTimeout { interval: Duration },
+}
+
+impl StompError {
+	/// Checks if the connection carried on after this error, such as a single bad frame being skipped.
+	pub fn is_recoverable(&self) -> bool {
+		matches!(
+			self,
+			StompError::Parse { .. }
+				| StompError::NullTermination { .. }
+				| StompError::Utf8(_)
+				| StompError::UnknownCommand(_)
+		)
+	}
+
+	/// Checks if trying the same thing again later could work, such as after the network drops out.
+	/// Errors caused by the configuration, the server refusing us, or the protocol being broken, are permanent.
+	pub fn is_transient(&self) -> bool {
+		matches!(
+			self,
+			StompError::DnsResolution { .. }
+				| StompError::AllAddressesFailed { .. }
+				| StompError::Io(_)
+				| StompError::ConnectionClosed
+				| StompError::Timeout
+				| StompError::WouldExceedRate
+				| StompError::DrainTimeout
+				| StompError::HeartbeatTimeout { .. }
+		)
+	}
+}
+
+impl fmt::Display for StompError {
+	fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			StompError::DnsResolution { address } => {
+				write!(
+					formatter,
+					"Unable to resolve '{}' to a socket address",
+					address
+				)
+			}
+			StompError::AllAddressesFailed { errors } => {
+				let reasons = errors
+					.iter()
+					.map(|(address, error)| format!("{}: {}", address, error))
+					.collect::<Vec<String>>()
+					.join(", ");
+
+				write!(formatter, "Unable to connect to any address ({})", reasons)
+			}
+			StompError::UnexpectedCommand { expected, received } => {
+				write!(
+					formatter,
+					"Expected a {} frame but received a {} frame",
+					expected, received
+				)
+			}
+			StompError::Parse {
+				command: Some(command),
+				reason,
+			} => write!(formatter, "Unable to parse {} frame: {}", command, reason),
+			StompError::Parse {
+				command: None,
+				reason,
+			} => write!(formatter, "Unable to parse frame: {}", reason),
+			StompError::NullTermination { command } => {
+				write!(formatter, "{} frame not null terminated", command)
+			}
+			StompError::Utf8(error) => write!(formatter, "Invalid UTF-8: {}", error),
+			StompError::UnknownCommand(command) => {
+				write!(formatter, "Unknown command '{}'", command.escape_debug())
+			}
+			StompError::FrameTooLarge {
+				size,
+				max_frame_size,
+			} => write!(
+				formatter,
+				"Frame of at least {} bytes is larger than the maximum of {} bytes",
+				size, max_frame_size
+			),
+			StompError::InvalidEscape { name, sequence } => write!(
+				formatter,
+				"Header '{}' has an undefined escape sequence '{}'",
+				name, sequence
+			),
+			StompError::InvalidOutgoing { command, reason } => {
+				write!(formatter, "Unable to send {} frame: {}", command, reason)
+			}
+			StompError::Server(error) => write!(formatter, "Server error: {}", error),
+			StompError::AuthRequired(error) => {
+				write!(formatter, "Server requires credentials: {}", error)
+			}
+			StompError::AuthFailed(error) => {
+				write!(formatter, "Server rejected the credentials: {}", error)
+			}
+			StompError::MissingHeader { command, name } => {
+				write!(
+					formatter,
+					"{} frame is missing the '{}' header",
+					command, name
+				)
+			}
+			StompError::InvalidHeader { name, value } => {
+				write!(
+					formatter,
+					"Header '{}' has an invalid value '{}'",
+					name, value
+				)
+			}
+			StompError::UnsupportedCharset(charset) => {
+				write!(formatter, "Character set '{}' is not supported", charset)
+			}
+			StompError::UnmappableBody { charset, position } => write!(
+				formatter,
+				"Body has a byte at position {} that isn't valid {}",
+				position, charset
+			),
+			StompError::Io(error) => write!(formatter, "I/O error: {}", error),
+			StompError::ConnectionClosed => write!(formatter, "Connection closed"),
+			StompError::NoHandler { destination } => {
+				write!(formatter, "No handler for destination '{}'", destination)
+			}
+			StompError::Timeout => write!(formatter, "Timed out"),
+			StompError::ReceiveThreadPanicked => write!(formatter, "Receive thread panicked"),
+			StompError::BrokerRequired { feature } => {
+				write!(formatter, "{} require a broker to be configured", feature)
+			}
+			StompError::UnsupportedByBroker { feature, broker } => {
+				write!(formatter, "{} aren't supported by {:?}", feature, broker)
+			}
+			StompError::VersionMismatch {
+				command,
+				required,
+				negotiated,
+			} => write!(
+				formatter,
+				"{} frames require STOMP {} but STOMP {} was agreed",
+				command,
+				required.as_str(),
+				negotiated.as_str()
+			),
+			StompError::UnsupportedVersion { offered, supported } => write!(
+				formatter,
+				"Server supports STOMP {} but only {} was offered",
+				supported.join(", "),
+				offered.join(", ")
+			),
+			StompError::VersionNotOffered { offered, agreed } => write!(
+				formatter,
+				"Server agreed to STOMP {} but only {} was offered",
+				agreed,
+				offered.join(", ")
+			),
+			StompError::UnknownVersion(version) => {
+				write!(formatter, "'{}' is not a known STOMP version", version)
+			}
+			StompError::UnknownMessage(message_id) => {
+				write!(
+					formatter,
+					"Message '{}' is not waiting to be acknowledged",
+					message_id
+				)
+			}
+			StompError::UnknownTransaction(transaction) => {
+				write!(formatter, "Transaction '{}' is not open", transaction)
+			}
+			StompError::InvalidRegex(pattern) => {
+				write!(formatter, "'{}' is not a valid regular expression", pattern)
+			}
+			StompError::InvalidPriority(priority) => {
+				write!(formatter, "Priority {} is not between 0 & 9", priority)
+			}
+			StompError::ExpiryInPast => write!(formatter, "Expiry time has already passed"),
+			StompError::WouldExceedRate => {
+				write!(formatter, "Sending now would exceed the rate limit")
+			}
+			StompError::ReconnectExhausted { attempts } => {
+				write!(
+					formatter,
+					"Gave up reconnecting after {} attempts",
+					attempts
+				)
+			}
+			StompError::DrainTimeout => {
+				write!(formatter, "Timed out waiting for the connection to close")
+			}
+			StompError::HeartbeatTimeout { interval } => write!(
+				formatter,
+				"Nothing received from the server even though it agreed to heart-beat every {}ms",
+				interval.as_millis()
+			),
+		}
+	}
+}
+
+impl Error for StompError {
+	fn source(&self) -> Option<&(dyn Error + 'static)> {
+		match self {
+			StompError::Io(error) => Some(error),
+			StompError::Utf8(error) => Some(error),
+			_ => None,
+		}
+	}
+}
+
+impl From<io::Error> for StompError {
+	fn from(error: io::Error) -> StompError {
+		StompError::Io(error)
+	}
+}
+
+impl From<Utf8Error> for StompError {
+	fn from(error: Utf8Error) -> StompError {
+		StompError::Utf8(error)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		io::Write,
+		net::TcpListener,
+		sync::mpsc::channel,
+		thread::{sleep, spawn},
+		time::{Duration, SystemTime},
+	};
+
+	use super::*;
+	use crate::{
+		builder::ConnectionBuilder,
+		test_support::{frame_bytes, FakeServer, Reply},
+	};
+
+	#[test]
+	fn server_error_from_error_frame() {
+		let frame = Frame::new("ERROR")
+			.with_header("message", "Bad destination")
+			.with_header("receipt-id", "7")
+			.with_header("content-type", "text/plain")
+			.with_body("No such queue");
+
+		let error = ServerError::try_from(&frame).unwrap();
+		assert_eq!(error.message.as_deref(), Some("Bad destination"));
+		assert_eq!(error.receipt_id.as_deref(), Some("7"));
+		assert_eq!(error.content_type.as_deref(), Some("text/plain"));
+		assert_eq!(error.to_string(), "Bad destination (No such queue)");
+	}
+
+	#[test]
+	fn server_error_from_other_frame() {
+		let result = ServerError::try_from(&Frame::new("RECEIPT"));
+
+		assert!(matches!(
+			result,
+			Err(StompError::UnexpectedCommand { expected, received })
+				if expected == "ERROR" && received == "RECEIPT"
+		));
+	}
+
+	#[test]
+	fn refused_connection_fails_every_address() {
+		let port = TcpListener::bind("127.0.0.1:0")
+			.unwrap()
+			.local_addr()
+			.unwrap()
+			.port(); // Nothing listens once it is dropped
+
+		let result = ConnectionBuilder::new("127.0.0.1", port).open();
+
+		match result {
+			Err(error @ StompError::AllAddressesFailed { .. }) => {
+				assert!(error.is_transient());
+				assert!(
+					matches!(&error, StompError::AllAddressesFailed { errors } if errors.len() == 1)
+				);
+			}
+			Err(error) => panic!("Expected every address to fail, got {:?}", error),
+			Ok(_) => panic!("Expected every address to fail"),
+		}
+	}
+
+	#[test]
+	fn sending_after_close_fails_as_closed() {
+		let server = FakeServer::silent();
+		let mut connection = server.connect();
+		connection.close().unwrap();
+
+		let result = connection.send("/queue/a", "Hello");
+
+		assert!(
+			matches!(result, Err(StompError::ConnectionClosed)),
+			"{:?}",
+			result
+		);
+	}
+
+	#[test]
+	fn invalid_arguments_fail_before_sending() {
+		let server = FakeServer::silent();
+		let mut connection = server.connect();
+
+		let result = connection.send_priority("/queue/a", "Hello", 10, Vec::new());
+		assert!(
+			matches!(result, Err(StompError::InvalidPriority(10))),
+			"{:?}",
+			result
+		);
+
+		let expiry = SystemTime::now() - Duration::from_secs(60);
+		let result = connection.send_with_expiry("/queue/a", "Hello", expiry, Vec::new());
+		assert!(
+			matches!(result, Err(StompError::ExpiryInPast)),
+			"{:?}",
+			result
+		);
+
+		assert!(server.next_frame(Duration::from_millis(100)).is_none());
+	}
+
+	#[test]
+	fn bad_frame_is_received_as_parse_error() {
+		let server = FakeServer::silent();
+		let connection = server.connect();
+
+		server.write(b"bad:command\n\n\x00");
+		server.write(&frame_bytes("RECEIPT", &[("receipt-id", "1")], ""));
+
+		match connection.recv_timeout(Duration::from_secs(1)) {
+			Err(error @ StompError::Parse { .. }) => assert!(error.is_recoverable()),
+			result => panic!("Expected a parse error, got {:?}", result),
+		}
+		assert_eq!(
+			connection
+				.recv_timeout(Duration::from_secs(1))
+				.unwrap()
+				.command,
+			"RECEIPT"
+		);
+	}
+
+	#[test]
+	fn dropping_mid_frame_is_received_as_closed() {
+		let server = FakeServer::start(Box::new(|_| {
+			vec![
+				Reply::Bytes(b"MESSAGE\ndestination:/queue/a\ncontent-length:100\n\nHel".to_vec()),
+				Reply::Close,
+			]
+		}));
+		let mut connection = server.connect();
+		connection.send("/queue/a", "Hello").unwrap();
+
+		let result = connection.receive();
+
+		assert!(
+			matches!(
+				&result,
+				Err(StompError::Io(_)) | Err(StompError::ConnectionClosed)
+			),
+			"{:?}",
+			result
+		);
+		assert!(result.unwrap_err().is_transient());
+	}
+
+	#[test]
+	fn reset_mid_frame_is_received_as_io_error() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let port = listener.local_addr().unwrap().port();
+		let (unread_sender, unread_receiver) = channel();
+		let server_thread = spawn(move || {
+			let (mut tcp_stream, _) = listener.accept().unwrap();
+			tcp_stream
+				.write_all(b"MESSAGE\ncontent-length:100\n\nHel")
+				.unwrap();
+
+			// Closing with the client's frame left unread resets the connection
+			unread_receiver.recv().unwrap();
+			sleep(Duration::from_millis(100)); // Give it time to arrive
+		});
+		let mut connection = ConnectionBuilder::new("127.0.0.1", port).open().unwrap();
+		connection.send("/queue/a", "Hello").unwrap();
+		unread_sender.send(()).unwrap();
+		server_thread.join().unwrap();
+
+		let result = connection.receive();
+
+		match result {
+			Err(StompError::Io(error)) => {
+				assert_eq!(error.kind(), io::ErrorKind::ConnectionReset);
+			}
+			result => panic!("Expected an I/O error, got {:?}", result),
+		}
+	}
+
+	#[test]
+	fn classifies_errors() {
+		let recoverable = StompError::UnknownCommand("PING".to_string());
+		assert!(recoverable.is_recoverable());
+		assert!(!recoverable.is_transient());
+
+		let too_large = StompError::FrameTooLarge {
+			size: 2,
+			max_frame_size: 1,
+		};
+		assert!(!too_large.is_recoverable());
+		assert!(!too_large.is_transient());
+
+		let invalid_utf8 = Frame::new("SEND")
+			.with_body_bytes(b"\xff")
+			.body_text()
+			.unwrap_err();
+		assert!(invalid_utf8.is_recoverable());
+		assert!(invalid_utf8.source().is_some());
+
+		assert!(StompError::Timeout.is_transient());
+		assert!(StompError::from(io::Error::from(io::ErrorKind::BrokenPipe)).is_transient());
+		assert!(
+			!StompError::Server(ServerError::try_from(&Frame::new("ERROR")).unwrap())
+				.is_transient()
+		);
+	}
+
+	#[test]
+	fn heart_beat_timeout_is_transient() {
+		let error = StompError::HeartbeatTimeout {
+			interval: Duration::from_secs(1),
+		};
+		assert!(error.is_transient());
+		assert!(!error.is_recoverable());
+	}
+
+	#[test]
+	fn drain_timeout_is_transient() {
+		assert!(StompError::DrainTimeout.is_transient());
+		assert!(!StompError::DrainTimeout.is_recoverable());
+	}
+
+	#[test]
+	fn auth_failure_is_permanent() {
+		let error = StompError::AuthFailed(ServerError::try_from(&Frame::new("ERROR")).unwrap());
+		assert!(!error.is_transient());
+		assert!(!error.is_recoverable());
+	}
+
+	#[test]
+	fn version_mismatch_is_permanent() {
+		let error = StompError::VersionMismatch {
+			command: "NACK".to_string(),
+			required: StompVersion::V1_1,
+			negotiated: StompVersion::V1_0,
+		};
+		assert!(!error.is_transient());
+		assert!(!error.is_recoverable());
+	}
+
+	#[test]
+	fn missing_null_termination_is_recoverable() {
+		let error = StompError::NullTermination {
+			command: "MESSAGE".to_string(),
+		};
+		assert!(error.is_recoverable());
+		assert!(!error.is_transient());
+		assert_eq!(error.to_string(), "MESSAGE frame not null terminated");
+	}
+}
@@ -0,0 +1,159 @@
+use crate::frame::Frame;
+
+/// Controls when the receive thread stops reading from the STOMP server because frames aren't being taken quickly enough.
+///
+/// Reading stops once either limit is exceeded, letting TCP flow control slow the server down,
+/// and carries on once both amounts have dropped to their low-water marks. Sizes are approximate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlowControl {
+	/// Stop reading once more than this many frames are waiting to be taken.
+	pub high_water_frames: usize,
+
+	/// Carry on reading once this many frames or fewer are waiting to be taken.
+	pub low_water_frames: usize,
+
+	/// Stop reading once more than this many bytes of frames are waiting to be taken.
+	pub high_water_bytes: usize,
+
+	/// Carry on reading once this many bytes of frames or fewer are waiting to be taken.
+	pub low_water_bytes: usize,
+}
+
+impl FlowControl {
+	/// Limits how many frames can wait to be taken.
+	pub fn frames(high_water: usize, low_water: usize) -> FlowControl {
+		FlowControl {
+			high_water_frames: high_water,
+			low_water_frames: low_water,
+			high_water_bytes: usize::MAX,
+			low_water_bytes: usize::MAX,
+		}
+	}
+
+	/// Limits how many bytes of frames can wait to be taken.
+	pub fn bytes(high_water: usize, low_water: usize) -> FlowControl {
+		FlowControl {
+			high_water_frames: usize::MAX,
+			low_water_frames: usize::MAX,
+			high_water_bytes: high_water,
+			low_water_bytes: low_water,
+		}
+	}
+
+	/// Checks if too much is waiting to be taken.
+	pub(crate) fn is_above_high_water(&self, backlog: &Backlog) -> bool {
+		backlog.frames > self.high_water_frames || backlog.bytes > self.high_water_bytes
+	}
+
+	/// Checks if enough has been taken to carry on reading.
+	pub(crate) fn is_at_low_water(&self, backlog: &Backlog) -> bool {
+		backlog.frames <= self.low_water_frames && backlog.bytes <= self.low_water_bytes
+	}
+}
+
+/// Keeps track of the frames waiting to be taken from the channel.
+#[derive(Default)]
+pub(crate) struct Backlog {
+	pub(crate) frames: usize,
+	pub(crate) bytes: usize,
+	pub(crate) paused: bool,
+	pub(crate) pauses: u64,
+}
+
+/// Estimates how much memory a frame takes up.
+pub(crate) fn frame_size(frame: &Frame) -> usize {
+	let header_size = frame
+		.headers
+		.iter()
+		.map(|(name, value)| name.len() + value.len())
+		.sum::<usize>();
+
+	frame.command.len() + header_size + frame.body_len()
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		thread::sleep,
+		time::{Duration, Instant},
+	};
+
+	use super::*;
+	use crate::test_support::{frame_bytes, FakeServer};
+
+	fn backlog(frames: usize, bytes: usize) -> Backlog {
+		Backlog {
+			frames,
+			bytes,
+			..Backlog::default()
+		}
+	}
+
+	#[test]
+	fn frames_have_hysteresis() {
+		let flow_control = FlowControl::frames(4, 1);
+
+		assert!(!flow_control.is_above_high_water(&backlog(4, usize::MAX)));
+		assert!(flow_control.is_above_high_water(&backlog(5, 0)));
+
+		// Between the marks is neither, so a paused reader stays paused
+		assert!(!flow_control.is_at_low_water(&backlog(2, 0)));
+		assert!(flow_control.is_at_low_water(&backlog(1, 0)));
+	}
+
+	#[test]
+	fn bytes_have_hysteresis() {
+		let flow_control = FlowControl::bytes(100, 10);
+
+		assert!(!flow_control.is_above_high_water(&backlog(usize::MAX, 100)));
+		assert!(flow_control.is_above_high_water(&backlog(0, 101)));
+
+		assert!(!flow_control.is_at_low_water(&backlog(0, 50)));
+		assert!(flow_control.is_at_low_water(&backlog(0, 10)));
+	}
+
+	#[test]
+	fn either_limit_pauses_but_both_must_drop() {
+		let flow_control = FlowControl {
+			high_water_frames: 4,
+			low_water_frames: 1,
+			high_water_bytes: 100,
+			low_water_bytes: 10,
+		};
+
+		assert!(flow_control.is_above_high_water(&backlog(0, 101)));
+		assert!(flow_control.is_above_high_water(&backlog(5, 0)));
+		assert!(!flow_control.is_at_low_water(&backlog(1, 50)));
+		assert!(!flow_control.is_at_low_water(&backlog(2, 10)));
+	}
+
+	#[test]
+	fn pauses_reading_until_frames_are_taken() {
+		let server = FakeServer::silent();
+		let connection = server
+			.builder()
+			.flow_control(FlowControl::frames(1, 0))
+			.open()
+			.unwrap();
+
+		// Each frame arrives separately, so the backlog is checked between them
+		for receipt_id in ["1", "2", "3"] {
+			server.write(&frame_bytes("RECEIPT", &[("receipt-id", receipt_id)], ""));
+			sleep(Duration::from_millis(20));
+		}
+
+		let deadline = Instant::now() + Duration::from_secs(2);
+		while !connection.stats().reading_paused && Instant::now() < deadline {
+			sleep(Duration::from_millis(10));
+		}
+		let stats = connection.stats();
+		assert!(stats.reading_paused);
+		assert_eq!(stats.reading_pauses, 1);
+
+		for receipt_id in ["1", "2", "3"] {
+			let frame = connection.recv_timeout(Duration::from_secs(1)).unwrap();
+			assert_eq!(frame.get_header("receipt-id"), Some(receipt_id));
+		}
+		assert!(!connection.stats().reading_paused);
+	}
+}
@@ -1,64 +1,494 @@
 use flate2::read::GzDecoder;
-use std::{error::Error, io::Read, str::from_utf8};
+use std::{borrow::Cow, io::Read, str::from_utf8};
 
-use crate::header::Headers;
+use crate::{
+	charset,
+	command::ServerCommand,
+	error::StompError,
+	header::{self, HeaderMap, Headers},
+	heart_beat,
+};
 
 // https://stomp.github.io/stomp-specification-1.2.html
 
 /// Represents a STOMP frame.
+///
+/// Header names keep the case they were sent with, as some brokers treat vendor headers such as `JMSXGroupID` as case-sensitive.
+/// Looking them up ignores case instead.
+#[derive(Debug, Clone, Default)]
 pub struct Frame {
 	pub command: String,
-	pub headers: Vec<(String, String)>,
-	pub body: Option<String>,
+	pub headers: HeaderMap,
+	pub body: Option<Vec<u8>>,
+	pub(crate) compressed: bool, // The body was gzip-compressed when received
+}
+
+impl Frame {
+	/// Creates a frame with no headers or body.
+	pub fn new(command: &str) -> Frame {
+		Frame {
+			command: command.to_string(),
+			headers: HeaderMap::new(),
+			body: None,
+			compressed: false,
+		}
+	}
+
+	/// Checks if the body was gzip-compressed when it was received, before being decompressed.
+	pub fn was_compressed(&self) -> bool {
+		self.compressed
+	}
+
+	/// Adds a header to this frame.
+	pub fn with_header(mut self, name: &str, value: &str) -> Frame {
+		self.headers.append(name, value);
+		self
+	}
+
+	/// Sets the body of this frame to text.
+	pub fn with_body(mut self, body: &str) -> Frame {
+		self.body = Some(body.as_bytes().to_vec());
+		self
+	}
+
+	/// Sets the body of this frame to any bytes, such as a serialized or encrypted payload.
+	pub fn with_body_bytes(mut self, body: &[u8]) -> Frame {
+		self.body = Some(body.to_vec());
+		self
+	}
+
+	/// Gets the body as text, failing with `StompError::Utf8` if it isn't valid UTF-8.
+	pub fn body_text(&self) -> Result<Option<&str>, StompError> {
+		Ok(self.body.as_deref().map(from_utf8).transpose()?)
+	}
+
+	/// Gets the body as text, the same as `body_text()`.
+	pub fn body_str(&self) -> Result<Option<&str>, StompError> {
+		self.body_text()
+	}
+
+	/// Gets the body as text, decoded with the character set named in the content-type header.
+	/// UTF-8 is assumed when no character set is named, as the specification says.
+	pub fn body_text_decoded(&self) -> Result<Option<Cow<'_, str>>, StompError> {
+		let charset = self.charset().unwrap_or(charset::UTF_8);
+
+		self.body
+			.as_deref()
+			.map(|body| charset::decode(body, charset))
+			.transpose()
+	}
+
+	/// Gets the character set named in the content-type header, such as `iso-8859-1`.
+	pub fn charset(&self) -> Option<&str> {
+		self.content_type().and_then(charset::from_content_type)
+	}
+
+	/// Gets the body as bytes, exactly as they are held.
+	pub fn body_raw(&self) -> Option<&[u8]> {
+		self.body.as_deref()
+	}
+
+	/// Gets the length of the body in bytes, which is zero if there isn't one.
+	pub fn body_len(&self) -> usize {
+		self.body.as_ref().map_or(0, Vec::len)
+	}
+
+	/// Gets the value of the first header with the given name, ignoring case.
+	pub fn get_header(&self, name: &str) -> Option<&str> {
+		self.headers.get(name)
+	}
+
+	/// Checks if the frame has at least one header with the given name, ignoring case.
+	pub fn has_header(&self, name: &str) -> bool {
+		self.headers.contains(name)
+	}
+
+	/// Replaces the value of the first header with the given name, or adds it to the end if there isn't one.
+	/// A content-length that doesn't match the body is refused when the frame is sent, as it would corrupt the stream.
+	pub fn set_header(&mut self, name: &str, value: &str) {
+		self.headers.set(name, value);
+	}
+
+	/// Adds a header to the end, keeping any existing headers with the same name.
+	pub fn append_header(&mut self, name: &str, value: &str) {
+		self.headers.append(name, value);
+	}
+
+	/// Removes every header with the given name, ignoring case, returning how many there were.
+	pub fn remove_header(&mut self, name: &str) -> usize {
+		self.headers.remove(name)
+	}
+
+	/// Gets the value of the content-length header, if it is present & valid.
+	pub fn content_length(&self) -> Option<usize> {
+		self.get_header(Headers::ContentLength.as_str())
+			.and_then(|value| value.parse::<usize>().ok())
+	}
+
+	/// Recognises the command of a frame the server sent, or None for client or vendor-specific commands.
+	pub fn server_command(&self) -> Option<ServerCommand> {
+		ServerCommand::from_command(&self.command)
+	}
+
+	/// Gets the destination header, set on MESSAGE frames.
+	pub fn destination(&self) -> Option<&str> {
+		self.get_header(Headers::Destination.as_str())
+	}
+
+	/// Gets the message-id header, set on MESSAGE frames.
+	pub fn message_id(&self) -> Option<&str> {
+		self.get_header(Headers::MessageId.as_str())
+	}
+
+	/// Gets the subscription header, set on MESSAGE frames.
+	pub fn subscription(&self) -> Option<&str> {
+		self.get_header(Headers::Subscription.as_str())
+	}
+
+	/// Gets the ack header, set on MESSAGE frames that need acknowledging (STOMP 1.2).
+	pub fn ack_id(&self) -> Option<&str> {
+		self.get_header(Headers::Ack.as_str())
+	}
+
+	/// Gets the receipt-id header, set on RECEIPT frames & ERROR frames caused by a frame that asked for a receipt.
+	pub fn receipt_id(&self) -> Option<&str> {
+		self.get_header(Headers::ReceiptId.as_str())
+	}
+
+	/// Gets the content-type header, describing the MIME type of the body.
+	pub fn content_type(&self) -> Option<&str> {
+		self.get_header(Headers::ContentType.as_str())
+	}
+
+	/// Gets the heart-beat header, set on CONNECT & CONNECTED frames, as the send & receive intervals in milliseconds.
+	/// Malformed values are treated as 0,0, which means no heart-beating.
+	pub fn heart_beat(&self) -> Option<(u32, u32)> {
+		self.headers
+			.get(Headers::HeartBeat.as_str())
+			.map(|value| heart_beat::parse_header_value(value).unwrap_or((0, 0)))
+	}
+
+	/// Checks if the server has delivered this message before, treating unrecognised values as false.
+	///
+	/// ActiveMQ, Artemis & RabbitMQ set the `redelivered` header, whereas JMS bridges & HornetQ
+	/// carry it over as `JMSRedelivered`. Either being `true` counts, as does a `JMSXDeliveryCount` above one.
+	pub fn is_redelivered(&self) -> bool {
+		self.redelivered().unwrap_or(false)
+	}
+
+	/// Checks if the server has delivered this message before, failing if a header has a value other than true or false.
+	/// A missing header means the message has not been redelivered.
+	pub fn redelivered(&self) -> Result<bool, StompError> {
+		let mut redelivered = self.delivery_count().is_some_and(|count| count > 1);
+
+		for header in [Headers::Redelivered, Headers::JmsRedelivered] {
+			let value = self.get_header(header.as_str());
+			if value.is_none() {
+				continue;
+			}
+			let value = value.unwrap();
+
+			if value.eq_ignore_ascii_case("true") {
+				redelivered = true;
+			} else if !value.eq_ignore_ascii_case("false") {
+				return Err(StompError::InvalidHeader {
+					name: header.as_str().to_string(),
+					value: value.to_string(),
+				});
+			}
+		}
+
+		Ok(redelivered)
+	}
+
+	/// Gets how many times ActiveMQ has attempted to deliver this message, if it says.
+	pub fn delivery_count(&self) -> Option<u32> {
+		self.headers
+			.get(Headers::JmsxDeliveryCount.as_str())
+			.and_then(|value| value.trim().parse::<u32>().ok())
+	}
+}
+
+/// Represents a STOMP frame that borrows from the bytes it was parsed from, rather than copying them.
+#[derive(Debug, Clone)]
+pub struct BorrowedFrame<'a> {
+	pub command: &'a str,
+	pub headers: Vec<(&'a str, Cow<'a, str>)>,
+	pub body: Option<Cow<'a, [u8]>>,
+	pub(crate) compressed: bool,
+}
+
+impl<'a> BorrowedFrame<'a> {
+	/// Gets the value of the first header with the given name.
+	pub fn header(&self, name: &str) -> Option<&str> {
+		self.headers
+			.iter()
+			.find(|(key, _)| key.eq_ignore_ascii_case(name))
+			.map(|(_, value)| value.as_ref())
+	}
+
+	/// Gets the values of every header with the given name, in order.
+	pub fn header_all(&self, name: &str) -> Vec<&str> {
+		self.headers
+			.iter()
+			.filter(|(key, _)| key.eq_ignore_ascii_case(name))
+			.map(|(_, value)| value.as_ref())
+			.collect()
+	}
+
+	/// Checks if the body was gzip-compressed when it was received, before being decompressed.
+	pub fn was_compressed(&self) -> bool {
+		self.compressed
+	}
+
+	/// Copies everything this frame borrows, so it can outlive the bytes it was parsed from.
+	pub fn into_owned(self) -> Frame {
+		Frame {
+			command: self.command.to_string(),
+			headers: self
+				.headers
+				.into_iter()
+				.map(|(name, value)| (name.to_string(), value.into_owned()))
+				.collect(),
+			body: self.body.map(Cow::into_owned),
+			compressed: self.compressed,
+		}
+	}
 }
 
 /// Creates a STOMP frame.
 pub fn create(command: &str, headers: Option<Vec<(&str, &str)>>, body: Option<&str>) -> String {
-	// Just command & body if there aren't any headers
-	if headers.is_none() {
-		return format!("{}\n\n{}\0", command, body.unwrap_or(""));
+	let bytes = create_bytes(command, headers, body.map(str::as_bytes));
+
+	String::from_utf8(bytes).expect("Frame created from strings is not UTF-8")
+}
+
+/// Creates a STOMP frame as bytes.
+/// A content-length header is added if the body contains a NUL octet & there isn't one already,
+/// as otherwise the body would end at the NUL.
+pub fn create_bytes(
+	command: &str,
+	headers: Option<Vec<(&str, &str)>>,
+	body: Option<&[u8]>,
+) -> Vec<u8> {
+	let mut headers = headers.unwrap_or_default();
+	let body = body.unwrap_or_default();
+
+	// Ensure the body can't be cut short
+	let content_length = body.len().to_string();
+	if needs_content_length(body, headers.iter().map(|(name, _)| *name)) {
+		headers.push((Headers::ContentLength.as_str(), &content_length));
 	}
 
-	// Convert headers into colon delimited key-value pairs between line feeds
-	let header_lines = headers
-		.unwrap()
-		.iter()
-		.map(|(name, value)| format!("{}:{}", name, value))
-		.collect::<Vec<String>>()
-		.join("\n");
+	let mut bytes = Vec::with_capacity(command.len() + body.len() + 3);
+	bytes.extend_from_slice(command.as_bytes());
+	bytes.push(b'\n');
+
+	// Headers are colon delimited key-value pairs, each on their own line
+	for (name, value) in headers {
+		bytes.extend_from_slice(name.as_bytes());
+		bytes.push(b':');
+		bytes.extend_from_slice(value.as_bytes());
+		bytes.push(b'\n');
+	}
+
+	bytes.push(b'\n');
+	bytes.extend_from_slice(body);
+	bytes.push(0x00);
 
-	// Include headers in the frame
-	return format!("{}\n{}\n\n{}\0", command, header_lines, body.unwrap_or(""));
+	bytes
+}
+
+/// Checks if a body contains a NUL octet without a content-length header to say it isn't the end of the frame.
+pub(crate) fn needs_content_length<'a>(
+	body: &[u8],
+	mut header_names: impl Iterator<Item = &'a str>,
+) -> bool {
+	body.contains(&0x00)
+		&& !header_names.any(|name| name.eq_ignore_ascii_case(Headers::ContentLength.as_str()))
+}
+
+/// Checks that a frame can be sent without corrupting the stream, such as a destination injecting extra headers.
+/// Header values can only contain CR, LF & colons if they are going to be escaped.
+pub(crate) fn validate_outgoing(frame: &Frame, escaped: bool) -> Result<(), StompError> {
+	let invalid = |reason: String| StompError::InvalidOutgoing {
+		command: frame.command.clone(),
+		reason,
+	};
+
+	// Every command is made of capital letters
+	if frame.command.is_empty() || !frame.command.bytes().all(|byte| byte.is_ascii_uppercase()) {
+		return Err(invalid("Command must only be capital letters".to_string()));
+	}
+
+	for (name, value) in frame.headers.iter() {
+		if name.is_empty() || name.contains([':', '\r', '\n', '\0']) {
+			return Err(invalid(format!(
+				"Header name '{}' is invalid",
+				name.escape_debug()
+			)));
+		}
+
+		if value.contains('\0') || (!escaped && value.contains(['\r', '\n'])) {
+			return Err(invalid(format!(
+				"Header '{}' has a value that can't be sent '{}'",
+				name,
+				value.escape_debug()
+			)));
+		}
+	}
+
+	// The server would read the wrong number of bytes as the body
+	for value in frame.headers.get_all(Headers::ContentLength.as_str()) {
+		if value.parse::<usize>().ok() != Some(frame.body_len()) {
+			return Err(invalid(format!(
+				"Header 'content-length' is '{}' but the body is {} bytes",
+				value.escape_debug(),
+				frame.body_len()
+			)));
+		}
+	}
+
+	Ok(())
+}
+
+/// Checks if a command has its header values escaped, which is everything other than the handshake (STOMP 1.1 onwards).
+pub(crate) fn escapes_headers(command: &str) -> bool {
+	!matches!(command, "CONNECT" | "STOMP" | "CONNECTED")
+}
+
+/// Controls how leniently incoming STOMP frames are parsed.
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+	/// Fall back to scanning for the NUL terminator when it isn't where the content-length header says it should be.
+	pub lenient_content_length: bool,
+
+	/// Leave backslashes in header values as they are, as STOMP 1.0 has no escaping.
+	pub literal_header_values: bool,
+
+	/// Refuse frames larger than this, such as by their content-length header, rather than buffering that much.
+	pub max_frame_size: Option<usize>,
+
+	/// Refuse frames with a command the server shouldn't send, unless it is one of the allowed commands.
+	pub strict_commands: bool,
+
+	/// Extra commands to accept when strict, such as vendor extensions.
+	pub allowed_commands: Vec<String>,
+
+	/// Replace invalid UTF-8 in bodies with U+FFFD, so they can always be read as text.
+	pub lossy_body_utf8: bool,
+
+	/// Refuse frames with more headers than this.
+	pub max_headers: Option<usize>,
+
+	/// Refuse frames with a command or header line longer than this, in bytes.
+	pub max_header_line_length: Option<usize>,
+
+	/// Leave bodies with a gzip content-encoding header compressed, rather than decompressing them.
+	pub skip_decompression: bool,
 }
 
 /// Attempts to parse the first STOMP frame in a byte buffer.
-pub fn parse(buffer: &mut Vec<u8>) -> Result<Option<(Frame, usize)>, Box<dyn Error>> {
-	// TODO: This implementation does not account for optional CR before each LF
+pub fn parse(buffer: &[u8]) -> Result<Option<(Frame, usize)>, StompError> {
+	parse_with_options(buffer, &ParseOptions::default())
+}
+
+/// Attempts to parse the command & headers of the first STOMP frame in a byte buffer.
+/// Returns a frame without a body & the position of where the body starts.
+pub fn parse_head(buffer: &[u8]) -> Result<Option<(Frame, usize)>, StompError> {
+	parse_head_with_options(buffer, &ParseOptions::default())
+}
+
+/// Attempts to parse the command & headers of the first STOMP frame in a byte buffer, using the given options.
+pub fn parse_head_with_options(
+	buffer: &[u8],
+	options: &ParseOptions,
+) -> Result<Option<(Frame, usize)>, StompError> {
+	let head = parse_head_borrowed_with_options(buffer, options)?;
+
+	Ok(head.map(|(frame, body_start_position)| (frame.into_owned(), body_start_position)))
+}
+
+/// Attempts to parse the first STOMP frame in a byte buffer, using the given options.
+pub fn parse_with_options(
+	buffer: &[u8],
+	options: &ParseOptions,
+) -> Result<Option<(Frame, usize)>, StompError> {
+	let parsed = parse_borrowed_with_options(buffer, options)?;
+
+	Ok(parsed.map(|(frame, end_position)| (frame.into_owned(), end_position)))
+}
+
+/// Attempts to parse the first STOMP frame in a byte buffer, borrowing from it rather than copying.
+pub fn parse_borrowed(buffer: &[u8]) -> Result<Option<(BorrowedFrame<'_>, usize)>, StompError> {
+	parse_borrowed_with_options(buffer, &ParseOptions::default())
+}
+
+/// Attempts to parse the command & headers of the first STOMP frame in a byte buffer, borrowing from it, using the given options.
+fn parse_head_borrowed_with_options<'a>(
+	buffer: &'a [u8],
+	options: &ParseOptions,
+) -> Result<Option<(BorrowedFrame<'a>, usize)>, StompError> {
+	// Skip heart-beats before the frame
+	let heart_beat_length = heart_beat_length(buffer);
+	let buffer = &buffer[heart_beat_length..];
 
 	// Can't continue until we have at least a NT + LF
 	if buffer.len() < 2 {
 		return Ok(None); // Wait for more data
 	}
 
-	// Locate the double LF between headers & body
-	let separator_position = buffer.windows(2).position(|bytes| bytes == [b'\n', b'\n']);
+	// Locate the blank line between headers & body
+	let separator_position = find_blank_line(buffer);
 	if separator_position.is_none() {
+		// Don't wait for more of a frame that is already too long
+		if let Some(reason) = exceeded_header_limit(buffer, options) {
+			return Err(StompError::Parse {
+				command: None,
+				reason,
+			});
+		}
+
 		return Ok(None); // Wait for more data
 	}
 
 	// Extract the command from the first line
 	let command_end_position = buffer.iter().position(|&byte| byte == b'\n').unwrap();
-	let command = from_utf8(&buffer[..command_end_position])?
-		.trim_end() // Strip trailing CR/LF
-		.to_string();
+	let command = from_utf8(&buffer[..command_end_position])?.trim_end(); // Strip trailing CR/LF
+	if command.is_empty() {
+		return Err(StompError::Parse {
+			command: None,
+			reason: "Frame has no command".to_string(),
+		});
+	}
+
+	// Every command is made of capital letters, so anything else is likely a header line after a missing command
+	if !command.bytes().all(|byte| byte.is_ascii_uppercase()) {
+		return Err(StompError::Parse {
+			command: None,
+			reason: format!("Invalid command '{}'", command.escape_debug()),
+		});
+	}
+	if options.strict_commands
+		&& ServerCommand::from_command(command).is_none()
+		&& !options
+			.allowed_commands
+			.iter()
+			.any(|allowed| allowed == command)
+	{
+		return Err(StompError::UnknownCommand(command.to_string()));
+	}
 
-	// Extract the headers hereafter until the double LF
+	// Extract the headers hereafter until the blank line
+	let (last_line_end_position, headers_end_position) = separator_position.unwrap();
 	let headers_start_position = command_end_position + 1;
-	let headers_end_position = separator_position.unwrap() + 1;
-	if headers_end_position > buffer.len() {
-		return Ok(None); // Wait for more data
+	if let Some(reason) = exceeded_header_limit(&buffer[..last_line_end_position + 1], options) {
+		return Err(StompError::Parse {
+			command: Some(command.to_string()),
+			reason,
+		});
 	}
-	let headers = from_utf8(&buffer[headers_start_position..headers_end_position])?
+	let headers = from_utf8(&buffer[headers_start_position..last_line_end_position + 1])?
 		.lines()
 		.filter_map(|line| {
 			// Skip empty lines
@@ -74,83 +504,856 @@ pub fn parse(buffer: &mut Vec<u8>) -> Result<Option<(Frame, usize)>, Box<dyn Err
 				return None;
 			}
 
-			// Force name to lowercase
-			let name = name.to_lowercase();
+			// Apply transformations to value, only copying it if there's something to unescape
+			if options.literal_header_values || !escapes_headers(command) || !value.contains('\\') {
+				return Some(Ok((name, Cow::Borrowed(value))));
+			}
+			let value = match header::unescape_value(value) {
+				Ok(value) => value,
+				Err(sequence) => {
+					return Some(Err(StompError::InvalidEscape {
+						name: name.to_string(),
+						sequence,
+					}))
+				}
+			};
+
+			Some(Ok((name, Cow::Owned(value))))
+		})
+		.collect::<Result<Vec<(&str, Cow<str>)>, StompError>>()?;
+	let frame = BorrowedFrame {
+		command,
+		headers,
+		body: None,
+		compressed: false,
+	};
+
+	// Refuse content lengths that can't be right, rather than misreading the rest of the stream
+	if let Some(value) = frame.header(Headers::ContentLength.as_str()) {
+		let length = value
+			.parse::<usize>()
+			.ok()
+			.filter(|_| value.bytes().all(|byte| byte.is_ascii_digit()));
+		if length.is_none() {
+			return Err(StompError::Parse {
+				command: Some(command.to_string()),
+				reason: format!("Invalid content-length '{}'", value),
+			});
+		}
+
+		if let Some(max_frame_size) = options.max_frame_size {
+			if length.unwrap() > max_frame_size {
+				return Err(StompError::FrameTooLarge {
+					size: length.unwrap(),
+					max_frame_size,
+				});
+			}
+		}
+	}
+
+	// Return the frame & the position of where its body starts
+	Ok(Some((
+		frame,
+		heart_beat_length + headers_end_position + 1, // Move past the blank line
+	)))
+}
 
-			// Apply transformations to value
-			let value = value
-				.replace("\\r", "\r")
-				.replace("\\n", "\n")
-				.replace("\\c", ":")
-				.replace("\\\\", "\\");
+/// Counts the EOLs at the start of a byte buffer, which are heart-beats sent between frames.
+pub fn heart_beat_length(buffer: &[u8]) -> usize {
+	buffer
+		.iter()
+		.take_while(|&&byte| byte == b'\n' || byte == b'\r')
+		.count()
+}
 
-			// Force name to lowercase
-			Some((name, value.to_string()))
+/// Finds the blank line after the command & headers, where each line ends with LF or CR LF.
+/// Returns the positions of the LF before the blank line, and the LF ending it.
+fn find_blank_line(buffer: &[u8]) -> Option<(usize, usize)> {
+	buffer
+		.iter()
+		.enumerate()
+		.filter(|(_, &byte)| byte == b'\n')
+		.find_map(|(position, _)| match buffer.get(position + 1..) {
+			Some([b'\n', ..]) => Some((position, position + 1)),
+			Some([b'\r', b'\n', ..]) => Some((position, position + 2)),
+			_ => None,
 		})
-		.collect::<Vec<(String, String)>>();
+}
+
+/// Checks the command & header lines at the start of a frame against the limits, even before all of them have arrived.
+/// Returns which limit was exceeded, if any.
+fn exceeded_header_limit(head: &[u8], options: &ParseOptions) -> Option<String> {
+	for (index, line) in head.split(|&byte| byte == b'\n').enumerate() {
+		if let Some(max_header_line_length) = options.max_header_line_length {
+			if line.len() > max_header_line_length {
+				return Some(format!(
+					"Line of at least {} bytes is longer than the maximum of {} bytes",
+					line.len(),
+					max_header_line_length
+				));
+			}
+		}
+
+		// The first line is the command, and the last is empty once the line before it has ended
+		if let Some(max_headers) = options.max_headers {
+			if index > max_headers && !line.is_empty() {
+				return Some(format!("More than the maximum of {} headers", max_headers));
+			}
+		}
+	}
+
+	None
+}
+
+/// Attempts to parse the first STOMP frame in a byte buffer, borrowing from it rather than copying, using the given options.
+/// Header values are only copied when they need unescaping, and bodies when they need decompressing.
+pub fn parse_borrowed_with_options<'a>(
+	buffer: &'a [u8],
+	options: &ParseOptions,
+) -> Result<Option<(BorrowedFrame<'a>, usize)>, StompError> {
+	// Start with the command & headers
+	let head = parse_head_borrowed_with_options(buffer, options)?;
+	if head.is_none() {
+		return Ok(None); // Wait for more data
+	}
+	let (mut frame, body_start_position) = head.unwrap();
 
 	// Find the size of the body
-	let content_length = headers.iter().find_map(|(name, value)| {
-		if name.eq(Headers::ContentLength.as_str()) {
-			return value.parse::<usize>().ok();
+	let content_length = frame
+		.header(Headers::ContentLength.as_str())
+		.and_then(|value| value.parse::<usize>().ok());
+
+	// Locate the end of the body
+	let mut body_end_position;
+	if let Some(content_length) = content_length {
+		body_end_position = body_start_position + content_length;
+		if body_end_position >= buffer.len() {
+			return Ok(None); // Wait for more data
 		}
 
-		None
-	});
+		// Some servers get the content length wrong, so trust the NUL terminator instead
+		if options.lenient_content_length && buffer[body_end_position] != 0x00 {
+			let nul_position = buffer[body_start_position..]
+				.iter()
+				.position(|&byte| byte == 0x00);
+			if nul_position.is_none() {
+				return Ok(None); // Wait for more data
+			}
 
-	// Frame is finished if we don't have a body
-	if content_length.is_none() {
-		// Ensure we're terminated with a NT + LF
-		if buffer.len() < headers_end_position + 2 {
+			body_end_position = body_start_position + nul_position.unwrap();
+		}
+	} else {
+		// Without a content length, the body is everything up to the NUL terminator
+		let nul_position = buffer[body_start_position..]
+			.iter()
+			.position(|&byte| byte == 0x00);
+		if nul_position.is_none() {
 			return Ok(None); // Wait for more data
 		}
-		if buffer[headers_end_position + 1] != 0x00 {
-			return Err("Frame not null terminated".into());
+
+		body_end_position = body_start_position + nul_position.unwrap();
+	}
+
+	// Ensure we're terminated with a NT, as any EOLs after it are skipped before the next frame
+	if buffer[body_end_position] != 0x00 {
+		return Err(StompError::NullTermination {
+			command: frame.command.to_string(),
+		});
+	}
+
+	// Only decompress bodies that say they are compressed, passing everything else through untouched
+	let body = &buffer[body_start_position..body_end_position];
+	frame.compressed = !options.skip_decompression
+		&& !body.is_empty()
+		&& frame
+			.header(Headers::ContentEncoding.as_str())
+			.is_some_and(|encoding| encoding.trim().eq_ignore_ascii_case("gzip"));
+	frame.body = decode_body(body, frame.compressed, frame.command, options)?;
+
+	// Return the frame & the position of where this frame ends
+	Ok(Some((
+		frame,
+		body_end_position, // The NT
+	)))
+}
+
+/// Decompresses the bytes of a body if needed. Empty bodies are None.
+fn decode_body<'a>(
+	bytes: &'a [u8],
+	compressed: bool,
+	command: &str,
+	options: &ParseOptions,
+) -> Result<Option<Cow<'a, [u8]>>, StompError> {
+	if bytes.is_empty() {
+		return Ok(None);
+	}
+
+	// Decompress the body
+	let decompressed = if compressed {
+		let mut decompressed = Vec::new();
+		GzDecoder::new(bytes)
+			.read_to_end(&mut decompressed)
+			.map_err(|error| StompError::Parse {
+				command: Some(command.to_string()),
+				reason: format!("Unable to decompress body: {}", error),
+			})?;
+
+		Cow::Owned(decompressed)
+	} else {
+		Cow::Borrowed(bytes)
+	};
+
+	// Replace invalid UTF-8, if asked to, so the body can always be read as text
+	if options.lossy_body_utf8 && from_utf8(&decompressed).is_err() {
+		let body = String::from_utf8_lossy(&decompressed).into_owned();
+
+		return Ok(Some(Cow::Owned(body.into_bytes())));
+	}
+
+	Ok(Some(decompressed))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::serializer::{DefaultSerializer, FrameSerializer};
+
+	/// Generates the same sequence of pseudo-random numbers every run (xorshift64).
+	struct Random(u64);
+
+	impl Random {
+		fn next(&mut self) -> u64 {
+			self.0 ^= self.0 << 13;
+			self.0 ^= self.0 >> 7;
+			self.0 ^= self.0 << 17;
+			self.0
+		}
+
+		fn below(&mut self, limit: usize) -> usize {
+			(self.next() % limit as u64) as usize
+		}
+
+		fn string(&mut self, alphabet: &str, max_length: usize) -> String {
+			let characters = alphabet.chars().collect::<Vec<char>>();
+			(0..self.below(max_length + 1))
+				.map(|_| characters[self.below(characters.len())])
+				.collect()
+		}
+	}
+
+	/// Serializes a frame as it would be sent once STOMP 1.2 is agreed, then parses it back.
+	fn round_trip(frame: &Frame) -> Frame {
+		let escaped = Frame {
+			headers: frame.headers.escaped(),
+			..frame.clone()
+		};
+		let bytes = DefaultSerializer.serialize(&escaped);
+
+		let (parsed, end_position) = parse(&bytes).unwrap().unwrap();
+		assert_eq!(end_position, bytes.len() - 1, "Frame should end at its NUL");
+
+		parsed
+	}
+
+	/// Checks a parsed frame matches the original, ignoring a content-length header added when serializing.
+	fn assert_same(original: &Frame, parsed: &Frame) {
+		assert_eq!(parsed.command, original.command);
+		let mut headers = parsed.headers.clone();
+		if !original.has_header("content-length") {
+			headers.remove("content-length");
+		}
+		assert_eq!(headers, original.headers);
+		assert_eq!(parsed.body_raw(), original.body_raw());
+	}
+
+	#[test]
+	fn round_trips_table() {
+		let frames = [
+			Frame::new("SEND"),
+			Frame::new("SEND").with_header("destination", "/queue/a"),
+			Frame::new("SEND")
+				.with_header("destination", "/queue/a")
+				.with_body("Hello"),
+			Frame::new("SEND")
+				.with_header("colon", "a:b")
+				.with_header("backslash", "a\\b")
+				.with_header("line", "a\nb\r\n")
+				.with_header("empty", ""),
+			Frame::new("SEND")
+				.with_header("repeated", "first")
+				.with_header("repeated", "second"),
+			Frame::new("SEND").with_body_bytes(b"\x00"),
+			Frame::new("SEND").with_body_bytes(b"before\x00after\x00"),
+			Frame::new("SEND").with_body_bytes(b"\xff\xfe not UTF-8 \xc3"),
+			Frame::new("SEND").with_body("\n\n"),
+			Frame::new("MESSAGE")
+				.with_header("content-length", "5")
+				.with_body("a\x00b\x00c"),
+		];
+
+		for frame in &frames {
+			assert_same(frame, &round_trip(frame));
+		}
+	}
+
+	#[test]
+	fn round_trips_random() {
+		let mut random = Random(0x5EED_1234_ABCD_0001);
+
+		for _ in 0..500 {
+			let mut frame = Frame::new(["SEND", "MESSAGE", "ACK", "BEGIN"][random.below(4)]);
+
+			for _ in 0..random.below(6) {
+				let mut name = random.string("abcdefghijklmnopqrstuvwxyz-", 12);
+				if name.is_empty() || name.eq_ignore_ascii_case("content-length") {
+					name.push('x');
+				}
+				let value = random.string("abcXYZ019 :\\\n\r/.-", 16);
+
+				frame.headers.append(&name, &value);
+			}
+
+			let body = (0..random.below(64))
+				.map(|_| random.next() as u8)
+				.collect::<Vec<u8>>();
+			if !body.is_empty() {
+				frame = frame.with_body_bytes(&body);
+			}
+
+			assert_same(&frame, &round_trip(&frame));
+		}
+	}
+
+	#[test]
+	fn parses_crlf_line_endings() {
+		let buffer = b"MESSAGE\r\ndestination:/queue/a\r\nmessage-id:1\r\n\r\nHello\x00";
+
+		let (frame, end_position) = parse(buffer).unwrap().unwrap();
+		assert_eq!(frame.command, "MESSAGE");
+		assert_eq!(frame.destination(), Some("/queue/a"));
+		assert_eq!(frame.message_id(), Some("1"));
+		assert_eq!(frame.body_raw(), Some(&b"Hello"[..]));
+		assert_eq!(end_position, buffer.len() - 1);
+	}
+
+	#[test]
+	fn finds_blank_line_after_mixed_line_endings() {
+		assert_eq!(find_blank_line(b"A\r\nb:1\n\r\nX"), Some((6, 8)));
+		assert_eq!(find_blank_line(b"A\nb:1\r\n\nX"), Some((6, 7)));
+		assert_eq!(find_blank_line(b"A\r\nb:1\nc:2\r\n"), None);
+
+		let buffer = b"MESSAGE\r\ndestination:/queue/a\nmessage-id:1\r\n\nHello\x00";
+		let (frame, end_position) = parse(buffer).unwrap().unwrap();
+		assert_eq!(frame.destination(), Some("/queue/a"));
+		assert_eq!(frame.message_id(), Some("1"));
+		assert_eq!(frame.body_raw(), Some(&b"Hello"[..]));
+		assert_eq!(end_position, buffer.len() - 1);
+	}
+
+	#[test]
+	fn body_without_content_length_ends_at_first_nul() {
+		let buffer = b"MESSAGE\n\nfirst\x00second\x00";
+
+		let (frame, end_position) = parse(buffer).unwrap().unwrap();
+		assert_eq!(frame.body_raw(), Some(&b"first"[..]));
+		assert_eq!(end_position, 14);
+		assert_eq!(&buffer[end_position + 1..], b"second\x00");
+	}
+
+	#[test]
+	fn body_with_content_length_keeps_nuls() {
+		let buffer = b"MESSAGE\ncontent-length:5\n\na\x00b\x00c\x00";
+
+		let (frame, end_position) = parse(buffer).unwrap().unwrap();
+		assert_eq!(frame.body_raw(), Some(&b"a\x00b\x00c"[..]));
+		assert_eq!(end_position, buffer.len() - 1);
+	}
+
+	#[test]
+	fn zero_content_length_ends_at_the_next_byte() {
+		let buffer = b"MESSAGE\ncontent-length:0\n\n\x00RECEIPT\n\n\x00";
+
+		let (frame, end_position) = parse(buffer).unwrap().unwrap();
+		assert_eq!(frame.body_raw(), None); // Empty bodies are None
+		assert_eq!(frame.body_len(), 0);
+		assert_eq!(end_position, b"MESSAGE\ncontent-length:0\n\n".len()); // Straight after the blank line
+
+		let (next, _) = parse(&buffer[end_position + 1..]).unwrap().unwrap();
+		assert_eq!(next.command, "RECEIPT");
+	}
+
+	#[test]
+	fn rejects_body_after_zero_content_length() {
+		let error = parse(b"MESSAGE\ncontent-length:0\n\nHello\x00").unwrap_err();
+
+		assert!(
+			matches!(&error, StompError::NullTermination { command } if command == "MESSAGE"),
+			"{:?}",
+			error
+		);
+	}
+
+	#[test]
+	fn zero_content_length_is_sent_with_empty_or_no_body() {
+		let empty = Frame {
+			body: Some(Vec::new()),
+			..Frame::new("SEND").with_header("content-length", "0")
+		};
+		let none = Frame {
+			body: None,
+			..empty.clone()
+		};
+
+		for frame in [&empty, &none] {
+			assert!(validate_outgoing(frame, false).is_ok());
 		}
-		if buffer[headers_end_position + 2] != b'\n' {
-			return Err("Frame not terminated with a new line".into());
+		assert_eq!(
+			crate::serializer::DefaultSerializer.serialize(&empty),
+			crate::serializer::DefaultSerializer.serialize(&none)
+		);
+		assert!(validate_outgoing(&empty.with_body("a"), false).is_err());
+	}
+
+	#[test]
+	fn waits_for_missing_nul_without_content_length() {
+		assert!(parse(b"MESSAGE\n\nno end yet").unwrap().is_none());
+	}
+
+	#[test]
+	fn rejects_missing_nul_after_content_length() {
+		let error = parse(b"MESSAGE\ncontent-length:2\n\nabc\x00").unwrap_err();
+
+		assert!(
+			matches!(&error, StompError::NullTermination { command } if command == "MESSAGE"),
+			"{:?}",
+			error
+		);
+	}
+
+	#[test]
+	fn rejects_non_numeric_content_length() {
+		for value in ["abc", "-1", "+5", " 5", "5x", ""] {
+			let buffer = format!("MESSAGE\ncontent-length:{}\n\nabcde\x00", value);
+			let error = parse(buffer.as_bytes()).unwrap_err();
+
+			assert!(
+				matches!(&error, StompError::Parse { reason, .. } if reason.starts_with("Invalid content-length")),
+				"{:?} gave {:?}",
+				value,
+				error
+			);
 		}
+	}
 
-		// Return the frame & the position of where this frame ends
-		return Ok(Some((
-			Frame {
+	#[test]
+	fn waits_for_content_length_larger_than_buffer() {
+		let buffer = b"MESSAGE\ncontent-length:100\n\nshort\x00";
+
+		assert!(parse(buffer).unwrap().is_none());
+	}
+
+	#[test]
+	fn rejects_header_line_as_command() {
+		// A stray heart-beat followed by a frame that is missing its command
+		let error = parse(b"\ndestination:/queue/a\nmessage-id:1\n\nHello\x00").unwrap_err();
+
+		assert!(
+			matches!(&error, StompError::Parse { command: None, reason } if reason == "Invalid command 'destination:/queue/a'"),
+			"{:?}",
+			error
+		);
+	}
+
+	#[test]
+	fn rejects_command_that_isnt_capital_letters() {
+		for command in ["message", "SEND1", "SE ND", "SEND:"] {
+			let buffer = format!("{}\n\n\x00", command);
+			let error = parse(buffer.as_bytes()).unwrap_err();
+
+			assert!(
+				matches!(&error, StompError::Parse { reason, .. } if reason.starts_with("Invalid command")),
+				"{:?} gave {:?}",
 				command,
-				headers,
-				body: None,
-			},
-			headers_end_position + 2, // Skip the double LF
-		)));
+				error
+			);
+		}
 	}
 
-	// Decompress the body
-	let body_start_position = headers_end_position + 1; // Move past the double LF
-	let body_length = content_length.unwrap();
-	let body_end_position = body_start_position + body_length;
-	if body_end_position > buffer.len() {
-		return Ok(None); // Wait for more data
+	#[test]
+	fn parses_command_after_heart_beats() {
+		let (frame, _) = parse(b"\n\r\n\nRECEIPT\nreceipt-id:1\n\n\x00")
+			.unwrap()
+			.unwrap();
+
+		assert_eq!(frame.command, "RECEIPT");
+		assert_eq!(frame.receipt_id(), Some("1"));
 	}
-	let mut decompressor = GzDecoder::new(&buffer[body_start_position..body_end_position]);
-	let mut body = String::new();
-	decompressor.read_to_string(&mut body)?;
 
-	// Ensure we're terminated with a NT + LF
-	if buffer.len() < body_end_position + 2 {
-		return Ok(None); // Wait for more data
+	fn limited(max_headers: usize, max_header_line_length: usize) -> ParseOptions {
+		ParseOptions {
+			max_headers: Some(max_headers),
+			max_header_line_length: Some(max_header_line_length),
+			..ParseOptions::default()
+		}
 	}
-	if buffer[body_end_position] != 0x00 {
-		return Err("Frame not null terminated".into());
+
+	#[test]
+	fn strict_commands_rejects_unknown_commands() {
+		let options = ParseOptions {
+			strict_commands: true,
+			..ParseOptions::default()
+		};
+
+		let error = parse_with_options(b"PING\n\n\x00", &options).unwrap_err();
+
+		assert!(
+			matches!(&error, StompError::UnknownCommand(command) if command == "PING"),
+			"{:?}",
+			error
+		);
+		assert!(error.is_recoverable());
+		assert!(parse_with_options(b"MESSAGE\n\n\x00", &options)
+			.unwrap()
+			.is_some());
 	}
-	if buffer[body_end_position + 1] != b'\n' {
-		return Err("Frame not terminated with a new line".into());
+
+	#[test]
+	fn strict_commands_accepts_allowed_commands() {
+		let options = ParseOptions {
+			strict_commands: true,
+			allowed_commands: vec!["PING".to_string()],
+			..ParseOptions::default()
+		};
+
+		let (frame, _) = parse_with_options(b"PING\n\n\x00", &options)
+			.unwrap()
+			.unwrap();
+		assert_eq!(frame.command, "PING");
 	}
 
-	// Return the frame & the position of where this frame ends
-	Ok(Some((
-		Frame {
-			command,
-			headers,
-			body: Some(body),
-		},
-		body_end_position + 1, // Skip the NT + LF
-	)))
+	#[test]
+	fn permissive_commands_passes_unknown_commands_through() {
+		let (frame, _) = parse(b"PING\n\n\x00").unwrap().unwrap();
+
+		assert_eq!(frame.command, "PING");
+	}
+
+	#[test]
+	fn allows_headers_up_to_max_headers() {
+		let options = limited(2, 1024);
+
+		assert!(parse_with_options(b"SEND\na:1\nb:2\n\n\x00", &options)
+			.unwrap()
+			.is_some());
+	}
+
+	#[test]
+	fn rejects_headers_over_max_headers() {
+		let options = limited(2, 1024);
+
+		// Both when the whole head has arrived, and while waiting for the rest of it
+		for buffer in [
+			&b"SEND\na:1\nb:2\nc:3\n\n\x00"[..],
+			b"SEND\na:1\nb:2\nc:3\n",
+		] {
+			let error = parse_with_options(buffer, &options).unwrap_err();
+
+			assert!(
+				matches!(&error, StompError::Parse { reason, .. } if reason == "More than the maximum of 2 headers"),
+				"{:?}",
+				error
+			);
+		}
+	}
+
+	#[test]
+	fn allows_lines_up_to_max_header_line_length() {
+		let options = limited(16, 6);
+
+		assert!(parse_with_options(b"SEND\nab:123\n\n\x00", &options)
+			.unwrap()
+			.is_some());
+	}
+
+	#[test]
+	fn rejects_lines_over_max_header_line_length() {
+		let options = limited(16, 6);
+
+		for buffer in [
+			&b"SEND\nab:1234\n\n\x00"[..],
+			b"SEND\nab:1234",
+			b"MESSAGE\n\n\x00",
+		] {
+			let error = parse_with_options(buffer, &options).unwrap_err();
+
+			assert!(
+				matches!(&error, StompError::Parse { reason, .. } if reason.ends_with("longer than the maximum of 6 bytes")),
+				"{:?}",
+				error
+			);
+		}
+	}
+
+	#[test]
+	fn allows_content_length_up_to_max_frame_size() {
+		let options = ParseOptions {
+			max_frame_size: Some(5),
+			..ParseOptions::default()
+		};
+
+		let (frame, _) = parse_with_options(b"SEND\ncontent-length:5\n\nabcde\x00", &options)
+			.unwrap()
+			.unwrap();
+		assert_eq!(frame.body_len(), 5);
+	}
+
+	#[test]
+	fn rejects_content_length_over_max_frame_size() {
+		let options = ParseOptions {
+			max_frame_size: Some(5),
+			..ParseOptions::default()
+		};
+
+		// The body hasn't arrived yet, as it shouldn't be waited for
+		let error = parse_with_options(b"SEND\ncontent-length:6\n\n", &options).unwrap_err();
+		assert!(
+			matches!(
+				error,
+				StompError::FrameTooLarge {
+					size: 6,
+					max_frame_size: 5
+				}
+			),
+			"{:?}",
+			error
+		);
+	}
+
+	#[test]
+	fn create_bytes_parses_back() {
+		let bytes = create_bytes(
+			"SEND",
+			Some(vec![("destination", "/queue/a")]),
+			Some(b"one\x00two"),
+		);
+
+		let (frame, end_position) = parse(&bytes).unwrap().unwrap();
+		assert_eq!(end_position, bytes.len() - 1);
+		assert_eq!(frame.destination(), Some("/queue/a"));
+		assert_eq!(frame.content_length(), Some(7));
+		assert_eq!(frame.body_raw(), Some(&b"one\x00two"[..]));
+	}
+
+	/// Compresses bytes the way a server would before sending them.
+	fn gzip(bytes: &[u8]) -> Vec<u8> {
+		use flate2::{write::GzEncoder, Compression};
+		use std::io::Write;
+
+		let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+		encoder.write_all(bytes).unwrap();
+		encoder.finish().unwrap()
+	}
+
+	#[test]
+	fn decompresses_gzip_body() {
+		let compressed = gzip(b"Hello\x00World");
+		let bytes = create_bytes(
+			"MESSAGE",
+			Some(vec![("content-encoding", "gzip")]),
+			Some(&compressed),
+		);
+
+		let (frame, _) = parse(&bytes).unwrap().unwrap();
+		assert!(frame.was_compressed());
+		assert_eq!(frame.body_raw(), Some(&b"Hello\x00World"[..]));
+	}
+
+	#[test]
+	fn leaves_gzip_body_compressed_when_asked() {
+		let compressed = gzip(b"Hello");
+		let bytes = create_bytes(
+			"MESSAGE",
+			Some(vec![("content-encoding", "gzip")]),
+			Some(&compressed),
+		);
+		let options = ParseOptions {
+			skip_decompression: true,
+			..ParseOptions::default()
+		};
+
+		let (frame, _) = parse_with_options(&bytes, &options).unwrap().unwrap();
+		assert!(!frame.was_compressed());
+		assert_eq!(frame.body_raw(), Some(&compressed[..]));
+	}
+
+	#[test]
+	fn leaves_gzip_body_without_content_encoding_alone() {
+		let compressed = gzip(b"Hello");
+		assert_eq!(compressed[..2], [0x1f, 0x8b]); // Looks like gzip, but isn't labelled as such
+		let bytes = create_bytes("MESSAGE", None, Some(&compressed));
+
+		let (frame, _) = parse(&bytes).unwrap().unwrap();
+		assert!(!frame.was_compressed());
+		assert_eq!(frame.body_raw(), Some(&compressed[..]));
+	}
+
+	#[test]
+	fn rejects_corrupt_gzip_body() {
+		let mut compressed = gzip(b"Hello");
+		compressed.truncate(compressed.len() / 2);
+		let bytes = create_bytes(
+			"MESSAGE",
+			Some(vec![("content-encoding", "gzip")]),
+			Some(&compressed),
+		);
+
+		let error = parse(&bytes).unwrap_err();
+		assert!(
+			matches!(
+				&error,
+				StompError::Parse { command: Some(command), reason }
+					if command == "MESSAGE" && reason.starts_with("Unable to decompress body")
+			),
+			"{:?}",
+			error
+		);
+	}
+
+	#[test]
+	fn binary_body_survives_create_bytes() {
+		let body = b"\x00\xff\xfe\x00\xc3(\x00";
+		let bytes = create_bytes("SEND", None, Some(body));
+
+		let (frame, _) = parse(&bytes).unwrap().unwrap();
+		assert_eq!(frame.body_raw(), Some(&body[..]));
+		assert!(matches!(frame.body_text(), Err(StompError::Utf8(_))));
+	}
+
+	#[test]
+	fn replaces_invalid_utf8_body_when_asked() {
+		let bytes = create_bytes("SEND", None, Some(b"a\xffb"));
+		let options = ParseOptions {
+			lossy_body_utf8: true,
+			..ParseOptions::default()
+		};
+
+		let (frame, _) = parse_with_options(&bytes, &options).unwrap().unwrap();
+		assert_eq!(frame.body_text().unwrap(), Some("a\u{fffd}b"));
+	}
+
+	#[test]
+	fn typed_accessors_get_their_header() {
+		type Accessor = fn(&Frame) -> Option<&str>;
+		let accessors: [(&str, Accessor); 6] = [
+			("destination", Frame::destination),
+			("message-id", Frame::message_id),
+			("subscription", Frame::subscription),
+			("ack", Frame::ack_id),
+			("receipt-id", Frame::receipt_id),
+			("content-type", Frame::content_type),
+		];
+
+		for (name, accessor) in accessors {
+			assert_eq!(accessor(&Frame::new("MESSAGE")), None, "{}", name);
+
+			// Escaped values come back unescaped
+			let bytes = format!("MESSAGE\n{}:a\\cb\n\n\x00", name);
+			let (frame, _) = parse(bytes.as_bytes()).unwrap().unwrap();
+			assert_eq!(accessor(&frame), Some("a:b"), "{}", name);
+		}
+	}
+
+	#[test]
+	fn typed_accessors_parse_their_header() {
+		let frame = Frame::new("CONNECTED");
+		assert_eq!(frame.content_length(), None);
+		assert_eq!(frame.heart_beat(), None);
+
+		let frame = frame
+			.with_header("content-length", "5")
+			.with_header("heart-beat", "1000,2000");
+		assert_eq!(frame.content_length(), Some(5));
+		assert_eq!(frame.heart_beat(), Some((1000, 2000)));
+
+		let frame = Frame::new("CONNECTED")
+			.with_header("content-length", "five")
+			.with_header("heart-beat", "often");
+		assert_eq!(frame.content_length(), None);
+		assert_eq!(frame.heart_beat(), Some((0, 0)));
+	}
+
+	#[test]
+	fn edits_headers() {
+		let mut frame = Frame::new("SEND")
+			.with_header("receipt", "1")
+			.with_header("Receipt", "2");
+
+		frame.set_header("RECEIPT", "3");
+		assert_eq!(frame.headers.get_all("receipt"), ["3", "2"]);
+
+		frame.append_header("destination", "/queue/a");
+		assert_eq!(frame.get_header("Destination"), Some("/queue/a"));
+
+		assert_eq!(frame.remove_header("receipt"), 2);
+		assert!(!frame.has_header("receipt"));
+		assert_eq!(frame.headers.len(), 1);
+	}
+
+	#[test]
+	fn keeps_header_name_case() {
+		let bytes = b"MESSAGE\nJMSXGroupID:orders\n\n\x00";
+
+		let (frame, _) = parse(bytes).unwrap().unwrap();
+		assert_eq!(frame.headers.iter().next(), Some(("JMSXGroupID", "orders")));
+		assert_eq!(frame.get_header("jmsxgroupid"), Some("orders"));
+
+		let (borrowed, _) = parse_borrowed(bytes).unwrap().unwrap();
+		assert_eq!(borrowed.header("JMSXGROUPID"), Some("orders"));
+	}
+
+	#[test]
+	fn rejects_invalid_utf8_command_or_headers() {
+		for bytes in [&b"SE\xffND\n\n\x00"[..], b"SEND\nname:\xc3(\n\n\x00"] {
+			let result = parse(bytes);
+
+			assert!(
+				matches!(&result, Err(error @ StompError::Utf8(_)) if error.is_recoverable()),
+				"{:?}",
+				result
+			);
+		}
+	}
+
+	/// Measures how quickly a typical MESSAGE frame is parsed.
+	/// Run with `cargo test --release parse_throughput -- --ignored --nocapture`.
+	#[test]
+	#[ignore]
+	fn parse_throughput() {
+		const ITERATIONS: u32 = 100_000;
+		let bytes = create_bytes(
+			"MESSAGE",
+			Some(vec![
+				("destination", "/queue/a"),
+				("message-id", "ID:broker-1234"),
+				("subscription", "1"),
+				("ack", "ack-1234"),
+				("content-type", "text/plain"),
+			]),
+			Some(&[b'a'; 1024]),
+		);
+
+		let started = std::time::Instant::now();
+		for _ in 0..ITERATIONS {
+			std::hint::black_box(parse(std::hint::black_box(&bytes)).unwrap());
+		}
+		let elapsed = started.elapsed();
+
+		println!(
+			"{} frames of {} bytes in {:?} ({:.0} per second)",
+			ITERATIONS,
+			bytes.len(),
+			elapsed,
+			ITERATIONS as f64 / elapsed.as_secs_f64()
+		);
+	}
 }
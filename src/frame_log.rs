@@ -0,0 +1,153 @@
+use std::{
+	fmt::Write as _,
+	io::Write,
+	ops::{Deref, DerefMut},
+	sync::{Arc, Mutex},
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{frame::Frame, shared::lock, Connection};
+
+/// Called with every frame sent to & received from the STOMP server, or None for a heart-beat.
+pub(crate) type FrameObserver = Box<dyn Fn(Direction, Option<&Frame>) + Send + Sync>;
+
+/// Which way a logged frame went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+	Sent,
+	Received,
+}
+
+/// Controls which frames are logged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+	/// Every frame, along with heart-beats.
+	All,
+
+	/// Every frame, without heart-beats.
+	Frames,
+
+	/// Only frames carrying messages or errors, which are SEND, MESSAGE & ERROR frames.
+	Messages,
+
+	/// Only ERROR frames.
+	Errors,
+}
+
+impl Level {
+	/// Checks if a frame, or a heart-beat if there isn't one, should be logged at this level.
+	fn includes(&self, frame: Option<&Frame>) -> bool {
+		match (self, frame) {
+			(Level::All, _) => true,
+			(_, None) => false,
+			(Level::Frames, Some(_)) => true,
+			(Level::Messages, Some(frame)) => {
+				matches!(frame.command.as_str(), "SEND" | "MESSAGE" | "ERROR")
+			}
+			(Level::Errors, Some(frame)) => frame.command == "ERROR",
+		}
+	}
+}
+
+/// Wraps a connection to write every frame sent & received to a sink, in a human-readable format with timestamps.
+/// Everything else is done through the connection as usual, as this dereferences to it.
+pub struct FrameLog<W: Write + Send + 'static> {
+	connection: Connection,
+	writer: Arc<Mutex<W>>,
+}
+
+impl<W: Write + Send + 'static> FrameLog<W> {
+	/// Starts logging every frame, other than heart-beats, on a connection.
+	pub fn new(connection: Connection, writer: W) -> FrameLog<W> {
+		let frame_log = FrameLog {
+			connection,
+			writer: Arc::new(Mutex::new(writer)),
+		};
+
+		frame_log.with_level(Level::Frames)
+	}
+
+	/// Changes which frames are logged.
+	pub fn with_level(self, level: Level) -> FrameLog<W> {
+		let writer = self.writer.clone();
+
+		*lock(&self.connection.shared.frame_observer) = Some(Box::new(move |direction, frame| {
+			if level.includes(frame) {
+				let _ = lock(&writer).write_all(format_entry(direction, frame).as_bytes());
+			}
+		}));
+
+		self
+	}
+
+	/// Stops logging, giving back the connection & the sink.
+	pub fn into_inner(self) -> (Connection, W) {
+		*lock(&self.connection.shared.frame_observer) = None;
+
+		let writer = Arc::try_unwrap(self.writer)
+			.ok()
+			.expect("Frame log sink is still in use")
+			.into_inner()
+			.unwrap_or_else(|error| error.into_inner());
+
+		(self.connection, writer)
+	}
+}
+
+impl<W: Write + Send + 'static> Deref for FrameLog<W> {
+	type Target = Connection;
+
+	fn deref(&self) -> &Connection {
+		&self.connection
+	}
+}
+
+impl<W: Write + Send + 'static> DerefMut for FrameLog<W> {
+	fn deref_mut(&mut self) -> &mut Connection {
+		&mut self.connection
+	}
+}
+
+/// Formats a frame, or a heart-beat if there isn't one, as a log entry.
+fn format_entry(direction: Direction, frame: Option<&Frame>) -> String {
+	let timestamp = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.unwrap_or_default();
+	let arrow = match direction {
+		Direction::Sent => ">>>",
+		Direction::Received => "<<<",
+	};
+
+	let mut entry = format!(
+		"[{}.{:03}] {} ",
+		timestamp.as_secs(),
+		timestamp.subsec_millis(),
+		arrow
+	);
+
+	if frame.is_none() {
+		entry.push_str("(heart-beat)\n");
+		return entry;
+	}
+	let frame = frame.unwrap();
+
+	let _ = writeln!(entry, "{}", frame.command);
+	for (name, value) in frame.headers.iter() {
+		// Keep passwords out of logs
+		if name.eq_ignore_ascii_case("passcode") {
+			let _ = writeln!(entry, "    {}: (hidden)", name);
+			continue;
+		}
+
+		let _ = writeln!(entry, "    {}: {}", name, value.escape_debug());
+	}
+	if let Some(body) = frame.body.as_deref().filter(|body| !body.is_empty()) {
+		let _ = writeln!(
+			entry,
+			"    {}",
+			String::from_utf8_lossy(body).escape_debug()
+		);
+	}
+
+	entry
+}
@@ -1,14 +1,354 @@
+use std::ops::Index;
+
 pub enum Headers {
+	Ack,
+	ContentEncoding,
 	ContentLength,
 	ContentType,
+	Destination,
+	HeartBeat,
+	JmsRedelivered,
+	JmsxDeliveryCount,
+	MessageId,
+	ReceiptId,
+	Redelivered,
+	Subscription,
 }
 
 impl Headers {
 	/// Converts the header to its name.
 	pub fn as_str(&self) -> &'static str {
 		match self {
+			Headers::Ack => "ack",
+			Headers::ContentEncoding => "content-encoding",
 			Headers::ContentLength => "content-length",
-			Headers::ContentType => "content_hyphen_type", // bruh
+			Headers::ContentType => "content-type",
+			Headers::Destination => "destination",
+			Headers::HeartBeat => "heart-beat",
+			Headers::JmsRedelivered => "JMSRedelivered",
+			Headers::JmsxDeliveryCount => "JMSXDeliveryCount",
+			Headers::MessageId => "message-id",
+			Headers::ReceiptId => "receipt-id",
+			Headers::Redelivered => "redelivered",
+			Headers::Subscription => "subscription",
+		}
+	}
+}
+
+/// Escapes the special characters in a header value, as STOMP 1.1 onwards expects.
+pub fn escape_value(value: &str) -> String {
+	value
+		.replace('\\', "\\\\")
+		.replace('\r', "\\r")
+		.replace('\n', "\\n")
+		.replace(':', "\\c")
+}
+
+/// Unescapes the special characters in a header value, in a single pass so an escaped backslash is never reused.
+/// Anything other than the four escape sequences STOMP 1.2 defines is returned as the error, including a lone backslash at the end.
+pub fn unescape_value(value: &str) -> Result<String, String> {
+	let mut unescaped = String::with_capacity(value.len());
+	let mut characters = value.chars();
+
+	while let Some(character) = characters.next() {
+		if character != '\\' {
+			unescaped.push(character);
+			continue;
+		}
+
+		match characters.next() {
+			Some('r') => unescaped.push('\r'),
+			Some('n') => unescaped.push('\n'),
+			Some('c') => unescaped.push(':'),
+			Some('\\') => unescaped.push('\\'),
+			Some(other) => return Err(format!("\\{}", other)),
+			None => return Err("\\".to_string()),
+		}
+	}
+
+	Ok(unescaped)
+}
+
+/// Represents the headers of a STOMP frame.
+///
+/// Headers are kept in the order they were received, including any repeated names.
+/// Lookups by name are case-insensitive, and use the first of any repeated names, as the specification says.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeaderMap {
+	entries: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+	/// Creates an empty set of headers.
+	pub fn new() -> HeaderMap {
+		HeaderMap {
+			entries: Vec::new(),
+		}
+	}
+
+	/// Gets the value of the first header with the given name.
+	pub fn get(&self, name: &str) -> Option<&str> {
+		self.entries
+			.iter()
+			.find(|(key, _)| key.eq_ignore_ascii_case(name))
+			.map(|(_, value)| value.as_str())
+	}
+
+	/// Gets the values of every header with the given name, in order.
+	pub fn get_all(&self, name: &str) -> Vec<&str> {
+		self.entries
+			.iter()
+			.filter(|(key, _)| key.eq_ignore_ascii_case(name))
+			.map(|(_, value)| value.as_str())
+			.collect()
+	}
+
+	/// Checks if at least one header with the given name exists.
+	pub fn contains(&self, name: &str) -> bool {
+		self.get(name).is_some()
+	}
+
+	/// Adds a header to the end, keeping any existing headers with the same name.
+	pub fn append(&mut self, name: &str, value: &str) {
+		self.entries.push((name.to_string(), value.to_string()));
+	}
+
+	/// Replaces the value of the first header with the given name, or adds it to the end if there isn't one.
+	/// Any later headers with the same name are left alone.
+	pub fn set(&mut self, name: &str, value: &str) {
+		match self
+			.entries
+			.iter_mut()
+			.find(|(key, _)| key.eq_ignore_ascii_case(name))
+		{
+			Some((_, existing)) => *existing = value.to_string(),
+			None => self.append(name, value),
+		}
+	}
+
+	/// Replaces every header with the given name with just one, where the first of them was, or adds it to the end.
+	/// Returns the value of the first header that was replaced, if there was one.
+	pub fn insert(&mut self, name: &str, value: &str) -> Option<String> {
+		let Some(first) = self
+			.entries
+			.iter()
+			.position(|(key, _)| key.eq_ignore_ascii_case(name))
+		else {
+			self.append(name, value);
+			return None;
+		};
+
+		let previous = std::mem::replace(&mut self.entries[first].1, value.to_string());
+
+		// Only remove the later ones, so the replaced header keeps its place
+		let mut index = 0;
+		self.entries.retain(|(key, _)| {
+			let keep = index <= first || !key.eq_ignore_ascii_case(name);
+			index += 1;
+			keep
+		});
+
+		Some(previous)
+	}
+
+	/// Removes every header with the given name, returning how many there were.
+	pub fn remove(&mut self, name: &str) -> usize {
+		let count = self.len();
+		self.entries
+			.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+
+		count - self.len()
+	}
+
+	/// Iterates over every header as name-value pairs, in order.
+	pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+		self.entries
+			.iter()
+			.map(|(name, value)| (name.as_str(), value.as_str()))
+	}
+
+	/// Gets the number of headers, including repeated names.
+	pub fn len(&self) -> usize {
+		self.entries.len()
+	}
+
+	/// Checks if there are no headers.
+	pub fn is_empty(&self) -> bool {
+		self.entries.is_empty()
+	}
+
+	/// Escapes the special characters in every value, as STOMP 1.1 onwards expects.
+	pub fn escaped(&self) -> HeaderMap {
+		self.entries
+			.iter()
+			.map(|(name, value)| (name.clone(), escape_value(value)))
+			.collect()
+	}
+}
+
+impl Index<&str> for HeaderMap {
+	type Output = str;
+
+	/// Gets the value of the first header with the given name, panicking if it doesn't exist.
+	fn index(&self, name: &str) -> &str {
+		self.get(name)
+			.unwrap_or_else(|| panic!("Header '{}' does not exist", name))
+	}
+}
+
+impl From<Vec<(String, String)>> for HeaderMap {
+	fn from(entries: Vec<(String, String)>) -> HeaderMap {
+		HeaderMap { entries }
+	}
+}
+
+impl From<HeaderMap> for Vec<(String, String)> {
+	fn from(headers: HeaderMap) -> Vec<(String, String)> {
+		headers.entries
+	}
+}
+
+impl IntoIterator for HeaderMap {
+	type Item = (String, String);
+	type IntoIter = std::vec::IntoIter<(String, String)>;
+
+	fn into_iter(self) -> Self::IntoIter {
+		self.entries.into_iter()
+	}
+}
+
+impl<'a> IntoIterator for &'a HeaderMap {
+	type Item = (&'a str, &'a str);
+	type IntoIter = Box<dyn Iterator<Item = (&'a str, &'a str)> + 'a>;
+
+	fn into_iter(self) -> Self::IntoIter {
+		Box::new(self.iter())
+	}
+}
+
+impl FromIterator<(String, String)> for HeaderMap {
+	fn from_iter<I: IntoIterator<Item = (String, String)>>(iter: I) -> HeaderMap {
+		HeaderMap {
+			entries: iter.into_iter().collect(),
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn unescapes_spec_sequences() {
+		assert_eq!(unescape_value("a\\rb"), Ok("a\rb".to_string()));
+		assert_eq!(unescape_value("a\\nb"), Ok("a\nb".to_string()));
+		assert_eq!(unescape_value("a\\cb"), Ok("a:b".to_string()));
+		assert_eq!(unescape_value("a\\\\b"), Ok("a\\b".to_string()));
+		assert_eq!(unescape_value("plain"), Ok("plain".to_string()));
+	}
+
+	#[test]
+	fn unescapes_escaped_backslash_before_n_once() {
+		// An escaped backslash followed by n is a backslash & an n, not a line feed
+		assert_eq!(unescape_value("\\\\n"), Ok("\\n".to_string()));
+		assert_eq!(unescape_value("\\\\\\n"), Ok("\\\n".to_string()));
+	}
+
+	#[test]
+	fn rejects_trailing_backslash() {
+		assert_eq!(unescape_value("value\\"), Err("\\".to_string()));
+	}
+
+	#[test]
+	fn rejects_undefined_escape() {
+		assert_eq!(unescape_value("a\\tb"), Err("\\t".to_string()));
+	}
+
+	#[test]
+	fn escape_then_unescape_is_identity() {
+		let value = "a:b\\c\r\nd";
+
+		assert_eq!(unescape_value(&escape_value(value)), Ok(value.to_string()));
+	}
+
+	#[test]
+	fn connected_values_are_not_unescaped() {
+		let (frame, _) = crate::frame::parse(b"CONNECTED\nversion:1.2\nsession:a\\cb\\\\c\n\n\x00")
+			.unwrap()
+			.unwrap();
+
+		assert_eq!(frame.get_header("session"), Some("a\\cb\\\\c"));
+
+		// Anything after the handshake is unescaped as usual
+		let (frame, _) = crate::frame::parse(b"MESSAGE\nsession:a\\cb\\\\c\n\n\x00")
+			.unwrap()
+			.unwrap();
+		assert_eq!(frame.get_header("session"), Some("a:b\\c"));
+	}
+
+	/// Creates headers from name-value pairs, in order.
+	fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+		let mut headers = HeaderMap::new();
+		for (name, value) in pairs {
+			headers.append(name, value);
+		}
+
+		headers
+	}
+
+	/// Gets every header as name-value pairs, in order.
+	fn pairs(headers: &HeaderMap) -> Vec<(&str, &str)> {
+		headers.iter().collect()
+	}
+
+	#[test]
+	fn gets_ignoring_case() {
+		let headers = headers(&[
+			("Content-Type", "text/plain"),
+			("content-type", "text/html"),
+		]);
+
+		assert_eq!(headers.get("CONTENT-TYPE"), Some("text/plain"));
+		assert_eq!(headers.get_all("content-TYPE"), ["text/plain", "text/html"]);
+		assert!(headers.contains("content-type"));
+		assert_eq!(headers.get("destination"), None);
+		assert_eq!(&headers["Content-Type"], "text/plain");
+	}
+
+	#[test]
+	fn sets_only_first_header() {
+		let mut headers = headers(&[("a", "1"), ("A", "2")]);
+
+		headers.set("a", "3");
+		headers.set("b", "4");
+
+		assert_eq!(pairs(&headers), [("a", "3"), ("A", "2"), ("b", "4")]);
+	}
+
+	#[test]
+	fn insert_keeps_first_position() {
+		let mut headers = headers(&[("x", "1"), ("a", "2"), ("y", "3"), ("A", "4")]);
+
+		assert_eq!(headers.insert("a", "5"), Some("2".to_string()));
+		assert_eq!(pairs(&headers), [("x", "1"), ("a", "5"), ("y", "3")]);
+
+		assert_eq!(headers.insert("z", "6"), None);
+		assert_eq!(pairs(&headers).last(), Some(&("z", "6")));
+	}
+
+	#[test]
+	fn remove_counts_every_header() {
+		let mut headers = headers(&[("a", "1"), ("b", "2"), ("A", "3")]);
+
+		assert_eq!(headers.remove("a"), 2);
+		assert_eq!(headers.remove("a"), 0);
+		assert_eq!(pairs(&headers), [("b", "2")]);
+	}
+
+	#[test]
+	fn escaped_escapes_values_only() {
+		let headers = headers(&[("a:b", "c:d\n")]).escaped();
+
+		assert_eq!(pairs(&headers), [("a:b", "c\\cd\\n")]);
+	}
+}
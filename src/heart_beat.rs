@@ -0,0 +1,298 @@
+use std::{
+	sync::Arc,
+	thread::sleep,
+	time::{Duration, Instant},
+};
+
+use crate::{
+	error::StompError,
+	frame_log::Direction,
+	shared::{lock, Shared},
+};
+
+/// The longest to sleep for at once between heart-beats, so closing isn't held up.
+const MAX_HEART_BEAT_WAIT: Duration = Duration::from_millis(100);
+
+/// How many of the server's heart-beat intervals can pass without anything arriving before it is considered gone.
+const MISSED_HEART_BEAT_LIMIT: u32 = 2;
+
+/// How often heart-beats are sent to & expected from the STOMP server, where zero means never.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HeartBeat {
+	/// How often something is sent, even if it is only a heart-beat.
+	pub send: Duration,
+
+	/// How often something is expected to be received.
+	pub receive: Duration,
+}
+
+impl HeartBeat {
+	/// Creates heart-beat intervals for each direction.
+	pub fn new(send: Duration, receive: Duration) -> HeartBeat {
+		HeartBeat { send, receive }
+	}
+
+	/// Converts the intervals to the value of the heart-beat header, in milliseconds.
+	pub fn to_header_value(&self) -> String {
+		format!("{},{}", self.send.as_millis(), self.receive.as_millis())
+	}
+
+	/// Converts the value of a heart-beat header to intervals, treating anything malformed as never.
+	pub fn from_header_value(value: &str) -> HeartBeat {
+		let (send, receive) = parse_header_value(value).unwrap_or((0, 0));
+
+		HeartBeat::new(
+			Duration::from_millis(send.into()),
+			Duration::from_millis(receive.into()),
+		)
+	}
+
+	/// Works out the intervals actually used, from what we asked for & what the server asked for in return.
+	/// Each direction uses the slower of the two intervals, or never if either side doesn't want it.
+	pub fn negotiate(&self, server: &HeartBeat) -> HeartBeat {
+		HeartBeat {
+			send: negotiate_interval(self.send, server.receive),
+			receive: negotiate_interval(self.receive, server.send),
+		}
+	}
+}
+
+/// Parses the value of a heart-beat header, such as `10000,10000`, into both intervals in milliseconds.
+pub fn parse_header_value(value: &str) -> Result<(u32, u32), StompError> {
+	let invalid = || StompError::InvalidHeader {
+		name: "heart-beat".to_string(),
+		value: value.to_string(),
+	};
+
+	let (send, receive) = value.split_once(',').ok_or_else(invalid)?;
+
+	Ok((
+		send.trim().parse::<u32>().map_err(|_| invalid())?,
+		receive.trim().parse::<u32>().map_err(|_| invalid())?,
+	))
+}
+
+/// Picks the interval for one direction, from what the sending & receiving sides want.
+fn negotiate_interval(sender: Duration, receiver: Duration) -> Duration {
+	if sender.is_zero() || receiver.is_zero() {
+		return Duration::ZERO;
+	}
+
+	sender.max(receiver)
+}
+
+/// Checks something has arrived from the STOMP server recently enough, if it agreed to send heart-beats.
+/// A server that keeps the TCP stream open without sending anything has most likely gone away.
+pub(crate) fn check_received(shared: &Shared) -> Result<(), StompError> {
+	let interval =
+		lock(&shared.agreed_heart_beat).map_or(Duration::ZERO, |heart_beat| heart_beat.receive);
+	if interval.is_zero() {
+		return Ok(());
+	}
+
+	if shared.last_received().elapsed() > interval * MISSED_HEART_BEAT_LIMIT {
+		return Err(StompError::HeartbeatTimeout { interval });
+	}
+
+	Ok(())
+}
+
+/// Sends a heart-beat whenever nothing else has been sent for the interval, until the connection is closed.
+pub(crate) fn send_heart_beats(shared: Arc<Shared>, interval: Duration) {
+	while !shared.is_closed() {
+		// Wait until the interval has passed since anything was last sent
+		let idle = Instant::now().saturating_duration_since(shared.last_written());
+		if idle < interval {
+			sleep((interval - idle).min(MAX_HEART_BEAT_WAIT));
+			continue;
+		}
+
+		if shared.write(b"\n").is_err() {
+			return; // Nothing more can be sent
+		}
+		shared.observe(Direction::Sent, None);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{sync::mpsc::channel, thread, time::Instant};
+
+	use super::*;
+	use crate::test_support::{frame_bytes, FakeServer};
+
+	fn millis(send: u64, receive: u64) -> HeartBeat {
+		HeartBeat::new(Duration::from_millis(send), Duration::from_millis(receive))
+	}
+
+	#[test]
+	fn negotiates_each_direction() {
+		// Ours, the server's, then what's agreed
+		let table = [
+			((0, 0), (0, 0), (0, 0)),
+			((100, 0), (0, 200), (200, 0)),
+			((0, 100), (200, 0), (0, 200)),
+			((300, 300), (100, 100), (300, 300)),
+			((100, 100), (0, 0), (0, 0)),
+			((0, 0), (100, 100), (0, 0)),
+			((100, 200), (300, 50), (100, 300)),
+		];
+
+		for (ours, servers, agreed) in table {
+			let ours = millis(ours.0, ours.1);
+			let servers = millis(servers.0, servers.1);
+
+			assert_eq!(
+				ours.negotiate(&servers),
+				millis(agreed.0, agreed.1),
+				"{ours:?} with {servers:?}"
+			);
+		}
+	}
+
+	#[test]
+	fn converts_header_values() {
+		assert_eq!(millis(10000, 500).to_header_value(), "10000,500");
+		assert_eq!(HeartBeat::from_header_value(" 10 , 20 "), millis(10, 20));
+		assert_eq!(HeartBeat::from_header_value("10"), HeartBeat::default());
+		assert_eq!(HeartBeat::from_header_value("-1,5"), HeartBeat::default());
+		assert!(parse_header_value("a,b").is_err());
+	}
+
+	#[test]
+	fn sends_heart_beats_once_agreed() {
+		let server = FakeServer::silent();
+		let connection = server
+			.builder()
+			.heart_beat(Duration::from_millis(20), Duration::ZERO)
+			.open()
+			.unwrap();
+		server.write(&frame_bytes(
+			"CONNECTED",
+			&[("version", "1.2"), ("heart-beat", "0,20")],
+			"",
+		));
+
+		let deadline = Instant::now() + Duration::from_secs(2);
+		while server.heart_beat_count() < 3 && Instant::now() < deadline {
+			sleep(Duration::from_millis(10));
+		}
+
+		assert!(server.heart_beat_count() >= 3);
+		assert_eq!(connection.heart_beat(), Some(millis(20, 0)));
+	}
+
+	#[test]
+	fn dropping_connection_stops_heart_beats() {
+		let server = FakeServer::silent();
+		let connection = server
+			.builder()
+			.heart_beat(Duration::from_millis(20), Duration::ZERO)
+			.open()
+			.unwrap();
+		server.write(&frame_bytes(
+			"CONNECTED",
+			&[("version", "1.2"), ("heart-beat", "0,20")],
+			"",
+		));
+		while connection.heart_beat().is_none() {
+			sleep(Duration::from_millis(10));
+		}
+
+		drop(connection);
+
+		assert!(server.wait_for_eof(Duration::from_secs(2)));
+	}
+
+	#[test]
+	fn heart_beats_are_sent_from_a_named_thread() {
+		let server = FakeServer::silent();
+		let connection = server
+			.builder()
+			.heart_beat(Duration::from_millis(20), Duration::ZERO)
+			.thread_name_prefix("orders")
+			.open()
+			.unwrap();
+		let (name_sender, name_receiver) = channel();
+		*lock(&connection.shared.frame_observer) = Some(Box::new(move |direction, frame| {
+			if direction == Direction::Sent && frame.is_none() {
+				let _ = name_sender.send(thread::current().name().map(str::to_string));
+			}
+		}));
+
+		server.write(&frame_bytes(
+			"CONNECTED",
+			&[("version", "1.2"), ("heart-beat", "0,20")],
+			"",
+		));
+
+		let name = name_receiver.recv_timeout(Duration::from_secs(1)).unwrap();
+		assert_eq!(name.as_deref(), Some("orders-heart-beat"));
+	}
+
+	/// Opens a connection that expects the server to heart-beat every 50ms, once the server has agreed to.
+	fn expecting_heart_beats(server: &FakeServer) -> crate::Connection {
+		let connection = server
+			.builder()
+			.heart_beat(Duration::ZERO, Duration::from_millis(50))
+			.open()
+			.unwrap();
+		server.write(&frame_bytes(
+			"CONNECTED",
+			&[("version", "1.2"), ("heart-beat", "50,0")],
+			"",
+		));
+		assert_eq!(
+			connection
+				.recv_timeout(Duration::from_secs(1))
+				.unwrap()
+				.command,
+			"CONNECTED"
+		);
+
+		connection
+	}
+
+	#[test]
+	fn silent_server_times_out() {
+		let server = FakeServer::silent();
+		let connection = expecting_heart_beats(&server);
+
+		let result = connection.recv_timeout(Duration::from_secs(2));
+
+		match result {
+			Err(error @ StompError::HeartbeatTimeout { .. }) => {
+				assert!(error.is_transient());
+				assert!(
+					matches!(error, StompError::HeartbeatTimeout { interval } if interval == Duration::from_millis(50))
+				);
+			}
+			result => panic!("Expected a heart-beat timeout, got {:?}", result),
+		}
+	}
+
+	#[test]
+	fn heart_beating_server_stays_connected() {
+		let server = FakeServer::silent();
+		let connection = expecting_heart_beats(&server);
+
+		for _ in 0..15 {
+			server.write(b"\n");
+			sleep(Duration::from_millis(20));
+			assert!(matches!(connection.try_recv(), Ok(None)));
+		}
+		assert!(connection.is_writable());
+	}
+
+	#[test]
+	fn skips_heart_beats_from_the_server() {
+		let server = FakeServer::silent();
+		let connection = server.connect();
+
+		server.write(b"\n\r\n\n");
+		server.write(&frame_bytes("RECEIPT", &[("receipt-id", "1")], ""));
+
+		let frame = connection.recv_timeout(Duration::from_secs(1)).unwrap();
+		assert_eq!(frame.command, "RECEIPT");
+	}
+}
@@ -1,61 +1,988 @@
+use self::broker::Broker;
+use self::builder::ConnectionBuilder;
+use self::connected::ConnectedInfo;
+use self::destination::{AdvisoryType, Destination, FanOut};
+use self::error::{ServerError, StompError};
 use self::frame::Frame;
-use std::error::Error;
-use std::io::{Read, Write};
-use std::net::{Shutdown, TcpStream, ToSocketAddrs};
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::thread::{spawn, JoinHandle};
-use std::time::Duration;
+use self::heart_beat::HeartBeat;
+use self::message::Delivery;
+use self::receipt::RetryPolicy;
+use self::receiver::FrameReceiver;
+use self::shared::{lock, Shared};
+use self::stats::Stats;
+use self::subscription::{AckMode, SubscriptionInfo};
+use self::version::StompVersion;
+use std::net::TcpStream;
+use std::sync::mpsc::{RecvTimeoutError, TryRecvError};
+use std::sync::Arc;
+use std::thread::{sleep, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+pub mod broker;
+pub mod builder;
+pub mod charset;
+pub mod command;
+pub mod connected;
+pub mod dead_letter;
+pub mod destination;
+pub mod error;
+pub mod flow_control;
 pub mod frame;
+pub mod frame_log;
 pub mod header;
+pub mod heart_beat;
+pub mod message;
+pub mod pool;
+pub mod rate_limit;
+pub mod receipt;
+mod receive;
+pub mod receiver;
+pub mod reconnect;
+pub mod router;
+pub mod serializer;
+mod shared;
+pub mod stats;
+pub mod stream;
+pub mod streaming;
+pub mod subscription;
+#[cfg(test)]
+mod test_support;
+mod uuid;
+pub mod version;
 
 const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
 
+/// How often to check if the receive thread has finished when waiting with a timeout.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
 /// Represents a connection to a STOMP server.
 pub struct Connection {
-	tcp_stream: TcpStream,
+	shared: Arc<Shared>,
 	receive_thread: Option<JoinHandle<()>>,
+	tcp_stream: TcpStream, // Same socket as everything else, only used for configuring it
 	host_header: String,
-	pub frame_receiver: Receiver<Result<Frame, String>>, // String instead of Box<dyn Error> as the latter doesn't implement Send trait
+	broker: Option<Broker>,
+	temp_queues: Vec<u32>, // Subscription identifiers
+	operation_timeout: Duration,
+	pub frame_receiver: FrameReceiver,
 }
 
 impl Connection {
+	/// Configures this connection to work around the quirks of a specific STOMP server implementation.
+	pub fn with_broker(mut self, broker: Broker) -> Connection {
+		self.broker = Some(broker);
+
+		lock(&self.shared.parse_options).lenient_content_length = broker.lenient_content_length();
+
+		self
+	}
+
+	/// Gets the underlying TCP stream, such as for setting socket options the builder doesn't have.
+	///
+	/// This is the same socket that frames are sent & received on, so reading from, writing to or
+	/// shutting down the stream directly will corrupt the connection. Changing options such as timeouts
+	/// while the receive thread is running can also change how it behaves.
+	pub fn tcp_stream_ref(&self) -> &TcpStream {
+		&self.tcp_stream
+	}
+
+	/// Gets the underlying TCP stream mutably. The same caveats as `tcp_stream_ref()` apply.
+	pub fn tcp_stream_mut(&mut self) -> &mut TcpStream {
+		&mut self.tcp_stream
+	}
+
+	/// Gets the STOMP server implementation this connection is configured for, if any.
+	pub fn broker(&self) -> Option<Broker> {
+		self.broker
+	}
+
+	/// Sends the CONNECT frame to the STOMP server, then waits for the CONNECTED frame in reply.
+	/// Waits for the operation timeout if no timeout is given. An ERROR frame is given back as `StompError::AuthFailed`.
+	pub fn connect_and_authenticate(
+		&mut self,
+		username: &str,
+		password: &str,
+		timeout: Option<Duration>,
+	) -> Result<Frame, StompError> {
+		self.authenticate(username, password)?;
+
+		let frame = self.await_connected(timeout)?;
+		if frame.command == "ERROR" {
+			return Err(StompError::AuthFailed(ServerError::try_from(&frame)?));
+		}
+
+		Ok(frame)
+	}
+
 	// Sends the CONNECT frame to the STOMP server.
-	pub fn authenticate(&mut self, username: &str, password: &str) -> Result<(), Box<dyn Error>> {
-		let headers = vec![
-			("accept-version", "1.2"),
-			("host", self.host_header.as_str()),
-			("heart-beat", "0,0"), // TODO: Implement heart-beating
-			("login", username),
-			("passcode", password),
-		];
+	pub fn authenticate(&mut self, username: &str, password: &str) -> Result<(), StompError> {
+		let frame = self
+			.connect_frame()
+			.with_header("login", username)
+			.with_header("passcode", password);
+
+		self.send_frame(&frame)
+	}
+
+	/// Sends the CONNECT frame to the STOMP server without any credentials, then waits for the CONNECTED frame in reply.
+	/// An ERROR frame is given back as `StompError::AuthRequired`, as the server wants credentials.
+	pub fn authenticate_anonymous(&mut self) -> Result<ConnectedInfo, StompError> {
+		let frame = self.connect_frame();
+		self.send_frame(&frame)?;
+
+		let frame = self.await_connected(None)?;
+		if frame.command == "ERROR" {
+			return Err(StompError::AuthRequired(ServerError::try_from(&frame)?));
+		}
+
+		ConnectedInfo::try_from(&frame)
+	}
+
+	/// Creates the CONNECT frame, without any credentials.
+	fn connect_frame(&self) -> Frame {
+		let mut frame = Frame::new("CONNECT")
+			.with_header("accept-version", &self.shared.accept_version)
+			.with_header("host", self.host_header.as_str());
+
+		// STOMP 1.0 has no heart-beating
+		if self.shared.accept_version != "1.0" {
+			frame
+				.headers
+				.append("heart-beat", &self.shared.heart_beat.to_header_value());
+		}
+
+		frame
+	}
 
-		let frame = frame::create("CONNECT", Some(headers), None);
+	/// Waits for the CONNECTED or ERROR frame in reply to the CONNECT frame, for the operation timeout if no timeout is given.
+	fn await_connected(&mut self, timeout: Option<Duration>) -> Result<Frame, StompError> {
+		self.await_frame(
+			timeout.unwrap_or(self.operation_timeout),
+			|result| match result {
+				Ok(frame) => frame.command == "CONNECTED" || frame.command == "ERROR",
+				Err(error) => !error.is_recoverable(),
+			},
+		)
+	}
+
+	/// Subscribes to a destination on the STOMP server.
+	pub fn subscribe(
+		&mut self,
+		identifier: u32,
+		destination: impl Into<Destination>,
+	) -> Result<(), StompError> {
+		self.subscribe_with_ack(identifier, destination, AckMode::Auto)
+	}
 
-		self.tcp_stream.write_all(frame.as_bytes())?;
+	/// Subscribes to a destination on the STOMP server, acknowledging messages in the given mode.
+	pub fn subscribe_with_ack(
+		&mut self,
+		identifier: u32,
+		destination: impl Into<Destination>,
+		ack_mode: AckMode,
+	) -> Result<(), StompError> {
+		self.subscribe_with_headers(identifier, destination.into(), ack_mode, Vec::new())?;
 
 		Ok(())
 	}
 
-	/// Subscribes to a topic on the STOMP server.
-	pub fn subscribe(&mut self, identifier: u32, topic: &str) -> Result<(), Box<dyn Error>> {
+	/// Subscribes to a destination on the STOMP server, under a name that outlives this connection.
+	/// Requires a broker to be configured with `with_broker`, as each one uses different headers.
+	pub fn subscribe_durable(
+		&mut self,
+		identifier: u32,
+		destination: impl Into<Destination>,
+		name: &str,
+	) -> Result<(), StompError> {
+		let broker = self.broker.ok_or_else(|| StompError::BrokerRequired {
+			feature: "Durable subscriptions".to_string(),
+		})?;
+
+		self.subscribe_with_headers(
+			identifier,
+			destination.into(),
+			AckMode::Auto,
+			broker.durable_subscription_headers(name),
+		)?;
+
+		Ok(())
+	}
+
+	/// Subscribes to a destination on the STOMP server, expiring messages that wait longer than the TTL to be delivered.
+	/// Requires a broker that supports it to be configured with `with_broker`, see `Broker` for how each one behaves.
+	pub fn subscribe_with_ttl(
+		&mut self,
+		identifier: u32,
+		destination: impl Into<Destination>,
+		ack_mode: AckMode,
+		ttl: Duration,
+	) -> Result<(), StompError> {
+		let broker = self.broker.ok_or_else(|| StompError::BrokerRequired {
+			feature: "Subscription TTLs".to_string(),
+		})?;
+		let ttl_header =
+			broker
+				.subscription_ttl_header()
+				.ok_or_else(|| StompError::UnsupportedByBroker {
+					feature: "Subscription TTLs".to_string(),
+					broker,
+				})?;
+
+		self.subscribe_with_headers(
+			identifier,
+			destination.into(),
+			ack_mode,
+			vec![(ttl_header, ttl.as_millis().to_string())],
+		)
+	}
+
+	/// Subscribes to every destination whose name matches a regular expression, failing if the pattern is invalid.
+	/// Requires a broker that supports it to be configured with `with_broker`, see `Broker` for how each one behaves.
+	pub fn subscribe_regex(
+		&mut self,
+		identifier: u32,
+		pattern: &str,
+		ack_mode: AckMode,
+	) -> Result<(), StompError> {
+		if !destination::is_valid_regex(pattern) {
+			return Err(StompError::InvalidRegex(pattern.to_string()));
+		}
+
+		let broker = self.broker.ok_or_else(|| StompError::BrokerRequired {
+			feature: "Regex subscriptions".to_string(),
+		})?;
+		let destination =
+			broker
+				.regex_destination(pattern)
+				.ok_or_else(|| StompError::UnsupportedByBroker {
+					feature: "Regex subscriptions".to_string(),
+					broker,
+				})?;
+
+		self.subscribe_with_headers(
+			identifier,
+			Destination::Custom(destination),
+			ack_mode,
+			Vec::new(),
+		)
+	}
+
+	/// Subscribes to an ActiveMQ advisory topic, to be notified of events inside the server.
+	pub fn subscribe_advisory(
+		&mut self,
+		identifier: u32,
+		advisory_type: AdvisoryType,
+	) -> Result<(), StompError> {
+		self.subscribe_with_headers(
+			identifier,
+			advisory_type.to_destination(),
+			AckMode::Auto,
+			Vec::new(),
+		)
+	}
+
+	/// Subscribes to several destinations on the STOMP server, in order.
+	/// If any of them can't be sent, the ones already sent are unsubscribed from before the error is returned.
+	pub fn subscribe_multi(
+		&mut self,
+		subscriptions: Vec<(u32, &str, AckMode)>,
+	) -> Result<(), StompError> {
+		self.subscribe_all(subscriptions, None)
+	}
+
+	/// Subscribes to several destinations on the STOMP server, then waits for the server to confirm all of them.
+	/// The last SUBSCRIBE frame asks for a receipt, which the server only sends once every frame before it has been handled.
+	/// Waits for the operation timeout if no timeout is given. If anything fails, every one of them is unsubscribed from.
+	pub fn subscribe_multi_with_receipt(
+		&mut self,
+		subscriptions: Vec<(u32, &str, AckMode)>,
+		timeout: Option<Duration>,
+	) -> Result<(), StompError> {
+		let timeout = timeout.unwrap_or(self.operation_timeout);
+
+		self.subscribe_all(subscriptions, Some(timeout))
+	}
+
+	/// Sends a SUBSCRIBE frame for each destination, waiting for a receipt on the last one if there is a timeout.
+	fn subscribe_all(
+		&mut self,
+		subscriptions: Vec<(u32, &str, AckMode)>,
+		timeout: Option<Duration>,
+	) -> Result<(), StompError> {
+		let receipt_id = timeout.map(|_| lock(&self.shared.receipts).register());
+		let count = subscriptions.len();
+
+		let mut subscribed = Vec::new();
+		let mut result = Ok(());
+		for (index, (identifier, destination, ack_mode)) in subscriptions.into_iter().enumerate() {
+			// Only the last one needs a receipt, as the server handles frames in order
+			let extra_headers = match &receipt_id {
+				Some(receipt_id) if index + 1 == count => vec![("receipt", receipt_id.clone())],
+				_ => Vec::new(),
+			};
+
+			result = self.subscribe_with_headers(
+				identifier,
+				destination.into(),
+				ack_mode,
+				extra_headers,
+			);
+			if result.is_err() {
+				break;
+			}
+			subscribed.push(identifier);
+		}
+
+		if let (Ok(()), Some(receipt_id), Some(timeout)) = (&result, &receipt_id, timeout) {
+			result = self.shared.wait_for_receipt(receipt_id, timeout);
+		}
+		if let Some(receipt_id) = &receipt_id {
+			lock(&self.shared.receipts).forget(receipt_id);
+		}
+
+		// Don't leave some of them behind
+		if result.is_err() {
+			for identifier in subscribed {
+				let _ = self.unsubscribe(identifier);
+			}
+		}
+
+		result
+	}
+
+	/// Sends a SUBSCRIBE frame with any additional headers.
+	fn subscribe_with_headers(
+		&mut self,
+		identifier: u32,
+		destination: Destination,
+		ack_mode: AckMode,
+		extra_headers: Vec<(&str, String)>,
+	) -> Result<(), StompError> {
+		let id = identifier.to_string();
+		let destination = destination.to_header_value();
+
+		let mut frame = Frame::new("SUBSCRIBE")
+			.with_header("id", &id)
+			.with_header("destination", &destination)
+			.with_header("ack", ack_mode.as_str());
+		for (name, value) in extra_headers {
+			frame.headers.append(name, &value);
+		}
+
+		// Keep track of it for acknowledgements, before any messages can arrive
+		lock(&self.shared.subscriptions).add(&id, &destination, ack_mode);
+
+		let result = self.shared.send(&frame);
+		if result.is_err() {
+			lock(&self.shared.subscriptions).remove(&id);
+		}
+
+		result
+	}
+
+	/// Subscribes to a destination on the STOMP server, asking it to send at most this many messages
+	/// before waiting for them to be acknowledged, so they can be processed in batches.
+	/// Uses the configured broker's prefetch header, or ActiveMQ's if there isn't one.
+	/// Fails with `StompError::UnsupportedByBroker` for brokers without one, such as HornetQ, rather than subscribing without a limit.
+	pub fn subscribe_prefetch_n(
+		&mut self,
+		identifier: u32,
+		destination: impl Into<Destination>,
+		ack_mode: AckMode,
+		count: u32,
+	) -> Result<(), StompError> {
+		let extra_headers = vec![(self.prefetch_header()?, count.to_string())];
+
+		self.subscribe_with_headers(identifier, destination.into(), ack_mode, extra_headers)
+	}
+
+	/// Gets the name of the configured broker's prefetch header, or ActiveMQ's if there isn't one.
+	/// Fails if the configured broker has no way to limit prefetching.
+	fn prefetch_header(&self) -> Result<&'static str, StompError> {
+		let broker = match self.broker {
+			Some(broker) => broker,
+			None => return Ok("activemq.prefetchSize"),
+		};
+
+		broker
+			.prefetch_header()
+			.ok_or_else(|| StompError::UnsupportedByBroker {
+				feature: "Prefetch limits".to_string(),
+				broker,
+			})
+	}
+
+	/// Subscribes to a new temporary queue, which only lasts as long as this connection.
+	/// Returns the destination to send replies to, such as in the reply-to header of `send_request()`.
+	///
+	/// ActiveMQ & RabbitMQ create the queue when it is first used under `/temp-queue/`. It is unsubscribed from
+	/// when the connection is dropped, and the server deletes it once the connection closes.
+	pub fn create_temp_queue(&mut self, identifier: u32) -> Result<String, StompError> {
+		let destination = Destination::TempQueue(uuid::new_v4());
+
+		self.subscribe(identifier, destination.clone())?;
+		self.temp_queues.push(identifier);
+
+		Ok(destination.to_header_value())
+	}
+
+	/// Subscribes to a queue on the STOMP server.
+	pub fn subscribe_queue(&mut self, identifier: u32, name: &str) -> Result<(), StompError> {
+		self.subscribe(identifier, Destination::Queue(name.to_string()))
+	}
+
+	/// Unsubscribes from a previous subscription on the STOMP server.
+	pub fn unsubscribe(&mut self, identifier: u32) -> Result<(), StompError> {
 		let id = identifier.to_string();
 
-		let headers = vec![
-			("id", id.as_str()),
-			("destination", topic),
-			("ack", "auto"), // TODO: Implement acknowledgements
-		];
+		let frame = Frame::new("UNSUBSCRIBE").with_header("id", &id);
+
+		self.send_frame(&frame)?;
+
+		lock(&self.shared.subscriptions).remove(&id);
+
+		Ok(())
+	}
+
+	/// Unsubscribes from every active subscription, such as before disconnecting.
+	/// Every subscription is forgotten even if sending fails, in which case the first failure is returned.
+	pub fn unsubscribe_all(&mut self) -> Result<(), StompError> {
+		// Take them all at once so nothing is left behind
+		let identifiers = lock(&self.shared.subscriptions).clear();
 
-		let frame = frame::create("SUBSCRIBE", Some(headers), None);
+		let mut first_error = None;
+		for id in identifiers {
+			let frame = Frame::new("UNSUBSCRIBE").with_header("id", &id);
 
-		self.tcp_stream.write_all(frame.as_bytes())?;
+			if let Err(error) = self.shared.send(&frame) {
+				first_error.get_or_insert(error);
+			}
+		}
+
+		match first_error {
+			Some(error) => Err(error),
+			None => Ok(()),
+		}
+	}
+
+	/// Sends a message to a destination on the STOMP server.
+	pub fn send(
+		&mut self,
+		destination: impl Into<Destination>,
+		body: &str,
+	) -> Result<(), StompError> {
+		let frame = Frame::new("SEND")
+			.with_header("destination", &destination.into().to_header_value())
+			.with_body(body);
+
+		self.send_frame(&frame)
+	}
+
+	/// Sends a message to a destination on the STOMP server, flushing it out of the TCP stream straight away.
+	/// Returning means the operating system has taken all of the bytes, not that they have reached the server.
+	/// Use `send_with_receipt` to know that the server has received the message.
+	pub fn send_flush(
+		&mut self,
+		destination: impl Into<Destination>,
+		body: &str,
+	) -> Result<(), StompError> {
+		self.send(destination, body)?;
+
+		self.shared.flush()
+	}
+
+	/// Flushes anything written to the TCP stream that the operating system hasn't taken yet.
+	/// Frames are written to the TCP stream without any buffering, so this is only an explicit sync point, and isn't
+	/// needed before waiting for a receipt. Returning doesn't mean the bytes have reached the server.
+	pub fn flush(&mut self) -> Result<(), StompError> {
+		self.shared.flush()
+	}
+
+	/// Sends a message to several destinations on the STOMP server.
+	/// Uses a single frame if the configured broker supports composite destinations, otherwise one frame each.
+	pub fn send_multi(&mut self, destinations: &[&str], body: &str) -> Result<(), StompError> {
+		let fan_out = match self.broker {
+			Some(broker) if broker.supports_composite_destinations() => FanOut::Composite,
+			_ => FanOut::Separate,
+		};
+
+		self.send_multi_with(destinations, body, fan_out)
+	}
+
+	/// Sends a message to several destinations on the STOMP server, in the given way.
+	/// Stops at the first destination that fails when sending separately.
+	pub fn send_multi_with(
+		&mut self,
+		destinations: &[&str],
+		body: &str,
+		fan_out: FanOut,
+	) -> Result<(), StompError> {
+		match fan_out {
+			FanOut::Separate => {
+				for destination in destinations {
+					self.send(*destination, body)?;
+				}
+
+				Ok(())
+			}
+			FanOut::Composite => self.send(destinations.join(","), body),
+		}
+	}
+
+	/// Sends a message to a destination on the STOMP server, with a priority from 0 (lowest) to 9 (highest).
+	///
+	/// The priority header is honoured by ActiveMQ (when `prioritizedMessages` is enabled on the destination),
+	/// Artemis & HornetQ, and by RabbitMQ for queues declared with `x-max-priority`. Other servers ignore it.
+	pub fn send_priority(
+		&mut self,
+		destination: impl Into<Destination>,
+		body: &str,
+		priority: u8,
+		headers: Vec<(&str, &str)>,
+	) -> Result<(), StompError> {
+		if priority > 9 {
+			return Err(StompError::InvalidPriority(priority));
+		}
+
+		let mut frame = Frame::new("SEND")
+			.with_header("destination", &destination.into().to_header_value())
+			.with_header("priority", &priority.to_string())
+			.with_body(body);
+		for (name, value) in headers {
+			frame.headers.append(name, value);
+		}
+
+		self.shared.send(&frame)
+	}
+
+	/// Sends a message to a destination on the STOMP server, which discards it if it isn't consumed before the expiry time.
+	///
+	/// The expires header is honoured by ActiveMQ & Artemis. Other servers ignore it.
+	pub fn send_with_expiry(
+		&mut self,
+		destination: impl Into<Destination>,
+		body: &str,
+		expiry: SystemTime,
+		headers: Vec<(&str, &str)>,
+	) -> Result<(), StompError> {
+		// The header is milliseconds since the Unix epoch
+		let expires = expiry
+			.duration_since(UNIX_EPOCH)
+			.map_err(|_| StompError::ExpiryInPast)?
+			.as_millis();
+		if expiry <= SystemTime::now() {
+			return Err(StompError::ExpiryInPast);
+		}
+
+		let mut frame = Frame::new("SEND")
+			.with_header("destination", &destination.into().to_header_value())
+			.with_header("expires", &expires.to_string())
+			.with_body(body);
+		for (name, value) in headers {
+			frame.headers.append(name, value);
+		}
+
+		self.shared.send(&frame)
+	}
+
+	/// Sends a message to a destination on the STOMP server, asking for it to be kept if the server restarts.
+	///
+	/// The persistent header is honoured by ActiveMQ, Artemis & RabbitMQ, but only if the destination itself is
+	/// durable & the server is configured to store messages. It doesn't confirm the message was stored either,
+	/// use `send_with_receipt` for that.
+	pub fn send_persistent(
+		&mut self,
+		destination: impl Into<Destination>,
+		body: &str,
+		headers: Vec<(&str, &str)>,
+	) -> Result<(), StompError> {
+		let mut frame = Frame::new("SEND")
+			.with_header("destination", &destination.into().to_header_value())
+			.with_header("persistent", "true")
+			.with_body(body);
+		for (name, value) in headers {
+			frame.headers.append(name, value);
+		}
+
+		self.shared.send(&frame)
+	}
+
+	/// Sends a request to a destination on the STOMP server, asking for the reply to be sent to another destination.
+	/// Returns the generated correlation identifier, which the reply is expected to carry.
+	pub fn send_request(
+		&mut self,
+		destination: impl Into<Destination>,
+		body: &str,
+		reply_to: &str,
+	) -> Result<String, StompError> {
+		let correlation_id = uuid::new_v4();
+
+		let frame = Frame::new("SEND")
+			.with_header("destination", &destination.into().to_header_value())
+			.with_header("reply-to", reply_to)
+			.with_header("correlation-id", &correlation_id)
+			.with_body(body);
+
+		self.shared.send(&frame)?;
+
+		Ok(correlation_id)
+	}
+
+	/// Waits for a frame with the given correlation identifier, such as the reply to `send_request()`.
+	/// Anything else that arrives in the meantime is kept, in order, for the next receive.
+	pub fn await_reply(
+		&self,
+		correlation_id: &str,
+		timeout: Duration,
+	) -> Result<Frame, StompError> {
+		self.await_frame(timeout, |result| {
+			result
+				.as_ref()
+				.is_ok_and(|frame| frame.get_header("correlation-id") == Some(correlation_id))
+		})
+	}
+
+	/// Waits for the first frame or error that matches, keeping anything else that arrives in the meantime, in order.
+	fn await_frame(
+		&self,
+		timeout: Duration,
+		mut matches: impl FnMut(&Result<Frame, StompError>) -> bool,
+	) -> Result<Frame, StompError> {
+		let deadline = Instant::now() + timeout;
+		let mut skipped = Vec::new();
+
+		let result = loop {
+			let now = Instant::now();
+			if now >= deadline {
+				break Err(StompError::Timeout);
+			}
+
+			match self.frame_receiver.recv_timeout(deadline - now) {
+				Ok(result) if matches(&result) => break result,
+				Ok(result) => skipped.push(result),
+				Err(RecvTimeoutError::Timeout) => break Err(StompError::Timeout),
+				Err(RecvTimeoutError::Disconnected) => break Err(StompError::ConnectionClosed),
+			}
+		};
+
+		self.frame_receiver.put_back(skipped);
+
+		result
+	}
+
+	/// Sends a message to a destination on the STOMP server as part of a transaction.
+	/// The server only delivers it once the transaction is committed.
+	pub fn send_in_transaction(
+		&mut self,
+		transaction: &str,
+		destination: impl Into<Destination>,
+		body: &str,
+	) -> Result<(), StompError> {
+		if !lock(&self.shared.transactions).contains_key(transaction) {
+			return Err(StompError::UnknownTransaction(transaction.to_string()));
+		}
+
+		let frame = Frame::new("SEND")
+			.with_header("destination", &destination.into().to_header_value())
+			.with_header("transaction", transaction)
+			.with_body(body);
+
+		self.shared.send(&frame)
+	}
+
+	/// Acknowledges a batch of received messages by their identifiers, sending an ACK for each one.
+	/// Fails on the first message that isn't waiting to be acknowledged, leaving the rest alone.
+	pub fn ack_n(&mut self, message_ids: &[&str]) -> Result<(), StompError> {
+		let version = self.shared.version();
+
+		for message_id in message_ids {
+			let (subscription, ack_id) = lock(&self.shared.subscriptions)
+				.find(message_id)
+				.ok_or_else(|| StompError::UnknownMessage(message_id.to_string()))?;
+
+			let frame = message::acknowledgement(
+				"ACK",
+				version,
+				message_id,
+				&subscription,
+				ack_id.as_deref(),
+			)?;
+
+			self.shared.send(&frame)?;
+			self.shared.settle(message_id);
+		}
+
+		Ok(())
+	}
+
+	/// Tells the server a message received on a subscription wasn't consumed, so it can be redelivered.
+	/// STOMP 1.2 identifies the message by its ack header when it is being tracked, otherwise by its message-id.
+	pub fn nack(&mut self, subscription_id: u32, message_id: &str) -> Result<(), StompError> {
+		let version = self.shared.version();
+
+		// NACK frames only exist from STOMP 1.1 onwards
+		if version == Some(StompVersion::V1_0) {
+			return Err(StompError::VersionMismatch {
+				command: "NACK".to_string(),
+				required: StompVersion::V1_1,
+				negotiated: StompVersion::V1_0,
+			});
+		}
+
+		let frame = Frame::new("NACK").with_header("subscription", &subscription_id.to_string());
+		let frame = match version {
+			Some(StompVersion::V1_1) => frame.with_header("message-id", message_id),
+			_ => {
+				let ack_id = lock(&self.shared.subscriptions)
+					.find(message_id)
+					.and_then(|(_, ack_id)| ack_id)
+					.unwrap_or_else(|| message_id.to_string());
+
+				frame.with_header("id", &ack_id)
+			}
+		};
+
+		self.shared.send(&frame)?;
+		self.shared.settle(message_id);
+
+		Ok(())
+	}
+
+	/// Begins a transaction, which groups sends & acknowledgements so they all happen or none do.
+	pub fn begin(&mut self, transaction: &str) -> Result<(), StompError> {
+		let frame = Frame::new("BEGIN").with_header("transaction", transaction);
+
+		self.shared.send(&frame)?;
+
+		lock(&self.shared.transactions).insert(transaction.to_string(), Vec::new());
+
+		Ok(())
+	}
+
+	/// Commits a transaction, so the server acts upon everything sent within it.
+	pub fn commit(&mut self, transaction: &str) -> Result<(), StompError> {
+		let settled = lock(&self.shared.transactions)
+			.remove(transaction)
+			.ok_or_else(|| StompError::UnknownTransaction(transaction.to_string()))?;
+
+		let frame = Frame::new("COMMIT").with_header("transaction", transaction);
+
+		self.shared.send(&frame)?;
+
+		// Acknowledgements within the transaction only count now
+		for message_id in settled {
+			self.shared.settle(&message_id);
+		}
 
 		Ok(())
 	}
 
+	/// Aborts a transaction, so the server discards everything sent within it.
+	pub fn abort(&mut self, transaction: &str) -> Result<(), StompError> {
+		lock(&self.shared.transactions)
+			.remove(transaction)
+			.ok_or_else(|| StompError::UnknownTransaction(transaction.to_string()))?;
+
+		let frame = Frame::new("ABORT").with_header("transaction", transaction);
+
+		self.shared.send(&frame)
+	}
+
+	/// Sends any frame to the STOMP server, using the configured serializer.
+	pub fn send_frame(&mut self, frame: &Frame) -> Result<(), StompError> {
+		self.shared.send(frame)
+	}
+
+	/// Sends a frame with any command, such as a vendor extension the library doesn't know about.
+	/// Nothing is checked or escaped, so the caller is responsible for the frame being valid for the server.
+	/// A content-length header is still added if the body contains a NUL octet & there isn't one already.
+	pub fn send_raw(
+		&mut self,
+		command: &str,
+		headers: Vec<(&str, &str)>,
+		body: Option<&[u8]>,
+	) -> Result<(), StompError> {
+		let mut frame = Frame::new(command);
+		for (name, value) in &headers {
+			frame.headers.append(name, value);
+		}
+		frame.body = body.map(<[u8]>::to_vec);
+
+		let bytes = frame::create_bytes(command, Some(headers), body);
+
+		self.shared.send_raw(&frame, &bytes)
+	}
+
+	/// Sends any frame to the STOMP server, then waits for the server to confirm it with a receipt.
+	pub fn send_with_receipt(
+		&mut self,
+		frame: &Frame,
+		timeout: Option<Duration>,
+	) -> Result<(), StompError> {
+		let timeout = timeout.unwrap_or(self.operation_timeout);
+		let receipt_id = lock(&self.shared.receipts).register();
+
+		let mut frame = frame.clone();
+		frame.headers.append("receipt", &receipt_id);
+
+		let result = self
+			.shared
+			.send(&frame)
+			.and_then(|_| self.shared.wait_for_receipt(&receipt_id, timeout));
+
+		lock(&self.shared.receipts).forget(&receipt_id);
+
+		result
+	}
+
+	/// Sends any frame to the STOMP server, sending it again if a receipt doesn't arrive in time.
+	/// Every attempt uses the same receipt, so a late receipt for an earlier attempt still counts.
+	///
+	/// This can deliver the frame more than once, so only use it when the server deduplicates frames
+	/// or the application tolerates duplicates.
+	pub fn send_with_retry(
+		&mut self,
+		frame: &Frame,
+		policy: &RetryPolicy,
+	) -> Result<(), StompError> {
+		let receipt_id = lock(&self.shared.receipts).register();
+
+		let mut frame = frame.clone();
+		frame.headers.append("receipt", &receipt_id);
+
+		let mut backoff = policy.backoff;
+		let mut result = Err(StompError::Timeout);
+		for attempt in 1..=policy.max_attempts.max(1) {
+			// Give the server a moment before trying again
+			if attempt > 1 {
+				sleep(backoff);
+				backoff *= 2;
+			}
+
+			result = self
+				.shared
+				.send(&frame)
+				.and_then(|_| self.shared.wait_for_receipt(&receipt_id, policy.timeout));
+
+			// Only timeouts are worth another attempt
+			if !matches!(result, Err(StompError::Timeout)) {
+				break;
+			}
+		}
+
+		lock(&self.shared.receipts).forget(&receipt_id);
+
+		result
+	}
+
+	/// Waits for the next frame from the STOMP server, converting MESSAGE frames into messages.
+	/// ERROR frames are given back as `StompError::Server`.
+	pub fn receive(&self) -> Result<Delivery, StompError> {
+		let frame = self.next()?;
+
+		if frame.command == "ERROR" {
+			return Err(StompError::Server(ServerError::try_from(&frame)?));
+		}
+
+		match Delivery::try_from(frame)? {
+			Delivery::Message(message) => Ok(Delivery::Message(message.with_handle(&self.shared))),
+			delivery => Ok(delivery),
+		}
+	}
+
+	/// Waits for the next frame from the STOMP server, without taking it.
+	/// The same frame is given back by the next call to `next()`, `receive()` or the frame receiver.
+	pub fn peek(&mut self) -> Result<&Frame, StompError> {
+		self.frame_receiver.peek()
+	}
+
+	/// Waits for the next frame from the STOMP server, including one that was peeked at.
+	pub fn next(&self) -> Result<Frame, StompError> {
+		self.frame_receiver
+			.recv()
+			.map_err(|_| StompError::ConnectionClosed)?
+	}
+
+	/// Takes the next frame from the STOMP server if one is waiting, without blocking.
+	/// Fails with `StompError::ConnectionClosed` once nothing more can arrive.
+	pub fn try_recv(&self) -> Result<Option<Frame>, StompError> {
+		match self.frame_receiver.try_recv() {
+			Ok(result) => result.map(Some),
+			Err(TryRecvError::Empty) => Ok(None),
+			Err(TryRecvError::Disconnected) => Err(StompError::ConnectionClosed),
+		}
+	}
+
+	/// Waits for the next frame from the STOMP server, giving up with `StompError::Timeout` after a while.
+	pub fn recv_timeout(&self, timeout: Duration) -> Result<Frame, StompError> {
+		match self.frame_receiver.recv_timeout(timeout) {
+			Ok(result) => result,
+			Err(RecvTimeoutError::Timeout) => Err(StompError::Timeout),
+			Err(RecvTimeoutError::Disconnected) => Err(StompError::ConnectionClosed),
+		}
+	}
+
+	/// Checks if frames can still be sent, without sending anything.
+	///
+	/// This is false once the connection has been closed, the server has sent an ERROR frame, or the
+	/// receive thread has stopped because the server closed the TCP stream or it was reset. A half-open
+	/// connection, where the other end has gone away without anything arriving to say so, still looks
+	/// writable until the operating system gives up on it, which can take minutes on Linux & Windows.
+	/// Heart-beats are the only reliable way to notice that sooner.
+	pub fn is_writable(&self) -> bool {
+		if self.shared.is_closed() || lock(&self.shared.poisoned).is_some() {
+			return false;
+		}
+
+		// The receive thread stops as soon as the server closes the TCP stream
+		self.receive_thread
+			.as_ref()
+			.is_some_and(|receive_thread| !receive_thread.is_finished())
+	}
+
+	/// Gets the ERROR frame the STOMP server sent, if it has sent one.
+	/// Nothing more can be sent once it has, as the server closes the connection.
+	pub fn server_error(&self) -> Option<ServerError> {
+		lock(&self.shared.poisoned).clone()
+	}
+
+	/// Gets every subscription that is currently active, including which messages are waiting to be acknowledged.
+	pub fn subscriptions(&self) -> Vec<SubscriptionInfo> {
+		lock(&self.shared.subscriptions).list()
+	}
+
+	/// Gets the identifiers of messages received on a subscription that haven't been acknowledged yet, oldest first.
+	/// These are still available after the connection drops, so the application knows which messages the server will redeliver.
+	pub fn unacknowledged(&self, subscription_id: u32) -> Vec<String> {
+		lock(&self.shared.subscriptions).outstanding(&subscription_id.to_string())
+	}
+
+	/// Gets the heart-beat intervals agreed with the STOMP server, once the CONNECTED frame has arrived.
+	pub fn heart_beat(&self) -> Option<HeartBeat> {
+		*lock(&self.shared.agreed_heart_beat)
+	}
+
+	/// Gets the name & version of the STOMP server, from the server header of the CONNECTED frame, such as for bug reports.
+	pub fn server_info(&self) -> Option<String> {
+		lock(&self.shared.connected_info)
+			.as_ref()
+			.and_then(|connected_info| connected_info.server.clone())
+	}
+
+	/// Gets the identifier the STOMP server gave this session, from the session header of the CONNECTED frame.
+	pub fn session_id(&self) -> Option<String> {
+		lock(&self.shared.connected_info)
+			.as_ref()
+			.and_then(|connected_info| connected_info.session.clone())
+	}
+
+	/// Gets a snapshot of this connection's counters.
+	pub fn stats(&self) -> Stats {
+		self.shared.stats()
+	}
+
 	/// Waits for the connection to close.
-	pub fn wait(&mut self) -> Result<(), Box<dyn Error>> {
+	pub fn wait(&mut self) -> Result<(), StompError> {
 		// Don't bother if the thread no longer exists
 		if self.receive_thread.is_none() {
 			return Ok(());
@@ -64,15 +991,52 @@ impl Connection {
 		// Yoink the thread handle & wait for it to finish
 		let result = self.receive_thread.take().unwrap().join();
 		if result.is_err() {
-			return Err("Unable to join receive thread".into());
+			return Err(StompError::ReceiveThreadPanicked);
 		}
 
 		Ok(())
 	}
 
+	/// Waits for the connection to close, giving up with `StompError::DrainTimeout` after the timeout.
+	pub fn wait_timeout(&mut self, timeout: Duration) -> Result<(), StompError> {
+		// Don't bother if the thread no longer exists
+		if self.receive_thread.is_none() {
+			return Ok(());
+		}
+
+		// Check in on the thread until it finishes or we run out of time
+		let deadline = Instant::now() + timeout;
+		while !self.receive_thread.as_ref().unwrap().is_finished() {
+			let now = Instant::now();
+			if now >= deadline {
+				return Err(StompError::DrainTimeout);
+			}
+
+			sleep(WAIT_POLL_INTERVAL.min(deadline - now));
+		}
+
+		self.wait()
+	}
+
+	/// Sends a DISCONNECT frame & waits for the STOMP server to confirm it, then closes the connection.
+	/// Waits for the operation timeout if no timeout is given. The connection is closed even if confirmation fails.
+	/// The server closing the connection straight after confirming is expected, so isn't reported as an error.
+	pub fn disconnect(&mut self, timeout: Option<Duration>) -> Result<(), StompError> {
+		let result = self.send_with_receipt(&Frame::new("DISCONNECT"), timeout);
+
+		self.close()?;
+
+		result
+	}
+
+	/// Sets how long to wait for the STOMP server to reply when a method isn't given a timeout.
+	pub fn set_operation_timeout(&mut self, operation_timeout: Duration) {
+		self.operation_timeout = operation_timeout;
+	}
+
 	/// Closes the connection to the STOMP server.
-	pub fn close(&mut self) -> Result<(), Box<dyn Error>> {
-		self.tcp_stream.shutdown(Shutdown::Both)?;
+	pub fn close(&mut self) -> Result<(), StompError> {
+		self.shared.shutdown()?;
 
 		self.wait()?;
 
@@ -80,84 +1044,502 @@ impl Connection {
 	}
 }
 
-/// Establishes a connection to a STOMP server.
-pub fn open(
-	host: &str,
-	port: u16,
-	timeout: Option<Duration>,
-) -> Result<Connection, Box<dyn Error>> {
-	// Convert the host name & port number into a usable socket address
-	let address = format!("{}:{}", host, port)
-		.to_socket_addrs()?
-		.last()
-		.expect(format!("Unable to convert '{}:{}' to socket address", host, port).as_str());
-
-	// Open a TCP stream to the this address
-	let tcp_stream = TcpStream::connect_timeout(&address, timeout.unwrap_or(DEFAULT_TIMEOUT))?;
-
-	// Configure this stream
-	tcp_stream.set_nodelay(true)?;
-	tcp_stream.set_write_timeout(timeout.or(Some(DEFAULT_TIMEOUT)))?;
-
-	let (frame_sender, frame_receiver) = channel();
-
-	// Spawn a thread to listen for incoming bytes
-	let tcp_stream_clone = tcp_stream.try_clone()?;
-	let frame_sender_clone = frame_sender.clone();
-	let receive_thread = spawn(move || {
-		let result = receive_bytes(tcp_stream_clone, frame_sender_clone); // Blocks until the TCP stream is closed
-
-		if result.is_err() {
-			let reason = result.err().unwrap_or("Unknown error".into()).to_string();
-			frame_sender.send(Err(reason)).unwrap();
+impl Drop for Connection {
+	/// Unsubscribes from any temporary queues, then closes the connection if it is still open.
+	/// This stops the receive & heart-beat threads, which would otherwise keep the connection alive.
+	fn drop(&mut self) {
+		if self.shared.is_closed() {
 			return;
 		}
-	});
-
-	// Give the caller a handle to this connection
-	Ok(Connection {
-		tcp_stream,
-		receive_thread: Some(receive_thread),
-		host_header: host.to_string(),
-		frame_receiver,
-	})
-}
-
-/// Continuously waits for bytes from the STOMP server.
-fn receive_bytes(
-	mut tcp_stream: TcpStream,
-	frame_sender: Sender<Result<Frame, String>>,
-) -> Result<(), Box<dyn Error>> {
-	let mut receive_buffer = [0; 4096]; // 4 KiB
-	let mut pending_data: Vec<u8> = Vec::new(); // Infinite
 
-	loop {
-		// Try to receive some bytes
-		let received_byte_count = tcp_stream.read(&mut receive_buffer)?;
-		if received_byte_count == 0 {
-			return Ok(()); // Give up, there's nothing left to receive
+		for identifier in std::mem::take(&mut self.temp_queues) {
+			let _ = self.unsubscribe(identifier);
 		}
 
-		// Append the received bytes to the unprocessed data
-		pending_data.extend_from_slice(&receive_buffer[..received_byte_count]);
+		// Nothing can be done about a failure, as the connection is going away anyway
+		let _ = self.shared.shutdown();
+	}
+}
 
-		// Remove any complete frames from the unprocessed data
-		while let Some((frame, end_position)) = frame::parse(&mut pending_data)? {
-			pending_data.drain(..end_position + 1);
-			frame_sender.send(Ok(frame))?;
-		}
+/// Establishes a connection to a STOMP server.
+pub fn open(host: &str, port: u16, timeout: Option<Duration>) -> Result<Connection, StompError> {
+	let mut builder = ConnectionBuilder::new(host, port);
+	if let Some(timeout) = timeout {
+		builder = builder.timeout(timeout);
 	}
+
+	builder.open()
 }
 
-/*
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	use crate::test_support::{connected, frame_bytes, receipt_for, FakeServer, Reply};
+
+	/// Wait this long for something the server should receive.
+	const SERVER_TIMEOUT: Duration = Duration::from_secs(1);
+
+	/// Starts a server that agrees to STOMP 1.2 & sends receipts for anything else that asks.
+	fn receipting_server() -> FakeServer {
+		FakeServer::start(Box::new(|frame| match frame.command.as_str() {
+			"CONNECT" | "STOMP" => vec![connected()],
+			_ => receipt_for(frame),
+		}))
+	}
+
+	/// Connects to a server, waiting for it to agree to a version.
+	fn connect(server: &FakeServer) -> Connection {
+		authenticate(server, server.connect())
+	}
+
+	/// Authenticates a connection that has been opened to a server, waiting for it to agree to a version.
+	fn authenticate(server: &FakeServer, mut connection: Connection) -> Connection {
+		connection
+			.connect_and_authenticate("user", "", None)
+			.unwrap();
+		assert_eq!(
+			server.next_frame(SERVER_TIMEOUT).unwrap().command,
+			"CONNECT"
+		);
+
+		connection
+	}
 
 	#[test]
-	fn it_works() {
-		let result = add(2, 2);
-		assert_eq!(result, 4);
+	fn rejected_credentials_fail_authentication() {
+		let server = FakeServer::start(Box::new(|_| {
+			vec![Reply::Bytes(frame_bytes(
+				"ERROR",
+				&[("message", "Bad credentials")],
+				"",
+			))]
+		}));
+		let mut connection = server.connect();
+
+		let result = connection.connect_and_authenticate("user", "wrong", None);
+
+		assert!(
+			matches!(&result, Err(StompError::AuthFailed(error)) if error.message.as_deref() == Some("Bad credentials")),
+			"{:?}",
+			result
+		);
+	}
+
+	#[test]
+	fn waiting_for_an_open_connection_times_out_draining() {
+		let server = FakeServer::silent();
+		let mut connection = server.connect();
+
+		let result = connection.wait_timeout(Duration::from_millis(50));
+
+		assert!(
+			matches!(result, Err(StompError::DrainTimeout)),
+			"{:?}",
+			result
+		);
+	}
+
+	#[test]
+	fn connect_headers_are_not_escaped() {
+		let server = receipting_server();
+		let mut connection = server.connect();
+
+		connection
+			.connect_and_authenticate("user", "pa:ss\\word", None)
+			.unwrap();
+
+		let connect = server.next_frame(SERVER_TIMEOUT).unwrap();
+		assert_eq!(connect.command, "CONNECT");
+		assert_eq!(connect.get_header("passcode"), Some("pa:ss\\word"));
+	}
+
+	#[test]
+	fn send_headers_are_escaped_once_1_2_is_agreed() {
+		let server = receipting_server();
+		let mut connection = server.connect();
+		connection
+			.connect_and_authenticate("user", "", None)
+			.unwrap();
+		server.next_frame(SERVER_TIMEOUT).unwrap();
+
+		let frame = Frame::new("SEND")
+			.with_header("destination", "/queue/a")
+			.with_header("note", "a:b\nc\\d");
+		connection.send_frame(&frame).unwrap();
+
+		let sent = server.next_frame(SERVER_TIMEOUT).unwrap();
+		assert_eq!(sent.command, "SEND");
+		assert_eq!(sent.get_header("note"), Some("a\\cb\\nc\\\\d"));
+	}
+
+	#[test]
+	fn send_with_receipt_waits_for_receipt() {
+		let server = receipting_server();
+		let mut connection = connect(&server);
+
+		let frame = Frame::new("SEND").with_header("destination", "/queue/a");
+		connection.send_with_receipt(&frame, None).unwrap();
+
+		assert!(server
+			.next_frame(SERVER_TIMEOUT)
+			.unwrap()
+			.has_header("receipt"));
+	}
+
+	#[test]
+	fn send_with_receipt_times_out() {
+		let server = FakeServer::start(Box::new(|frame| match frame.command.as_str() {
+			"CONNECT" => vec![connected()],
+			_ => Vec::new(),
+		}));
+		let mut connection = connect(&server);
+
+		let frame = Frame::new("SEND").with_header("destination", "/queue/a");
+		let result = connection.send_with_receipt(&frame, Some(Duration::from_millis(50)));
+
+		assert!(matches!(result, Err(StompError::Timeout)), "{:?}", result);
+	}
+
+	#[test]
+	fn send_with_retry_resends_until_receipt() {
+		let sends = Arc::new(AtomicUsize::new(0));
+		let counted = sends.clone();
+		let server = FakeServer::start(Box::new(move |frame| match frame.command.as_str() {
+			"CONNECT" => vec![connected()],
+			_ if counted.fetch_add(1, Ordering::SeqCst) == 0 => Vec::new(), // Lose the first attempt
+			_ => receipt_for(frame),
+		}));
+		let mut connection = connect(&server);
+
+		let policy = RetryPolicy {
+			max_attempts: 3,
+			timeout: Duration::from_millis(100),
+			backoff: Duration::from_millis(10),
+		};
+		let frame = Frame::new("SEND").with_header("destination", "/queue/a");
+		connection.send_with_retry(&frame, &policy).unwrap();
+
+		let first = server.next_frame(SERVER_TIMEOUT).unwrap();
+		let second = server.next_frame(SERVER_TIMEOUT).unwrap();
+		assert_eq!(first.get_header("receipt"), second.get_header("receipt"));
+		assert_eq!(sends.load(Ordering::SeqCst), 2);
+	}
+
+	#[test]
+	fn error_for_receipt_fails_send_with_receipt() {
+		let server = FakeServer::start(Box::new(|frame| match frame.command.as_str() {
+			"CONNECT" => vec![connected()],
+			_ => vec![Reply::Bytes(frame_bytes(
+				"ERROR",
+				&[
+					("receipt-id", frame.get_header("receipt").unwrap()),
+					("message", "Not allowed"),
+				],
+				"",
+			))],
+		}));
+		let mut connection = connect(&server);
+
+		let frame = Frame::new("SEND").with_header("destination", "/queue/a");
+		let result = connection.send_with_receipt(&frame, None);
+
+		match result {
+			Err(StompError::Server(error)) => {
+				assert_eq!(error.message.as_deref(), Some("Not allowed"));
+				assert!(error.receipt_id.is_some());
+			}
+			result => panic!("Expected a server error, got {:?}", result),
+		}
+	}
+
+	#[test]
+	fn receive_gives_back_error_as_server_error() {
+		let server = receipting_server();
+		let connection = connect(&server);
+
+		server.write(&frame_bytes(
+			"ERROR",
+			&[
+				("message", "Malformed frame"),
+				("content-type", "text/plain"),
+			],
+			"Details",
+		));
+
+		match connection.receive() {
+			Err(StompError::Server(error)) => {
+				assert_eq!(error.message.as_deref(), Some("Malformed frame"));
+				assert_eq!(error.body.as_deref(), Some("Details"));
+			}
+			result => panic!("Expected a server error, got {:?}", result),
+		}
+		assert!(connection.server_error().is_some());
+		assert!(!connection.is_writable());
+	}
+
+	#[test]
+	fn negotiates_offered_version() {
+		let server = FakeServer::start(Box::new(|frame| match frame.command.as_str() {
+			"CONNECT" => vec![Reply::Bytes(frame_bytes(
+				"CONNECTED",
+				&[("version", "1.1")],
+				"",
+			))],
+			_ => Vec::new(),
+		}));
+		let mut connection = server.builder().accept_version("1.0,1.1").open().unwrap();
+
+		connection
+			.connect_and_authenticate("user", "", None)
+			.unwrap();
+
+		let connect = server.next_frame(SERVER_TIMEOUT).unwrap();
+		assert_eq!(connect.get_header("accept-version"), Some("1.0,1.1"));
+		assert_eq!(connection.shared.version(), Some(StompVersion::V1_1));
+	}
+
+	#[test]
+	fn nack_is_shaped_for_the_agreed_version() {
+		let server = FakeServer::start(Box::new(|frame| match frame.command.as_str() {
+			"CONNECT" => vec![Reply::Bytes(frame_bytes(
+				"CONNECTED",
+				&[("version", "1.1")],
+				"",
+			))],
+			_ => Vec::new(),
+		}));
+		let builder = server.builder().accept_version("1.1");
+		let mut connection = authenticate(&server, builder.open().unwrap());
+
+		connection.nack(1, "message-1").unwrap();
+
+		let nack = server.next_frame(SERVER_TIMEOUT).unwrap();
+		assert_eq!(nack.command, "NACK");
+		assert_eq!(nack.get_header("subscription"), Some("1"));
+		assert_eq!(nack.get_header("message-id"), Some("message-1"));
+		assert!(!nack.has_header("id"));
+	}
+
+	#[test]
+	fn nack_is_refused_under_1_0() {
+		let server = FakeServer::start(Box::new(|frame| match frame.command.as_str() {
+			"CONNECT" => vec![Reply::Bytes(frame_bytes("CONNECTED", &[], ""))],
+			_ => Vec::new(),
+		}));
+		let builder = server.builder().accept_version("1.0");
+		let mut connection = authenticate(&server, builder.open().unwrap());
+
+		let result = connection.nack(1, "message-1");
+
+		assert!(
+			matches!(
+				result,
+				Err(StompError::VersionMismatch {
+					negotiated: StompVersion::V1_0,
+					..
+				})
+			),
+			"{:?}",
+			result
+		);
+		assert!(server.next_frame(Duration::from_millis(100)).is_none());
+	}
+
+	#[test]
+	fn disconnect_waits_for_receipt() {
+		let server = FakeServer::start(Box::new(|frame| match frame.command.as_str() {
+			"CONNECT" => vec![connected()],
+			"DISCONNECT" => {
+				let mut replies = receipt_for(frame);
+				replies.push(Reply::Close);
+				replies
+			}
+			_ => Vec::new(),
+		}));
+		let mut connection = connect(&server);
+
+		connection.disconnect(None).unwrap();
+
+		let disconnect = server.next_frame(SERVER_TIMEOUT).unwrap();
+		assert_eq!(disconnect.command, "DISCONNECT");
+		assert!(disconnect.has_header("receipt"));
+		assert!(!connection.is_writable());
+	}
+
+	/// Checks a frame was refused for corrupting the stream, without anything reaching the server.
+	fn assert_refused(server: &FakeServer, bytes_before: usize, result: Result<(), StompError>) {
+		assert!(
+			matches!(result, Err(StompError::InvalidOutgoing { .. })),
+			"{:?}",
+			result
+		);
+		sleep(Duration::from_millis(50));
+		assert_eq!(server.byte_count(), bytes_before);
+	}
+
+	#[test]
+	fn refuses_line_breaks_before_escaping_is_agreed() {
+		let server = FakeServer::silent();
+		let mut connection = server.connect();
+
+		for destination in ["/queue/a\r\nevil:1", "/queue/a\nevil:1", "/queue/a\0"] {
+			let result = connection.send(destination, "Hello");
+			assert_refused(&server, 0, result);
+		}
+
+		let frame = Frame::new("SEND")
+			.with_header("destination", "/queue/a")
+			.with_header("note", "a\r\nevil:1");
+		let result = connection.send_frame(&frame);
+		assert_refused(&server, 0, result);
+
+		let result = connection.begin("transaction\nevil:1");
+		assert_refused(&server, 0, result);
+	}
+
+	#[test]
+	fn refuses_nul_once_escaping_is_agreed() {
+		let server = receipting_server();
+		let mut connection = connect(&server);
+		connection.begin("transaction").unwrap();
+		assert_eq!(server.next_frame(SERVER_TIMEOUT).unwrap().command, "BEGIN");
+		let bytes_before = server.byte_count();
+
+		let result = connection.send("/queue/a\0", "Hello");
+		assert_refused(&server, bytes_before, result);
+
+		let frame = Frame::new("SEND")
+			.with_header("destination", "/queue/a")
+			.with_header("note", "a\0b");
+		let result = connection.send_frame(&frame);
+		assert_refused(&server, bytes_before, result);
+
+		let frame = Frame::new("SEND")
+			.with_header("destination", "/queue/a")
+			.with_header("transaction", "transaction\0");
+		let result = connection.send_frame(&frame);
+		assert_refused(&server, bytes_before, result);
+	}
+
+	#[test]
+	fn transaction_frames_carry_the_transaction() {
+		let server = receipting_server();
+		let mut connection = connect(&server);
+		connection
+			.subscribe_with_ack(1, "/queue/a", AckMode::Client)
+			.unwrap();
+		assert_eq!(
+			server.next_frame(SERVER_TIMEOUT).unwrap().command,
+			"SUBSCRIBE"
+		);
+		server.write(&frame_bytes(
+			"MESSAGE",
+			&[
+				("destination", "/queue/a"),
+				("message-id", "1"),
+				("subscription", "1"),
+				("ack", "ack-1"),
+			],
+			"Hello",
+		));
+		let message = match connection.receive().unwrap() {
+			Delivery::Message(message) => message,
+			Delivery::Frame(frame) => panic!("Expected a message, got {}", frame.command),
+		};
+
+		connection.begin("transaction-1").unwrap();
+		connection
+			.send_in_transaction("transaction-1", "/queue/b", "Reply")
+			.unwrap();
+		message.ack_in("transaction-1").unwrap();
+		connection.commit("transaction-1").unwrap();
+
+		for command in ["BEGIN", "SEND", "ACK", "COMMIT"] {
+			let frame = server.next_frame(SERVER_TIMEOUT).unwrap();
+			assert_eq!(frame.command, command);
+			assert_eq!(frame.get_header("transaction"), Some("transaction-1"));
+		}
+		assert!(connection.unacknowledged(1).is_empty());
+	}
+
+	/// Starts a server that agrees to STOMP 1.2, then delivers messages to the first subscription.
+	/// Only as many as the prefetch header allows are waiting to be acknowledged at once, if there is one.
+	fn prefetching_server(message_count: u32) -> FakeServer {
+		let mut sent_count = 0;
+
+		FakeServer::start(Box::new(move |frame| {
+			let count = match frame.command.as_str() {
+				"CONNECT" => return vec![connected()],
+				"SUBSCRIBE" => frame
+					.get_header("activemq.prefetchSize")
+					.map_or(message_count, |value| value.parse().unwrap()),
+				"ACK" => 1,
+				_ => 0,
+			};
+
+			let mut replies = Vec::new();
+			while sent_count < message_count && replies.len() < count as usize {
+				sent_count += 1;
+				let message_id = sent_count.to_string();
+				replies.push(Reply::Bytes(frame_bytes(
+					"MESSAGE",
+					&[
+						("destination", "/queue/a"),
+						("message-id", &message_id),
+						("subscription", "1"),
+						("ack", &message_id),
+					],
+					"Hello",
+				)));
+			}
+
+			replies
+		}))
+	}
+
+	/// Compares how quickly messages are consumed when acknowledged in batches, rather than automatically.
+	/// Run with `cargo test --release batch_acknowledgement_throughput -- --ignored --nocapture`.
+	#[test]
+	#[ignore]
+	fn batch_acknowledgement_throughput() {
+		const MESSAGE_COUNT: u32 = 10_000;
+		const BATCH_SIZE: u32 = 100;
+
+		for ack_mode in [AckMode::Auto, AckMode::Client] {
+			let server = prefetching_server(MESSAGE_COUNT);
+			let mut connection = connect(&server);
+			match ack_mode {
+				AckMode::Client => connection
+					.subscribe_prefetch_n(1, "/queue/a", ack_mode, BATCH_SIZE)
+					.unwrap(),
+				_ => connection.subscribe(1, "/queue/a").unwrap(),
+			}
+
+			let started = Instant::now();
+			let mut batch = Vec::new();
+			for _ in 0..MESSAGE_COUNT {
+				match connection.receive().unwrap() {
+					Delivery::Message(message) => batch.push(message.message_id().to_string()),
+					Delivery::Frame(frame) => panic!("Expected a message, got {}", frame.command),
+				}
+
+				if ack_mode == AckMode::Client && batch.len() == BATCH_SIZE as usize {
+					let message_ids = batch.iter().map(String::as_str).collect::<Vec<&str>>();
+					connection.ack_n(&message_ids).unwrap();
+					batch.clear();
+				}
+			}
+			let elapsed = started.elapsed();
+
+			println!(
+				"{:?}: {} messages in {:?} ({:.0} per second)",
+				ack_mode,
+				MESSAGE_COUNT,
+				elapsed,
+				MESSAGE_COUNT as f64 / elapsed.as_secs_f64()
+			);
+		}
 	}
 }
-*/
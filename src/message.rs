@@ -0,0 +1,521 @@
+use std::sync::{
+	atomic::{AtomicBool, Ordering},
+	Arc, Weak,
+};
+
+use crate::{
+	error::StompError,
+	frame::Frame,
+	header::Headers,
+	shared::{lock, Shared},
+	subscription::AckMode,
+	version::StompVersion,
+};
+
+/// Represents a MESSAGE frame received from a STOMP server.
+///
+/// If the connection was opened with auto-NACK enabled, dropping the last copy of a message
+/// without calling `ack()` or `nack()` sends a NACK for it, so the server redelivers it straight away.
+/// This only happens for subscriptions in the client or client-individual ack modes, as the server
+/// rejects acknowledgements for messages it has already considered acknowledged.
+#[derive(Debug, Clone)]
+pub struct Message {
+	frame: Frame,
+	handle: Option<Weak<Shared>>,
+	settled: Arc<AtomicBool>,
+	auto_nack: bool,
+	ack_mode: Option<AckMode>, // Of the subscription it was delivered on, if that is known
+}
+
+impl Message {
+	/// Attaches a handle to the connection this message was received on, so it can acknowledge itself.
+	pub(crate) fn with_handle(mut self, shared: &Arc<Shared>) -> Message {
+		self.handle = Some(Arc::downgrade(shared));
+		self.ack_mode = lock(&shared.subscriptions).ack_mode(self.subscription());
+		self.auto_nack = shared.auto_nack
+			&& matches!(
+				self.ack_mode,
+				Some(AckMode::Client | AckMode::ClientIndividual)
+			);
+		self
+	}
+
+	/// Acknowledges that this message has been processed.
+	/// Fails if the connection it was received on has since closed.
+	pub fn ack(&self) -> Result<(), StompError> {
+		self.respond("ACK", None)
+	}
+
+	/// Acknowledges this message as part of a transaction, so it only takes effect once the transaction is committed.
+	pub fn ack_in(&self, transaction: &str) -> Result<(), StompError> {
+		self.respond("ACK", Some(transaction))
+	}
+
+	/// Tells the STOMP server that this message could not be processed.
+	/// Fails if the connection it was received on has since closed.
+	pub fn nack(&self) -> Result<(), StompError> {
+		self.respond("NACK", None)
+	}
+
+	/// Rejects this message as part of a transaction, so it only takes effect once the transaction is committed.
+	pub fn nack_in(&self, transaction: &str) -> Result<(), StompError> {
+		self.respond("NACK", Some(transaction))
+	}
+
+	/// Sends an ACK or NACK frame for this message over the connection it was received on.
+	fn respond(&self, command: &str, transaction: Option<&str>) -> Result<(), StompError> {
+		let shared = self
+			.handle
+			.as_ref()
+			.and_then(Weak::upgrade)
+			.ok_or(StompError::ConnectionClosed)?;
+
+		let mut frame = self.acknowledgement(command, shared.version())?;
+
+		// Settle it now, or once the transaction is committed
+		match transaction {
+			Some(transaction) => {
+				frame.headers.append("transaction", transaction);
+
+				shared.send_in_transaction(&frame, transaction, self.message_id())?;
+			}
+			None => {
+				shared.send(&frame)?;
+				shared.settle(self.message_id());
+			}
+		}
+
+		// Don't send anything else when dropped
+		self.settled.store(true, Ordering::SeqCst);
+
+		Ok(())
+	}
+
+	/// Creates an ACK or NACK frame for this message, shaped for the given protocol version.
+	pub fn acknowledgement(
+		&self,
+		command: &str,
+		version: Option<StompVersion>,
+	) -> Result<Frame, StompError> {
+		acknowledgement(
+			command,
+			version,
+			self.message_id(),
+			self.subscription(),
+			self.ack_id(),
+		)
+	}
+
+	/// Gets the destination this message was sent to.
+	pub fn destination(&self) -> &str {
+		&self.frame.headers[Headers::Destination.as_str()]
+	}
+
+	/// Gets the unique identifier of this message.
+	pub fn message_id(&self) -> &str {
+		&self.frame.headers[Headers::MessageId.as_str()]
+	}
+
+	/// Gets the identifier of the subscription this message was delivered on.
+	pub fn subscription(&self) -> &str {
+		&self.frame.headers[Headers::Subscription.as_str()]
+	}
+
+	/// Gets the identifier to acknowledge this message with, if the server provided one (STOMP 1.2).
+	pub fn ack_id(&self) -> Option<&str> {
+		self.frame.ack_id()
+	}
+
+	/// Gets how the subscription this message was delivered on acknowledges messages, if it is known.
+	pub fn ack_mode(&self) -> Option<AckMode> {
+		self.ack_mode
+	}
+
+	/// Gets the MIME type of the body, if the server provided one.
+	pub fn content_type(&self) -> Option<&str> {
+		self.frame.content_type()
+	}
+
+	/// Checks if the server has delivered this message before.
+	pub fn is_redelivered(&self) -> bool {
+		self.frame.is_redelivered()
+	}
+
+	/// Checks if the server has delivered this message before, failing if the header has an unrecognised value.
+	pub fn redelivered(&self) -> Result<bool, StompError> {
+		self.frame.redelivered()
+	}
+
+	/// Gets how many times the server has attempted to deliver this message, if it says (ActiveMQ only).
+	pub fn delivery_count(&self) -> Option<u32> {
+		self.frame.delivery_count()
+	}
+
+	/// Gets the body of this message as text, if there is one & it is valid UTF-8.
+	pub fn body(&self) -> Option<&str> {
+		self.frame.body_text().ok().flatten()
+	}
+
+	/// Gets the body of this message as bytes, such as for binary payloads.
+	pub fn body_raw(&self) -> Option<&[u8]> {
+		self.frame.body_raw()
+	}
+
+	/// Iterates over the headers that don't have a dedicated accessor.
+	pub fn other_headers(&self) -> impl Iterator<Item = (&str, &str)> {
+		let known = [
+			Headers::Ack.as_str(),
+			Headers::ContentLength.as_str(),
+			Headers::ContentType.as_str(),
+			Headers::Destination.as_str(),
+			Headers::JmsRedelivered.as_str(),
+			Headers::JmsxDeliveryCount.as_str(),
+			Headers::MessageId.as_str(),
+			Headers::Redelivered.as_str(),
+			Headers::Subscription.as_str(),
+		];
+
+		self.frame
+			.headers
+			.iter()
+			.filter(move |(name, _)| !known.iter().any(|known| name.eq_ignore_ascii_case(known)))
+	}
+
+	/// Gets the underlying frame.
+	pub fn frame(&self) -> &Frame {
+		&self.frame
+	}
+
+	/// Converts back into the underlying frame.
+	/// This stops the message from being automatically NACKed, as there is no longer a message to drop.
+	pub fn into_frame(mut self) -> Frame {
+		self.auto_nack = false;
+
+		std::mem::take(&mut self.frame)
+	}
+}
+
+impl TryFrom<Frame> for Message {
+	type Error = StompError;
+
+	/// Converts a MESSAGE frame, ensuring all the required headers are present.
+	fn try_from(frame: Frame) -> Result<Message, StompError> {
+		if frame.command != "MESSAGE" {
+			return Err(StompError::UnexpectedCommand {
+				expected: "MESSAGE".to_string(),
+				received: frame.command,
+			});
+		}
+
+		// These accessors assume the header exists
+		for header in [
+			Headers::Destination,
+			Headers::MessageId,
+			Headers::Subscription,
+		] {
+			if !frame.has_header(header.as_str()) {
+				return Err(StompError::MissingHeader {
+					command: frame.command,
+					name: header.as_str().to_string(),
+				});
+			}
+		}
+
+		Ok(Message {
+			frame,
+			handle: None,
+			settled: Arc::new(AtomicBool::new(false)),
+			auto_nack: false,
+			ack_mode: None,
+		})
+	}
+}
+
+impl Drop for Message {
+	/// Sends a NACK if auto-NACK is enabled & this is the last copy of a message that was never settled.
+	fn drop(&mut self) {
+		if !self.auto_nack || Arc::strong_count(&self.settled) > 1 {
+			return;
+		}
+
+		if self.settled.load(Ordering::SeqCst) {
+			return;
+		}
+
+		// Nothing can be done if the connection has gone away, so ignore any failure
+		let _ = self.respond("NACK", None);
+	}
+}
+
+/// Represents something received from a STOMP server.
+#[derive(Debug, Clone)]
+pub enum Delivery {
+	/// A MESSAGE frame for one of our subscriptions.
+	Message(Message),
+
+	/// Any other frame.
+	Frame(Frame),
+}
+
+impl TryFrom<Frame> for Delivery {
+	type Error = StompError;
+
+	/// Wraps a frame, converting it into a message if it is a MESSAGE frame.
+	fn try_from(frame: Frame) -> Result<Delivery, StompError> {
+		if frame.command == "MESSAGE" {
+			return Ok(Delivery::Message(Message::try_from(frame)?));
+		}
+
+		Ok(Delivery::Frame(frame))
+	}
+}
+
+/// Creates an ACK or NACK frame for a message, shaped for the given protocol version.
+///
+/// * STOMP 1.2 uses the id from the MESSAGE frame's ack header.
+/// * STOMP 1.1 uses the message-id & subscription headers.
+/// * STOMP 1.0 uses just the message-id header, and has no NACK frame.
+///
+/// If the version isn't known yet, the STOMP 1.2 shape is used when an ack header is available, otherwise STOMP 1.1.
+pub fn acknowledgement(
+	command: &str,
+	version: Option<StompVersion>,
+	message_id: &str,
+	subscription: &str,
+	ack_id: Option<&str>,
+) -> Result<Frame, StompError> {
+	let version = version.unwrap_or(if ack_id.is_some() {
+		StompVersion::V1_2
+	} else {
+		StompVersion::V1_1
+	});
+
+	match version {
+		StompVersion::V1_2 => {
+			let id = ack_id.ok_or_else(|| StompError::MissingHeader {
+				command: "MESSAGE".to_string(),
+				name: Headers::Ack.as_str().to_string(),
+			})?;
+
+			Ok(Frame::new(command).with_header("id", id))
+		}
+		StompVersion::V1_1 => Ok(Frame::new(command)
+			.with_header("message-id", message_id)
+			.with_header("subscription", subscription)),
+		StompVersion::V1_0 => {
+			if command != "ACK" {
+				return Err(StompError::VersionMismatch {
+					command: command.to_string(),
+					required: StompVersion::V1_1,
+					negotiated: version,
+				});
+			}
+
+			Ok(Frame::new(command).with_header("message-id", message_id))
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use super::*;
+	use crate::test_support::{frame_bytes, FakeServer};
+
+	/// Subscribes with auto-NACK enabled, then receives a message the server pushes straight away.
+	fn receive_message(ack_mode: AckMode) -> (FakeServer, crate::Connection, Message) {
+		let server = FakeServer::silent();
+		let mut connection = server.builder().auto_nack(true).open().unwrap();
+		connection
+			.subscribe_with_ack(1, "/queue/test", ack_mode)
+			.unwrap();
+		assert_eq!(
+			server.next_frame(Duration::from_secs(1)).unwrap().command,
+			"SUBSCRIBE"
+		);
+
+		server.write(&frame_bytes(
+			"MESSAGE",
+			&[
+				("destination", "/queue/test"),
+				("message-id", "1"),
+				("subscription", "1"),
+			],
+			"Hello",
+		));
+		let message = match connection.receive().unwrap() {
+			Delivery::Message(message) => message,
+			Delivery::Frame(frame) => panic!("Expected a message, got {}", frame.command),
+		};
+
+		(server, connection, message)
+	}
+
+	#[test]
+	fn dropping_auto_ack_message_sends_nothing() {
+		let (server, _connection, message) = receive_message(AckMode::Auto);
+		assert_eq!(message.ack_mode(), Some(AckMode::Auto));
+
+		drop(message);
+
+		assert!(server.next_frame(Duration::from_millis(200)).is_none());
+	}
+
+	#[test]
+	fn dropping_client_ack_message_sends_nack() {
+		let (server, _connection, message) = receive_message(AckMode::Client);
+
+		drop(message);
+
+		let nack = server.next_frame(Duration::from_secs(1)).unwrap();
+		assert_eq!(nack.command, "NACK");
+		assert_eq!(nack.get_header("message-id"), Some("1"));
+		assert_eq!(nack.get_header("subscription"), Some("1"));
+	}
+
+	#[test]
+	fn dropping_message_after_close_sends_nothing() {
+		let (server, mut connection, message) = receive_message(AckMode::Client);
+		connection.close().unwrap();
+		let byte_count = server.byte_count();
+
+		drop(message);
+
+		assert!(server.next_frame(Duration::from_millis(200)).is_none());
+		assert_eq!(server.byte_count(), byte_count);
+	}
+
+	#[test]
+	fn dropping_message_after_connection_sends_nothing() {
+		let (server, connection, message) = receive_message(AckMode::Client);
+		drop(connection);
+		assert!(server.wait_for_eof(Duration::from_secs(1)));
+		let byte_count = server.byte_count();
+
+		drop(message);
+
+		assert!(server.next_frame(Duration::from_millis(200)).is_none());
+		assert_eq!(server.byte_count(), byte_count);
+	}
+
+	#[test]
+	fn settled_message_is_not_nacked() {
+		let (server, _connection, message) = receive_message(AckMode::ClientIndividual);
+
+		message.ack().unwrap();
+		drop(message);
+
+		assert_eq!(
+			server.next_frame(Duration::from_secs(1)).unwrap().command,
+			"ACK"
+		);
+		assert!(server.next_frame(Duration::from_millis(200)).is_none());
+	}
+
+	/// Creates a message with the required headers & the given extra ones.
+	fn message(extra_headers: &[(&str, &str)]) -> Message {
+		let mut frame = Frame::new("MESSAGE")
+			.with_header("destination", "/queue/test")
+			.with_header("message-id", "1")
+			.with_header("subscription", "1");
+		for (name, value) in extra_headers {
+			frame.headers.append(name, value);
+		}
+
+		Message::try_from(frame).unwrap()
+	}
+
+	#[test]
+	fn recognises_each_redelivery_header() {
+		assert!(!message(&[]).is_redelivered());
+		assert!(message(&[("redelivered", "true")]).is_redelivered());
+		assert!(message(&[("JMSRedelivered", "TRUE")]).is_redelivered());
+		assert!(message(&[("JMSXDeliveryCount", "2")]).is_redelivered());
+
+		assert!(!message(&[("redelivered", "false")]).is_redelivered());
+		assert!(!message(&[("JMSXDeliveryCount", "1")]).is_redelivered());
+		assert_eq!(
+			message(&[("JMSXDeliveryCount", "3")]).delivery_count(),
+			Some(3)
+		);
+	}
+
+	#[test]
+	fn rejects_unrecognised_redelivered_value() {
+		let message = message(&[("redelivered", "yes")]);
+
+		assert!(!message.is_redelivered());
+		assert!(matches!(
+			message.redelivered(),
+			Err(StompError::InvalidHeader { name, value }) if name == "redelivered" && value == "yes"
+		));
+	}
+
+	#[test]
+	fn rejects_message_missing_required_header() {
+		let frame = Frame::new("MESSAGE")
+			.with_header("destination", "/queue/test")
+			.with_header("subscription", "1");
+
+		let result = Message::try_from(frame);
+		assert!(
+			matches!(
+				&result,
+				Err(StompError::MissingHeader { command, name }) if command == "MESSAGE" && name == "message-id"
+			),
+			"{:?}",
+			result
+		);
+	}
+
+	#[test]
+	fn rejects_other_commands() {
+		let result = Message::try_from(Frame::new("RECEIPT"));
+
+		assert!(matches!(
+			result,
+			Err(StompError::UnexpectedCommand { received, .. }) if received == "RECEIPT"
+		));
+	}
+
+	#[test]
+	fn acknowledgements_are_shaped_per_version() {
+		let message = message(&[("ack", "ack-1")]);
+
+		let ack = message
+			.acknowledgement("ACK", Some(StompVersion::V1_2))
+			.unwrap();
+		assert_eq!(ack.get_header("id"), Some("ack-1"));
+
+		let nack = message
+			.acknowledgement("NACK", Some(StompVersion::V1_1))
+			.unwrap();
+		assert_eq!(nack.get_header("message-id"), Some("1"));
+		assert_eq!(nack.get_header("subscription"), Some("1"));
+
+		let ack = message
+			.acknowledgement("ACK", Some(StompVersion::V1_0))
+			.unwrap();
+		assert_eq!(ack.get_header("message-id"), Some("1"));
+		assert!(!ack.has_header("subscription"));
+	}
+
+	#[test]
+	fn nack_is_refused_under_1_0() {
+		let result = message(&[]).acknowledgement("NACK", Some(StompVersion::V1_0));
+
+		assert!(
+			matches!(
+				&result,
+				Err(StompError::VersionMismatch {
+					command,
+					required: StompVersion::V1_1,
+					negotiated: StompVersion::V1_0,
+				}) if command == "NACK"
+			),
+			"{:?}",
+			result
+		);
+	}
+}
@@ -0,0 +1,256 @@
+use std::{
+	ops::{Deref, DerefMut},
+	sync::{Condvar, Mutex},
+};
+
+use crate::{
+	error::StompError, frame_log::Direction, heart_beat, reconnect::Connect, shared::lock,
+	Connection,
+};
+
+/// Keeps a number of connections open, handing them out to one thread at a time.
+///
+/// Idle connections are checked with a heart-beat before being handed out, and broken ones are replaced
+/// using the connect function, so it should authenticate & restore anything else a connection needs.
+pub struct ConnectionPool {
+	connect: Mutex<Connect>,
+	size: usize,
+	state: Mutex<PoolState>,
+	returned: Condvar,
+}
+
+/// The connections a pool isn't currently handing out.
+struct PoolState {
+	idle: Vec<Connection>,
+	open_count: usize,
+}
+
+impl ConnectionPool {
+	/// Opens a number of connections with the given function, failing if any can't be opened.
+	pub fn new(size: usize, mut connect: Connect) -> Result<ConnectionPool, StompError> {
+		let idle = (0..size)
+			.map(|_| connect())
+			.collect::<Result<Vec<Connection>, StompError>>()?;
+
+		Ok(ConnectionPool {
+			connect: Mutex::new(connect),
+			size,
+			state: Mutex::new(PoolState {
+				open_count: idle.len(),
+				idle,
+			}),
+			returned: Condvar::new(),
+		})
+	}
+
+	/// Takes a connection, waiting for one to be given back if they're all in use.
+	/// A broken connection is replaced with a new one, returning the error if that can't be opened.
+	pub fn get(&self) -> Result<PooledConnection<'_>, StompError> {
+		let mut state = lock(&self.state);
+
+		loop {
+			// Hand out the first idle connection that still works, throwing away any that don't
+			while let Some(connection) = state.idle.pop() {
+				if is_healthy(&connection) {
+					return Ok(PooledConnection::new(self, connection));
+				}
+
+				// Closing can block, so don't hold up anyone giving a connection back meanwhile
+				state.open_count -= 1;
+				drop(state);
+				discard(connection);
+				state = lock(&self.state);
+			}
+
+			// Replace a connection that was thrown away, without holding up anyone giving one back
+			if state.open_count < self.size {
+				state.open_count += 1;
+				drop(state);
+
+				let connected = (lock(&self.connect))();
+				if connected.is_err() {
+					lock(&self.state).open_count -= 1;
+					self.returned.notify_one();
+				}
+
+				return connected.map(|connection| PooledConnection::new(self, connection));
+			}
+
+			// Wait for a connection to be given back
+			state = self
+				.returned
+				.wait(state)
+				.unwrap_or_else(|poisoned| poisoned.into_inner());
+		}
+	}
+
+	/// Gets how many connections are kept open.
+	pub fn size(&self) -> usize {
+		self.size
+	}
+
+	/// Gets how many connections are waiting to be handed out.
+	pub fn idle_count(&self) -> usize {
+		lock(&self.state).idle.len()
+	}
+
+	/// Takes back a connection, throwing it away if it no longer works.
+	fn give_back(&self, connection: Connection) {
+		if connection.is_writable() {
+			lock(&self.state).idle.push(connection);
+		} else {
+			lock(&self.state).open_count -= 1;
+			discard(connection);
+		}
+
+		self.returned.notify_one();
+	}
+}
+
+/// A connection taken from a pool, which is given back when dropped.
+pub struct PooledConnection<'a> {
+	pool: &'a ConnectionPool,
+	connection: Option<Connection>,
+}
+
+impl<'a> PooledConnection<'a> {
+	fn new(pool: &'a ConnectionPool, connection: Connection) -> PooledConnection<'a> {
+		PooledConnection {
+			pool,
+			connection: Some(connection),
+		}
+	}
+}
+
+impl Deref for PooledConnection<'_> {
+	type Target = Connection;
+
+	fn deref(&self) -> &Connection {
+		self.connection.as_ref().unwrap()
+	}
+}
+
+impl DerefMut for PooledConnection<'_> {
+	fn deref_mut(&mut self) -> &mut Connection {
+		self.connection.as_mut().unwrap()
+	}
+}
+
+impl Drop for PooledConnection<'_> {
+	/// Gives the connection back to the pool.
+	fn drop(&mut self) {
+		if let Some(connection) = self.connection.take() {
+			self.pool.give_back(connection);
+		}
+	}
+}
+
+/// Checks an idle connection still works.
+///
+/// Writing succeeds on a half-open connection, so if the server agreed to send heart-beats, a connection that
+/// hasn't received anything for a few of its intervals is treated as broken. A heart-beat is then sent, to find
+/// out if the other end has closed the connection without anything arriving to say so.
+fn is_healthy(connection: &Connection) -> bool {
+	if !connection.is_writable() {
+		return false;
+	}
+
+	if heart_beat::check_received(&connection.shared).is_err()
+		|| connection.shared.write(b"\n").is_err()
+	{
+		return false;
+	}
+	connection.shared.observe(Direction::Sent, None);
+
+	true
+}
+
+/// Closes a broken connection, ignoring any errors as it is not being used anymore.
+fn discard(mut connection: Connection) {
+	let _ = connection.close();
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		sync::{
+			atomic::{AtomicUsize, Ordering},
+			Arc,
+		},
+		thread::sleep,
+		time::Duration,
+	};
+
+	use super::*;
+	use crate::test_support::{frame_bytes, FakeServer};
+
+	/// Creates a pool of one connection to the server, counting how many times it tries to connect.
+	/// Only the first connection can be opened, as the server accepts nothing else.
+	fn pool_of_one(connection: Connection) -> (ConnectionPool, Arc<AtomicUsize>) {
+		let connect_count = Arc::new(AtomicUsize::new(0));
+		let counted = connect_count.clone();
+		let mut connection = Some(connection);
+
+		let pool = ConnectionPool::new(
+			1,
+			Box::new(move || {
+				counted.fetch_add(1, Ordering::SeqCst);
+				connection.take().ok_or(StompError::ConnectionClosed)
+			}),
+		)
+		.unwrap();
+
+		(pool, connect_count)
+	}
+
+	/// Opens a connection that expects the server to heart-beat, once the server has agreed to.
+	fn heart_beating_connection(server: &FakeServer) -> Connection {
+		let connection = server
+			.builder()
+			.heart_beat(Duration::ZERO, Duration::from_millis(50))
+			.open()
+			.unwrap();
+		server.write(&frame_bytes(
+			"CONNECTED",
+			&[("version", "1.2"), ("heart-beat", "50,0")],
+			"",
+		));
+
+		while connection.heart_beat().is_none() {
+			sleep(Duration::from_millis(10));
+		}
+
+		connection
+	}
+
+	#[test]
+	fn hands_out_idle_connection() {
+		let server = FakeServer::silent();
+		let (pool, connect_count) = pool_of_one(server.connect());
+
+		assert!(pool.get().is_ok());
+		assert_eq!(pool.idle_count(), 1);
+		assert_eq!(connect_count.load(Ordering::SeqCst), 1);
+	}
+
+	#[test]
+	fn keeps_connection_that_recently_received() {
+		let server = FakeServer::silent();
+		let (pool, connect_count) = pool_of_one(heart_beating_connection(&server));
+
+		assert!(pool.get().is_ok());
+		assert_eq!(connect_count.load(Ordering::SeqCst), 1);
+	}
+
+	#[test]
+	fn replaces_connection_missing_heart_beats() {
+		let server = FakeServer::silent();
+		let (pool, connect_count) = pool_of_one(heart_beating_connection(&server));
+
+		// Still writable, but the server has gone quiet
+		sleep(Duration::from_millis(200));
+		assert!(matches!(pool.get(), Err(StompError::ConnectionClosed)));
+		assert_eq!(connect_count.load(Ordering::SeqCst), 2);
+		assert_eq!(pool.idle_count(), 0);
+	}
+}
@@ -0,0 +1,325 @@
+use std::{
+	ops::{Deref, DerefMut},
+	sync::Mutex,
+	thread::sleep,
+	time::{Duration, Instant},
+};
+
+use crate::{
+	destination::Destination,
+	error::StompError,
+	frame::Frame,
+	shared::{lock, Shared},
+	Connection,
+};
+
+/// The longest to sleep for at once while waiting for the rate limiter, so closing isn't held up.
+const MAX_RATE_LIMIT_WAIT: Duration = Duration::from_millis(100);
+
+/// Controls what happens to a frame that would go over the rate limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitMode {
+	/// Wait until the frame can be sent.
+	Block,
+
+	/// Fail with `StompError::WouldExceedRate`.
+	Reject,
+}
+
+/// Limits how quickly frames are sent to the STOMP server, using a token bucket.
+///
+/// ACK, NACK, CONNECT, STOMP & DISCONNECT frames, and heart-beats, are always sent straight away.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimit {
+	/// How many frames can be sent each second, on average.
+	pub frames_per_second: f64,
+
+	/// How many frames can be sent at once after a quiet period.
+	pub burst: u32,
+
+	/// What happens to a frame that would go over the limit.
+	pub mode: RateLimitMode,
+}
+
+/// Commands that are never held back, as they keep the connection itself working.
+const UNLIMITED_COMMANDS: [&str; 5] = ["ACK", "NACK", "CONNECT", "STOMP", "DISCONNECT"];
+
+/// Checks if a frame with the given command counts towards the rate limit.
+pub(crate) fn is_limited(command: &str) -> bool {
+	!UNLIMITED_COMMANDS.contains(&command)
+}
+
+/// Keeps track of how many frames can be sent right now.
+pub(crate) struct TokenBucket {
+	limit: RateLimit,
+	tokens: f64,
+	last_refill: Instant,
+}
+
+impl TokenBucket {
+	/// Creates a full bucket.
+	pub(crate) fn new(limit: RateLimit, now: Instant) -> TokenBucket {
+		TokenBucket {
+			tokens: f64::from(limit.burst),
+			limit,
+			last_refill: now,
+		}
+	}
+
+	/// Gets what happens to a frame that would go over the limit.
+	pub(crate) fn mode(&self) -> RateLimitMode {
+		self.limit.mode
+	}
+
+	/// Takes a token if there is one, otherwise returns how long until there will be.
+	pub(crate) fn try_take(&mut self, now: Instant) -> Result<(), Duration> {
+		// Top up for the time that has passed, up to the burst size
+		let elapsed = now
+			.saturating_duration_since(self.last_refill)
+			.as_secs_f64();
+		self.tokens = (self.tokens + elapsed * self.limit.frames_per_second)
+			.min(f64::from(self.limit.burst.max(1)));
+		self.last_refill = now;
+
+		if self.tokens >= 1.0 {
+			self.tokens -= 1.0;
+			return Ok(());
+		}
+
+		// A rate of zero never lets anything through
+		if self.limit.frames_per_second <= 0.0 {
+			return Err(Duration::MAX);
+		}
+
+		Err(
+			Duration::try_from_secs_f64((1.0 - self.tokens) / self.limit.frames_per_second)
+				.unwrap_or(Duration::MAX),
+		)
+	}
+
+	/// Takes a token from a bucket, waiting for one or failing if there aren't any, or if the connection closes first.
+	/// The bucket is unlocked while waiting, so other senders aren't held up.
+	pub(crate) fn wait_for_token(
+		bucket: &Mutex<TokenBucket>,
+		shared: &Shared,
+	) -> Result<(), StompError> {
+		loop {
+			let mut locked_bucket = lock(bucket);
+			let wait = match locked_bucket.try_take(Instant::now()) {
+				Ok(()) => return Ok(()),
+				Err(_) if locked_bucket.mode() == RateLimitMode::Reject => {
+					return Err(StompError::WouldExceedRate)
+				}
+				Err(wait) => wait,
+			};
+			drop(locked_bucket);
+
+			if shared.is_closed() {
+				return Err(StompError::ConnectionClosed);
+			}
+			sleep(wait.min(MAX_RATE_LIMIT_WAIT));
+		}
+	}
+}
+
+/// Wraps a connection to limit how quickly messages are sent through it, using a token bucket.
+/// Only frames sent through this are limited, whereas `ConnectionBuilder::rate_limit` limits everything on the connection.
+/// Everything else is done through the connection as usual, as this dereferences to it.
+pub struct RateLimitedConnection {
+	connection: Connection,
+	bucket: Mutex<TokenBucket>,
+}
+
+impl RateLimitedConnection {
+	/// Starts limiting a connection to a number of frames each second on average, with bursts of up to a number of frames at once.
+	/// Sending waits until the frame can be sent.
+	pub fn new(connection: Connection, rate: f64, burst: usize) -> RateLimitedConnection {
+		let limit = RateLimit {
+			frames_per_second: rate,
+			burst: u32::try_from(burst).unwrap_or(u32::MAX),
+			mode: RateLimitMode::Block,
+		};
+
+		RateLimitedConnection {
+			connection,
+			bucket: Mutex::new(TokenBucket::new(limit, Instant::now())),
+		}
+	}
+
+	/// Changes what happens to a frame that would go over the limit.
+	pub fn with_mode(self, mode: RateLimitMode) -> RateLimitedConnection {
+		lock(&self.bucket).limit.mode = mode;
+		self
+	}
+
+	/// Sends a message to a destination on the STOMP server, once the rate limit allows it.
+	pub fn send(
+		&mut self,
+		destination: impl Into<Destination>,
+		body: &str,
+	) -> Result<(), StompError> {
+		TokenBucket::wait_for_token(&self.bucket, &self.connection.shared)?;
+		self.connection.send(destination, body)
+	}
+
+	/// Sends any frame to the STOMP server, once the rate limit allows it.
+	pub fn send_frame(&mut self, frame: &Frame) -> Result<(), StompError> {
+		if is_limited(&frame.command) {
+			TokenBucket::wait_for_token(&self.bucket, &self.connection.shared)?;
+		}
+
+		self.connection.send_frame(frame)
+	}
+
+	/// Stops limiting, giving back the connection.
+	pub fn into_inner(self) -> Connection {
+		self.connection
+	}
+}
+
+impl Deref for RateLimitedConnection {
+	type Target = Connection;
+
+	fn deref(&self) -> &Connection {
+		&self.connection
+	}
+}
+
+impl DerefMut for RateLimitedConnection {
+	fn deref_mut(&mut self) -> &mut Connection {
+		&mut self.connection
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_support::FakeServer;
+
+	/// Creates a full bucket at a fixed time.
+	fn bucket(frames_per_second: f64, burst: u32) -> (TokenBucket, Instant) {
+		let limit = RateLimit {
+			frames_per_second,
+			burst,
+			mode: RateLimitMode::Block,
+		};
+		let start = Instant::now();
+
+		(TokenBucket::new(limit, start), start)
+	}
+
+	#[test]
+	fn allows_a_burst_then_waits() {
+		let (mut bucket, start) = bucket(10.0, 3);
+
+		for _ in 0..3 {
+			assert_eq!(bucket.try_take(start), Ok(()));
+		}
+		assert_eq!(bucket.try_take(start), Err(Duration::from_millis(100)));
+	}
+
+	#[test]
+	fn waits_for_the_rest_of_a_token() {
+		let (mut bucket, start) = bucket(10.0, 1);
+		assert_eq!(bucket.try_take(start), Ok(()));
+
+		// A quarter of the way to the next token
+		let wait = bucket
+			.try_take(start + Duration::from_millis(25))
+			.unwrap_err();
+		assert!((wait.as_secs_f64() - 0.075).abs() < 1e-9, "{wait:?}");
+	}
+
+	#[test]
+	fn refills_over_time() {
+		let (mut bucket, start) = bucket(10.0, 2);
+		assert_eq!(bucket.try_take(start), Ok(()));
+		assert_eq!(bucket.try_take(start), Ok(()));
+
+		let later = start + Duration::from_millis(100);
+		assert_eq!(bucket.try_take(later), Ok(()));
+		assert!(bucket.try_take(later).is_err());
+	}
+
+	#[test]
+	fn refills_no_further_than_the_burst() {
+		let (mut bucket, start) = bucket(10.0, 2);
+
+		let later = start + Duration::from_secs(60);
+		assert_eq!(bucket.try_take(later), Ok(()));
+		assert_eq!(bucket.try_take(later), Ok(()));
+		assert!(bucket.try_take(later).is_err());
+	}
+
+	#[test]
+	fn zero_rate_never_refills() {
+		let (mut bucket, start) = bucket(0.0, 1);
+		assert_eq!(bucket.try_take(start), Ok(()));
+
+		assert_eq!(
+			bucket.try_take(start + Duration::from_secs(60)),
+			Err(Duration::MAX)
+		);
+	}
+
+	#[test]
+	fn limited_connection_waits_then_rejects() {
+		let server = FakeServer::silent();
+		let mut connection = RateLimitedConnection::new(server.connect(), 20.0, 1);
+
+		let started = Instant::now();
+		connection.send("/queue/a", "1").unwrap();
+		connection.send("/queue/a", "2").unwrap();
+		assert!(started.elapsed() >= Duration::from_millis(40)); // Waited for the next token
+
+		let mut connection = connection.with_mode(RateLimitMode::Reject);
+		let result = connection.send("/queue/a", "3");
+		assert!(
+			matches!(result, Err(StompError::WouldExceedRate)),
+			"{:?}",
+			result
+		);
+	}
+
+	#[test]
+	fn rate_limited_connection_waits_for_tokens() {
+		let server = FakeServer::silent();
+		let mut connection = server
+			.builder()
+			.rate_limit(RateLimit {
+				frames_per_second: 20.0,
+				burst: 1,
+				mode: RateLimitMode::Block,
+			})
+			.open()
+			.unwrap();
+
+		let started = Instant::now();
+		connection.send("/queue/a", "1").unwrap();
+		connection.send("/queue/a", "2").unwrap();
+		assert!(started.elapsed() >= Duration::from_millis(40));
+	}
+
+	#[test]
+	fn waiting_for_a_token_stops_once_closed() {
+		let server = FakeServer::silent();
+		let mut connection = RateLimitedConnection::new(server.connect(), 0.0, 1);
+		connection.send("/queue/a", "1").unwrap();
+		connection.close().unwrap();
+
+		let result = TokenBucket::wait_for_token(&connection.bucket, &connection.shared);
+
+		assert!(
+			matches!(result, Err(StompError::ConnectionClosed)),
+			"{:?}",
+			result
+		);
+	}
+
+	#[test]
+	fn connection_frames_are_not_limited() {
+		assert!(is_limited("SEND"));
+		assert!(!is_limited("ACK"));
+		assert!(!is_limited("DISCONNECT"));
+	}
+}
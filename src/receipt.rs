@@ -0,0 +1,74 @@
+use std::{collections::HashMap, time::Duration};
+
+use crate::error::ServerError;
+
+/// Controls how many times a frame is sent before giving up on getting a receipt for it.
+///
+/// Retrying can deliver the same frame more than once if the server acted upon it but the receipt was lost
+/// or late, so only use this when the server deduplicates frames or the application tolerates duplicates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+	/// The most times to send the frame, including the first.
+	pub max_attempts: u32,
+
+	/// How long to wait for a receipt after each attempt.
+	pub timeout: Duration,
+
+	/// How long to wait before the second attempt, doubling after each attempt thereafter.
+	pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+	fn default() -> RetryPolicy {
+		RetryPolicy {
+			max_attempts: 3,
+			timeout: Duration::from_secs(5),
+			backoff: Duration::from_millis(500),
+		}
+	}
+}
+
+/// Keeps track of the receipts we're waiting for.
+#[derive(Default)]
+pub(crate) struct Receipts {
+	next_id: u64,
+	pending: HashMap<String, Option<Result<(), ServerError>>>, // Identifier to the RECEIPT or ERROR frame, once it arrives
+}
+
+impl Receipts {
+	/// Starts waiting for a new receipt, returning its identifier.
+	pub(crate) fn register(&mut self) -> String {
+		self.next_id += 1;
+		let id = format!("receipt-{}", self.next_id);
+
+		self.pending.insert(id.clone(), None);
+
+		id
+	}
+
+	/// Records that a RECEIPT frame or an ERROR frame referring to a receipt arrived.
+	/// Returns false if we weren't waiting for it.
+	pub(crate) fn settle(&mut self, id: &str, outcome: Result<(), ServerError>) -> bool {
+		match self.pending.get_mut(id) {
+			Some(pending) => {
+				*pending = Some(outcome);
+				true
+			}
+			None => false,
+		}
+	}
+
+	/// Forgets about a receipt once something has arrived for it, returning what did.
+	pub(crate) fn take_settled(&mut self, id: &str) -> Option<Result<(), ServerError>> {
+		if !matches!(self.pending.get(id), Some(Some(_))) {
+			return None;
+		}
+
+		self.pending.remove(id).flatten()
+	}
+
+	/// Stops waiting for a receipt, whether or not it has arrived.
+	pub(crate) fn forget(&mut self, id: &str) {
+		self.pending.remove(id);
+	}
+}
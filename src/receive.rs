@@ -0,0 +1,518 @@
+use std::{
+	io::{self, ErrorKind, Read},
+	net::TcpStream,
+	sync::{mpsc::Sender, Arc},
+	thread,
+};
+
+use crate::{
+	connected::ConnectedInfo,
+	error::{ServerError, StompError},
+	flow_control,
+	frame::{self, Frame, ParseOptions},
+	frame_log::Direction,
+	heart_beat::{self, HeartBeat},
+	shared::{lock, split_versions, Shared},
+	streaming::{BodyStreaming, StreamingBody},
+	version::StompVersion,
+};
+
+/// Continuously waits for bytes from the STOMP server.
+pub(crate) fn receive_bytes(
+	mut tcp_stream: TcpStream,
+	frame_sender: Sender<Result<Frame, StompError>>,
+	shared: Arc<Shared>,
+	mut body_streaming: Option<BodyStreaming>,
+) -> Result<(), StompError> {
+	let mut receive_buffer = [0; 4096]; // 4 KiB
+	let mut pending_data: Vec<u8> = Vec::new(); // Infinite
+	let mut streaming_body: Option<StreamingBody> = None;
+	let mut resyncing = false; // Discarding the rest of a bad frame
+	let mut frame_ended = false; // The first EOL after a frame pads it, rather than being a heart-beat
+
+	loop {
+		// Try to receive some bytes, carrying on if the read timeout elapses without any arriving
+		let received_byte_count = match tcp_stream.read(&mut receive_buffer) {
+			Ok(count) => count,
+			Err(error) if is_idle(&error) => {
+				// Nothing this interval, which is only a problem if the server should have sent a heart-beat
+				heart_beat::check_received(&shared)?;
+				continue;
+			}
+			Err(_) if shared.is_disconnecting() => return Ok(()), // The server is closing the connection, as we asked
+			Err(error) => return Err(error.into()),
+		};
+		if received_byte_count == 0 {
+			return Ok(()); // Give up, there's nothing left to receive
+		}
+		shared.received();
+
+		// Append the received bytes to the unprocessed data
+		pending_data.extend_from_slice(&receive_buffer[..received_byte_count]);
+
+		// Use the latest options, as they can change after the connection is opened
+		let options = lock(&shared.parse_options).clone();
+
+		// Remove as much as possible from the unprocessed data
+		loop {
+			// Throw away what's left of a bad frame, until the next one starts
+			if resyncing {
+				resyncing = !skip_frame(&mut pending_data);
+				if resyncing {
+					break; // Wait for more data
+				}
+			}
+
+			// Carry on writing a large body to its sink
+			if let Some(streaming) = streaming_body.as_mut() {
+				let used_count = streaming.feed(&pending_data)?;
+				pending_data.drain(..used_count);
+
+				// Ensure we're terminated with a NT, as any EOLs after it are skipped before the next frame
+				if !streaming.is_complete() || pending_data.is_empty() {
+					break; // Wait for more data
+				}
+				if pending_data[0] != 0x00 {
+					let error = StompError::NullTermination {
+						command: streaming_body.take().unwrap().frame.command,
+					};
+
+					resyncing = !recover(error, &mut pending_data, &frame_sender)?;
+					continue;
+				}
+				pending_data.drain(..1);
+
+				let frame = streaming_body.take().unwrap().finish()?;
+				deliver(frame, &shared, &frame_sender)?;
+				frame_ended = true;
+
+				continue;
+			}
+
+			// Skip heart-beats between frames
+			let heart_beat_length = frame::heart_beat_length(&pending_data);
+			for heart_beat in pending_data.drain(..heart_beat_length) {
+				if heart_beat == b'\n' && !std::mem::take(&mut frame_ended) {
+					shared.observe(Direction::Received, None);
+				}
+			}
+
+			// Start writing a large body to a sink rather than buffering it, however large it is
+			if let Some(streaming) = body_streaming.as_mut() {
+				let streaming_options = ParseOptions {
+					max_frame_size: None,
+					..options.clone()
+				};
+				let head = match frame::parse_head_with_options(&pending_data, &streaming_options) {
+					Ok(head) => head,
+					Err(error) => {
+						resyncing = !recover(error, &mut pending_data, &frame_sender)?;
+						continue;
+					}
+				};
+
+				if let Some((frame, body_start_position)) = head {
+					let length = frame.content_length();
+
+					if length.is_some_and(|length| length >= streaming.min_length) {
+						let sink = (streaming.sink_factory)(&frame)?;
+						pending_data.drain(..body_start_position);
+						streaming_body = Some(StreamingBody::new(frame, sink, length.unwrap()));
+
+						continue;
+					}
+				}
+			}
+
+			// Remove the next complete frame
+			let parsed = match frame::parse_with_options(&pending_data, &options) {
+				Ok(parsed) => parsed,
+				Err(error) => {
+					resyncing = !recover(error, &mut pending_data, &frame_sender)?;
+					continue;
+				}
+			};
+			if parsed.is_none() {
+				break; // Wait for more data
+			}
+			let (frame, end_position) = parsed.unwrap();
+			pending_data.drain(..end_position + 1);
+
+			deliver(frame, &shared, &frame_sender)?;
+			frame_ended = true;
+		}
+
+		// Give up on a frame that has grown too large without ending
+		if let Some(max_frame_size) = options.max_frame_size {
+			if pending_data.len() > max_frame_size {
+				return Err(StompError::FrameTooLarge {
+					size: pending_data.len(),
+					max_frame_size,
+				});
+			}
+		}
+	}
+}
+
+/// Hands a received frame over to the connection.
+fn deliver(
+	frame: Frame,
+	shared: &Arc<Shared>,
+	frame_sender: &Sender<Result<Frame, StompError>>,
+) -> Result<(), StompError> {
+	shared.observe(Direction::Received, Some(&frame));
+
+	// Anything other than the handshake before the handshake is out of sequence
+	if shared.track_state
+		&& shared.version().is_none()
+		&& frame.command != "CONNECTED"
+		&& frame.command != "ERROR"
+	{
+		return Err(StompError::UnexpectedCommand {
+			expected: "CONNECTED".to_string(),
+			received: frame.command,
+		});
+	}
+
+	// The handshake only happens once
+	if shared.track_state && shared.version().is_some() && frame.command == "CONNECTED" {
+		return Err(StompError::UnexpectedCommand {
+			expected: "MESSAGE, RECEIPT or ERROR".to_string(),
+			received: frame.command,
+		});
+	}
+
+	// Nothing more can be sent once the server has complained
+	let server_error = match frame.command.as_str() {
+		"ERROR" => Some(ServerError::try_from(&frame)?),
+		_ => None,
+	};
+	if let Some(server_error) = &server_error {
+		*lock(&shared.poisoned) = Some(server_error.clone());
+	}
+
+	// Hand receipts over to whoever is waiting for them, rather than the application
+	if let Some(receipt_id) = frame.receipt_id() {
+		let outcome = match (frame.command.as_str(), server_error) {
+			("RECEIPT", _) => Some(Ok(())),
+			(_, Some(server_error)) => Some(Err(server_error)),
+			_ => None,
+		};
+
+		if let Some(outcome) = outcome {
+			if shared.receipt_arrived(receipt_id, outcome) {
+				return Ok(());
+			}
+		}
+	}
+
+	// The server rejects the handshake with the versions it does support, if none of ours are
+	if frame.command == "ERROR" && shared.version().is_none() {
+		if let Some(supported) = frame.get_header("version") {
+			return Err(StompError::UnsupportedVersion {
+				offered: shared.offered_versions(),
+				supported: split_versions(supported),
+			});
+		}
+	}
+
+	// Remember which protocol version the server agreed to, as long as it is one we offered
+	if frame.command == "CONNECTED" {
+		let header = frame.get_header("version");
+		let version = StompVersion::from_header(header);
+		let offered = shared.offered_versions();
+		if !version.is_some_and(|version| offered.iter().any(|offered| offered == version.as_str()))
+		{
+			return Err(StompError::VersionNotOffered {
+				offered,
+				agreed: header.unwrap_or("1.0").to_string(),
+			});
+		}
+
+		*lock(&shared.version) = version;
+		*lock(&shared.connected_info) = ConnectedInfo::try_from(&frame).ok(); // Kept for diagnostics
+
+		// Only STOMP 1.1 onwards escapes header values
+		lock(&shared.parse_options).literal_header_values = version == Some(StompVersion::V1_0);
+
+		// Start sending heart-beats, if both sides want them
+		let server_heart_beat = frame
+			.headers
+			.get("heart-beat")
+			.map_or(HeartBeat::default(), HeartBeat::from_header_value);
+		let heart_beat = shared.heart_beat.negotiate(&server_heart_beat);
+		*lock(&shared.agreed_heart_beat) = Some(heart_beat);
+
+		// Wake up often enough to notice the server's heart-beats stopping
+		if !heart_beat.receive.is_zero() {
+			shared.limit_read_timeout(heart_beat.receive)?;
+		}
+		if !heart_beat.send.is_zero() {
+			let thread_name = format!("{}-heart-beat", shared.thread_name_prefix);
+			let shared = shared.clone();
+			thread::Builder::new()
+				.name(thread_name)
+				.spawn(move || heart_beat::send_heart_beats(shared, heart_beat.send))?;
+		}
+	}
+
+	// Remember which messages need acknowledging
+	let is_message = frame.command == "MESSAGE";
+	if is_message {
+		if let (Some(subscription), Some(message_id)) = (frame.subscription(), frame.message_id()) {
+			lock(&shared.subscriptions).received(subscription, message_id, frame.ack_id());
+		}
+	}
+
+	shared.queued(flow_control::frame_size(&frame));
+	frame_sender
+		.send(Ok(frame))
+		.map_err(|_| StompError::ConnectionClosed)?; // Nobody is listening anymore
+
+	// Stop reading while too many frames are waiting to be taken
+	shared.wait_for_backlog();
+
+	// Stop handing over messages while too many are waiting to be acknowledged
+	if is_message {
+		shared.wait_for_capacity();
+	}
+
+	Ok(())
+}
+
+/// Reports a bad frame & skips past it, returning whether the next frame was reached.
+/// Errors that can't be recovered from are returned instead, stopping the receive thread.
+fn recover(
+	error: StompError,
+	pending_data: &mut Vec<u8>,
+	frame_sender: &Sender<Result<Frame, StompError>>,
+) -> Result<bool, StompError> {
+	if !error.is_recoverable() {
+		return Err(error);
+	}
+
+	frame_sender
+		.send(Err(error))
+		.map_err(|_| StompError::ConnectionClosed)?; // Nobody is listening anymore
+
+	Ok(skip_frame(pending_data))
+}
+
+/// Removes everything up to & including the next NULL byte, along with any EOLs after it.
+/// Returns false, having removed everything, if the end of the frame hasn't arrived yet.
+fn skip_frame(pending_data: &mut Vec<u8>) -> bool {
+	let end_position = pending_data.iter().position(|&byte| byte == 0x00);
+	if end_position.is_none() {
+		pending_data.clear();
+		return false;
+	}
+	pending_data.drain(..=end_position.unwrap());
+
+	let eol_count = frame::heart_beat_length(pending_data);
+	pending_data.drain(..eol_count);
+
+	true
+}
+
+/// Checks if a read failed only because no data arrived before the read timeout.
+fn is_idle(error: &io::Error) -> bool {
+	matches!(
+		error.kind(),
+		ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use crate::{
+		error::StompError,
+		test_support::{frame_bytes, FakeServer, Reply},
+	};
+
+	#[test]
+	fn tracked_connection_rejects_message_before_connected() {
+		let server = FakeServer::silent();
+		let mut connection = server.builder().track_state(true).open().unwrap();
+
+		server.write(&frame_bytes(
+			"MESSAGE",
+			&[("subscription", "0"), ("message-id", "1")],
+			"Hello",
+		));
+
+		let result = connection.recv_timeout(Duration::from_secs(1));
+		assert!(
+			matches!(&result, Err(StompError::UnexpectedCommand { expected, received })
+				if expected == "CONNECTED" && received == "MESSAGE"),
+			"{:?}",
+			result
+		);
+		connection.wait_timeout(Duration::from_secs(1)).unwrap(); // The receive thread stopped
+	}
+
+	#[test]
+	fn tracked_connection_rejects_second_connected() {
+		let server = FakeServer::start(Box::new(|frame| match frame.command.as_str() {
+			"CONNECT" => vec![
+				Reply::Bytes(frame_bytes("CONNECTED", &[("version", "1.2")], "")),
+				Reply::Bytes(frame_bytes("CONNECTED", &[("version", "1.2")], "")),
+			],
+			_ => Vec::new(),
+		}));
+		let mut connection = server
+			.builder()
+			.accept_version("1.2")
+			.track_state(true)
+			.open()
+			.unwrap();
+
+		connection
+			.connect_and_authenticate("user", "", None)
+			.unwrap();
+
+		let result = connection.recv_timeout(Duration::from_secs(1));
+		assert!(
+			matches!(&result, Err(StompError::UnexpectedCommand { received, .. }) if received == "CONNECTED"),
+			"{:?}",
+			result
+		);
+		connection.wait_timeout(Duration::from_secs(1)).unwrap(); // The receive thread stopped
+	}
+
+	#[test]
+	fn strict_connection_skips_unknown_commands() {
+		let server = FakeServer::silent();
+		let connection = server.builder().strict_commands(true).open().unwrap();
+
+		server.write(b"PING\n\n\x00");
+		server.write(&frame_bytes("RECEIPT", &[("receipt-id", "1")], ""));
+
+		let result = connection.recv_timeout(Duration::from_secs(1));
+		assert!(
+			matches!(&result, Err(StompError::UnknownCommand(command)) if command == "PING"),
+			"{:?}",
+			result
+		);
+		assert_eq!(
+			connection
+				.recv_timeout(Duration::from_secs(1))
+				.unwrap()
+				.command,
+			"RECEIPT"
+		);
+	}
+
+	#[test]
+	fn permissive_connection_passes_unknown_commands_through() {
+		let server = FakeServer::silent();
+		let connection = server.connect();
+
+		server.write(b"PING\n\n\x00");
+
+		assert_eq!(
+			connection
+				.recv_timeout(Duration::from_secs(1))
+				.unwrap()
+				.command,
+			"PING"
+		);
+	}
+
+	#[test]
+	fn buffer_up_to_max_frame_size_keeps_waiting() {
+		let server = FakeServer::silent();
+		let connection = server.builder().max_frame_size(32).open().unwrap();
+
+		// A frame that hasn't ended yet, exactly at the limit
+		let mut unfinished = b"MESSAGE\n\n".to_vec();
+		unfinished.resize(32, b'a');
+		server.write(&unfinished);
+
+		let error = connection
+			.recv_timeout(Duration::from_millis(200))
+			.unwrap_err();
+		assert!(matches!(error, StompError::Timeout), "{:?}", error);
+
+		// The rest of it still arrives
+		server.write(b"\x00");
+		let frame = connection.recv_timeout(Duration::from_secs(1)).unwrap();
+		assert_eq!(frame.body_len(), 23);
+	}
+
+	#[test]
+	fn buffer_over_max_frame_size_closes_connection() {
+		let server = FakeServer::silent();
+		let connection = server.builder().max_frame_size(32).open().unwrap();
+
+		let mut unfinished = b"MESSAGE\n\n".to_vec();
+		unfinished.resize(33, b'a');
+		server.write(&unfinished);
+
+		let error = connection.recv_timeout(Duration::from_secs(1)).unwrap_err();
+		assert!(
+			matches!(
+				error,
+				StompError::FrameTooLarge {
+					size: 33,
+					max_frame_size: 32
+				}
+			),
+			"{:?}",
+			error
+		);
+	}
+
+	#[test]
+	fn error_with_versions_before_connected_is_unsupported_version() {
+		let server = FakeServer::start(Box::new(|_| {
+			vec![Reply::Bytes(frame_bytes(
+				"ERROR",
+				&[
+					("version", "1.0,1.1"),
+					("message", "Supported protocol versions are 1.0 1.1"),
+				],
+				"",
+			))]
+		}));
+		let mut connection = server.builder().accept_version("1.2").open().unwrap();
+
+		let result = connection.connect_and_authenticate("user", "", None);
+
+		assert!(
+			matches!(
+				&result,
+				Err(StompError::UnsupportedVersion { offered, supported })
+					if offered == &["1.2"] && supported == &["1.0", "1.1"]
+			),
+			"{:?}",
+			result
+		);
+	}
+
+	#[test]
+	fn connected_with_version_not_offered_is_refused() {
+		let server = FakeServer::start(Box::new(|_| {
+			vec![Reply::Bytes(frame_bytes(
+				"CONNECTED",
+				&[("version", "1.1")],
+				"",
+			))]
+		}));
+		let mut connection = server.builder().accept_version("1.2").open().unwrap();
+
+		let result = connection.connect_and_authenticate("user", "", None);
+
+		assert!(
+			matches!(
+				&result,
+				Err(StompError::VersionNotOffered { offered, agreed })
+					if offered == &["1.2"] && agreed == "1.1"
+			),
+			"{:?}",
+			result
+		);
+		assert_eq!(connection.shared.version(), None);
+	}
+}
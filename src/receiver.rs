@@ -0,0 +1,105 @@
+use std::{
+	cell::RefCell,
+	collections::VecDeque,
+	sync::{
+		mpsc::{Receiver, RecvError, RecvTimeoutError, TryRecvError},
+		Arc,
+	},
+	time::Duration,
+};
+
+use crate::{error::StompError, flow_control::frame_size, frame::Frame, shared::Shared};
+
+/// Receives frames from the receive thread, keeping track of how many are waiting for flow control.
+pub struct FrameReceiver {
+	receiver: Receiver<Result<Frame, StompError>>,
+	shared: Arc<Shared>,
+	buffered: RefCell<VecDeque<Result<Frame, StompError>>>, // Peeked at or set aside, already taken from the channel
+}
+
+impl FrameReceiver {
+	pub(crate) fn new(
+		receiver: Receiver<Result<Frame, StompError>>,
+		shared: Arc<Shared>,
+	) -> FrameReceiver {
+		FrameReceiver {
+			receiver,
+			shared,
+			buffered: RefCell::new(VecDeque::new()),
+		}
+	}
+
+	/// Waits for the next frame.
+	pub fn recv(&self) -> Result<Result<Frame, StompError>, RecvError> {
+		if let Some(result) = self.buffered.borrow_mut().pop_front() {
+			return Ok(result);
+		}
+
+		self.receiver.recv().inspect(|result| self.taken(result))
+	}
+
+	/// Takes the next frame, if one is waiting.
+	pub fn try_recv(&self) -> Result<Result<Frame, StompError>, TryRecvError> {
+		if let Some(result) = self.buffered.borrow_mut().pop_front() {
+			return Ok(result);
+		}
+
+		self.receiver
+			.try_recv()
+			.inspect(|result| self.taken(result))
+	}
+
+	/// Waits for the next frame, giving up after the timeout.
+	pub fn recv_timeout(
+		&self,
+		timeout: Duration,
+	) -> Result<Result<Frame, StompError>, RecvTimeoutError> {
+		if let Some(result) = self.buffered.borrow_mut().pop_front() {
+			return Ok(result);
+		}
+
+		self.receiver
+			.recv_timeout(timeout)
+			.inspect(|result| self.taken(result))
+	}
+
+	/// Waits for the next frame, without taking it.
+	/// Errors are taken straight away, as they can't be given back more than once.
+	pub fn peek(&mut self) -> Result<&Frame, StompError> {
+		if self.buffered.get_mut().is_empty() {
+			let result = self
+				.receiver
+				.recv()
+				.map_err(|_| StompError::ConnectionClosed)?;
+			self.taken(&result);
+
+			self.buffered.get_mut().push_back(result);
+		}
+
+		let buffered = self.buffered.get_mut();
+		if let Some(Err(_)) = buffered.front() {
+			return Err(buffered.pop_front().unwrap().unwrap_err());
+		}
+
+		match buffered.front() {
+			Some(Ok(frame)) => Ok(frame),
+			_ => unreachable!(),
+		}
+	}
+
+	/// Puts frames that were taken back in front of everything else, in the same order.
+	pub(crate) fn put_back(&self, results: Vec<Result<Frame, StompError>>) {
+		let mut buffered = self.buffered.borrow_mut();
+
+		for result in results.into_iter().rev() {
+			buffered.push_front(result);
+		}
+	}
+
+	/// Records that a frame is no longer waiting, so the receive thread can carry on reading.
+	fn taken(&self, result: &Result<Frame, StompError>) {
+		if let Ok(frame) = result {
+			self.shared.dequeued(frame_size(frame));
+		}
+	}
+}
@@ -0,0 +1,193 @@
+use std::{
+	sync::mpsc::{channel, Receiver, Sender},
+	thread::{sleep, spawn},
+	time::Duration,
+};
+
+use crate::{error::StompError, frame::Frame, Connection};
+
+/// Opens a connection, such as by calling `open()`, `authenticate()` & `subscribe()`.
+pub type Connect = Box<dyn FnMut() -> Result<Connection, StompError> + Send + 'static>;
+
+/// Keeps receiving frames from a STOMP server, opening a new connection whenever the current one goes away.
+///
+/// The connect function is called again for every reconnect, so it should restore any subscriptions.
+pub struct AutoReconnect {
+	connect: Connect,
+	max_attempts: u32,
+	backoff: Duration,
+	attempt_handler: Option<Box<dyn Fn(u32) + Send + 'static>>,
+}
+
+impl AutoReconnect {
+	/// Starts configuring a connection that is opened with the given function.
+	pub fn new(connect: Connect) -> AutoReconnect {
+		AutoReconnect {
+			connect,
+			max_attempts: 0,
+			backoff: Duration::from_secs(1),
+			attempt_handler: None,
+		}
+	}
+
+	/// Sets how many times in a row to try connecting before giving up, where 0 (the default) means forever.
+	pub fn max_attempts(mut self, max_attempts: u32) -> AutoReconnect {
+		self.max_attempts = max_attempts;
+		self
+	}
+
+	/// Sets how long to wait between attempts.
+	pub fn backoff(mut self, backoff: Duration) -> AutoReconnect {
+		self.backoff = backoff;
+		self
+	}
+
+	/// Sets a function to call with the attempt number before each attempt, such as for logging.
+	pub fn on_attempt(mut self, handler: Box<dyn Fn(u32) + Send + 'static>) -> AutoReconnect {
+		self.attempt_handler = Some(handler);
+		self
+	}
+
+	/// Connects on a background thread, giving back a channel of frames across every connection.
+	/// Once the attempts run out, `StompError::ReconnectExhausted` is sent & nothing else follows.
+	/// Errors that aren't transient, from connecting or the connection, are sent & stop it straight away.
+	pub fn start(self) -> Receiver<Result<Frame, StompError>> {
+		let (frame_sender, frame_receiver) = channel();
+
+		spawn(move || self.run(frame_sender));
+
+		frame_receiver
+	}
+
+	/// Connects & forwards frames until the attempts run out or nobody is listening anymore.
+	fn run(mut self, frame_sender: Sender<Result<Frame, StompError>>) {
+		let mut attempts = 0;
+
+		loop {
+			// Give up once we've tried too many times in a row
+			if self.max_attempts != 0 && attempts >= self.max_attempts {
+				let _ = frame_sender.send(Err(StompError::ReconnectExhausted { attempts }));
+				return;
+			}
+
+			// Wait a moment before trying again
+			if attempts > 0 {
+				sleep(self.backoff);
+			}
+
+			attempts += 1;
+			if let Some(attempt_handler) = &self.attempt_handler {
+				attempt_handler(attempts);
+			}
+
+			let connection = match (self.connect)() {
+				Ok(connection) => connection,
+				Err(error) if error.is_transient() => continue,
+				Err(error) => {
+					let _ = frame_sender.send(Err(error));
+					return; // Trying again won't help
+				}
+			};
+			attempts = 0;
+
+			// Forward everything until this connection goes away
+			while let Ok(result) = connection.frame_receiver.recv() {
+				let is_fatal = result.as_ref().is_err_and(|error| !error.is_recoverable());
+				let is_permanent = result.as_ref().is_err_and(|error| !error.is_transient());
+
+				if frame_sender.send(result).is_err() {
+					return; // Nobody is listening anymore
+				}
+
+				if is_fatal && is_permanent {
+					return; // Trying again won't help
+				}
+				if is_fatal {
+					break;
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		sync::{Arc, Mutex},
+		time::Instant,
+	};
+
+	use super::*;
+	use crate::test_support::{frame_bytes, FakeServer};
+
+	#[test]
+	fn gives_up_after_max_attempts() {
+		let attempts = Arc::new(Mutex::new(Vec::new()));
+		let recorded_attempts = attempts.clone();
+		let started = Instant::now();
+
+		let frame_receiver = AutoReconnect::new(Box::new(|| Err(StompError::Timeout)))
+			.max_attempts(3)
+			.backoff(Duration::from_millis(20))
+			.on_attempt(Box::new(move |attempt| {
+				recorded_attempts.lock().unwrap().push(attempt)
+			}))
+			.start();
+
+		let result = frame_receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+		assert!(
+			matches!(result, Err(StompError::ReconnectExhausted { attempts: 3 })),
+			"{:?}",
+			result
+		);
+		assert!(frame_receiver.recv().is_err()); // Nothing else follows
+		assert_eq!(*attempts.lock().unwrap(), [1, 2, 3]);
+		assert!(started.elapsed() >= Duration::from_millis(40)); // Backed off between each attempt
+	}
+
+	#[test]
+	fn stops_on_permanent_connect_error() {
+		let frame_receiver = AutoReconnect::new(Box::new(|| {
+			Err(StompError::NullTermination {
+				command: "CONNECTED".to_string(),
+			})
+		}))
+		.backoff(Duration::from_millis(1))
+		.start();
+
+		let result = frame_receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+		assert!(
+			matches!(result, Err(StompError::NullTermination { .. })),
+			"{:?}",
+			result
+		);
+		assert!(frame_receiver.recv().is_err());
+	}
+
+	#[test]
+	fn forwards_frames_once_an_attempt_succeeds() {
+		let server = FakeServer::silent();
+		let builder = server.builder();
+		let mut builder = Some(builder);
+		let mut failures = 2;
+
+		let frame_receiver = AutoReconnect::new(Box::new(move || {
+			if failures > 0 {
+				failures -= 1;
+				return Err(StompError::Timeout);
+			}
+
+			builder.take().ok_or(StompError::ConnectionClosed)?.open()
+		}))
+		.max_attempts(3)
+		.backoff(Duration::from_millis(1))
+		.start();
+		server.write(&frame_bytes("RECEIPT", &[("receipt-id", "1")], ""));
+
+		let frame = frame_receiver
+			.recv_timeout(Duration::from_secs(5))
+			.unwrap()
+			.unwrap();
+		assert_eq!(frame.command, "RECEIPT");
+	}
+}
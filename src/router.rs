@@ -0,0 +1,260 @@
+use crate::{error::StompError, frame::Frame, Connection};
+
+/// A function that handles frames received on a destination.
+pub type Handler = Box<dyn Fn(Frame) + Send + 'static>;
+
+/// Dispatches received frames to handlers based on their destination.
+///
+/// Destinations can be patterns, where `?` matches any one character, `*` matches anything except a `/`,
+/// and `**` matches anything at all. The first registered handler that matches is used.
+#[derive(Default)]
+pub struct MessageRouter {
+	routes: Vec<(String, Handler)>,
+	strict: bool,
+	error_handler: Option<Box<dyn Fn(StompError) + Send + 'static>>,
+}
+
+impl MessageRouter {
+	/// Creates a router with no handlers.
+	pub fn new() -> MessageRouter {
+		MessageRouter::default()
+	}
+
+	/// Registers a handler for a destination or destination pattern.
+	pub fn register(&mut self, destination: &str, handler: Handler) {
+		self.routes.push((destination.to_string(), handler));
+	}
+
+	/// Treats frames that no handler matches as errors, rather than silently dropping them.
+	pub fn strict(mut self, strict: bool) -> MessageRouter {
+		self.strict = strict;
+		self
+	}
+
+	/// Sets a function to call with errors that happen while routing forever.
+	pub fn on_error(mut self, handler: Box<dyn Fn(StompError) + Send + 'static>) -> MessageRouter {
+		self.error_handler = Some(handler);
+		self
+	}
+
+	/// Dispatches a frame to the first handler matching its destination.
+	/// Frames without a destination, such as RECEIPT frames, are ignored.
+	pub fn route(&self, frame: Frame) -> Result<(), StompError> {
+		let destination = frame.destination();
+		if destination.is_none() {
+			return Ok(());
+		}
+		let destination = destination.unwrap().to_string();
+
+		let handler = self
+			.routes
+			.iter()
+			.find(|(pattern, _)| matches_pattern(pattern.as_bytes(), destination.as_bytes()));
+
+		match handler {
+			Some((_, handler)) => handler(frame),
+			None if self.strict => return Err(StompError::NoHandler { destination }),
+			None => {}
+		}
+
+		Ok(())
+	}
+
+	/// Reads frames from a connection & dispatches them to handlers, until the connection closes.
+	/// Errors the connection carries on after, such as a bad frame or no handler matching, are passed to the
+	/// error handler, if there is one. Errors that stop the connection are returned instead.
+	pub fn route_forever(&self, connection: Connection) -> Result<(), StompError> {
+		// Nothing is left to route once the connection has closed
+		while let Ok(result) = connection.frame_receiver.recv() {
+			let result = match result {
+				Ok(frame) => self.route(frame),
+				Err(error) if !error.is_recoverable() => return Err(error),
+				Err(error) => Err(error),
+			};
+
+			if let (Err(error), Some(error_handler)) = (result, &self.error_handler) {
+				error_handler(error);
+			}
+		}
+
+		Ok(())
+	}
+}
+
+/// Checks if a destination matches a pattern.
+///
+/// Works through the pattern one part at a time, keeping track of every position in the destination that
+/// the pattern so far could have reached, so it never backtracks however many wildcards there are.
+fn matches_pattern(pattern: &[u8], destination: &[u8]) -> bool {
+	let mut reachable = vec![false; destination.len() + 1];
+	reachable[0] = true;
+
+	let mut position = 0;
+	while position < pattern.len() {
+		let mut next = vec![false; destination.len() + 1];
+
+		match pattern[position..] {
+			// Anything at all, including nothing, where any more stars are redundant
+			[b'*', b'*', ..] => {
+				position += pattern[position..]
+					.iter()
+					.take_while(|&&byte| byte == b'*')
+					.count();
+
+				let mut reached = false;
+				for (index, next) in next.iter_mut().enumerate() {
+					reached |= reachable[index];
+					*next = reached;
+				}
+
+				reachable = next;
+				continue;
+			}
+
+			// Anything up to the next slash, including nothing
+			[b'*', ..] => {
+				let mut reached = false;
+				for (index, next) in next.iter_mut().enumerate() {
+					if index > 0 && destination[index - 1] == b'/' {
+						reached = false;
+					}
+					reached |= reachable[index];
+					*next = reached;
+				}
+			}
+
+			// Any one character
+			[b'?', ..] => next[1..].copy_from_slice(&reachable[..destination.len()]),
+
+			[byte, ..] => {
+				for index in 0..destination.len() {
+					next[index + 1] = reachable[index] && destination[index] == byte;
+				}
+			}
+
+			[] => unreachable!(),
+		}
+
+		reachable = next;
+		position += 1;
+	}
+
+	reachable[destination.len()]
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		sync::{Arc, Mutex},
+		time::{Duration, Instant},
+	};
+
+	use super::*;
+	use crate::test_support::{frame_bytes, FakeServer, Reply};
+
+	fn matches(pattern: &str, destination: &str) -> bool {
+		matches_pattern(pattern.as_bytes(), destination.as_bytes())
+	}
+
+	#[test]
+	fn matches_exact_destination() {
+		assert!(matches("/queue/orders", "/queue/orders"));
+		assert!(!matches("/queue/orders", "/queue/order"));
+		assert!(!matches("/queue/orders", "/queue/orders/new"));
+	}
+
+	#[test]
+	fn single_star_stays_within_segment() {
+		assert!(matches("/queue/*", "/queue/orders"));
+		assert!(matches("/queue/*", "/queue/"));
+		assert!(matches("/queue/*/new", "/queue/orders/new"));
+		assert!(matches("/queue/o*s", "/queue/orders"));
+		assert!(!matches("/queue/*", "/queue/orders/new"));
+		assert!(!matches("/queue/*", "/topic/orders"));
+	}
+
+	#[test]
+	fn double_star_crosses_segments() {
+		assert!(matches("/queue/**", "/queue/orders/new"));
+		assert!(matches("/queue/**", "/queue/"));
+		assert!(matches("**/new", "/queue/orders/new"));
+		assert!(matches("/**/orders/**", "/a/b/orders/c/d"));
+		assert!(!matches("/queue/**", "/topic/orders"));
+	}
+
+	#[test]
+	fn question_mark_matches_one_character() {
+		assert!(matches("/queue/?", "/queue/a"));
+		assert!(matches("/queue/?", "/queue//"));
+		assert!(!matches("/queue/?", "/queue/"));
+		assert!(!matches("/queue/?", "/queue/ab"));
+	}
+
+	#[test]
+	fn repeated_double_stars_dont_backtrack() {
+		let pattern = "**".repeat(40) + "x";
+		let destination = "a".repeat(5000);
+
+		let started = Instant::now();
+		assert!(!matches(&pattern, &destination));
+		assert!(matches(&pattern, &(destination + "x")));
+		assert!(started.elapsed() < Duration::from_secs(1));
+
+		let pattern = "/*a".repeat(20) + "/b";
+		assert!(!matches(&pattern, &"/aaaaaaaaaaaaaaaaaaaa".repeat(20)));
+	}
+
+	#[test]
+	fn strict_router_reports_no_match() {
+		let router = MessageRouter::new().strict(true);
+
+		let error = router
+			.route(Frame::new("MESSAGE").with_header("destination", "/queue/none"))
+			.unwrap_err();
+		assert!(
+			matches!(&error, StompError::NoHandler { destination } if destination == "/queue/none"),
+			"{:?}",
+			error
+		);
+	}
+
+	#[test]
+	fn route_forever_returns_when_connection_closes() {
+		let server = FakeServer::start(Box::new(|frame| {
+			if frame.command != "SUBSCRIBE" {
+				return Vec::new();
+			}
+
+			vec![
+				Reply::Bytes(frame_bytes(
+					"MESSAGE",
+					&[
+						("destination", "/queue/orders"),
+						("message-id", "1"),
+						("subscription", "1"),
+					],
+					"Hello",
+				)),
+				Reply::Close,
+			]
+		}));
+		let mut connection = server.connect();
+		connection.subscribe(1, "/queue/orders").unwrap();
+
+		let routed = Arc::new(Mutex::new(Vec::new()));
+		let mut router = MessageRouter::new();
+		let handled = routed.clone();
+		router.register(
+			"/queue/*",
+			Box::new(move |frame| {
+				handled
+					.lock()
+					.unwrap()
+					.push(frame.body_raw().map(<[u8]>::to_vec))
+			}),
+		);
+
+		assert!(router.route_forever(connection).is_ok());
+		assert_eq!(*routed.lock().unwrap(), vec![Some(b"Hello".to_vec())]);
+	}
+}
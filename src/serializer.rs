@@ -0,0 +1,42 @@
+use crate::frame::{self, Frame};
+
+/// Converts STOMP frames into the bytes that are sent to the server.
+pub trait FrameSerializer: Send + Sync {
+	/// Converts a frame into bytes.
+	fn serialize(&self, frame: &Frame) -> Vec<u8>;
+}
+
+/// Serializes frames exactly as the STOMP specification describes.
+pub struct DefaultSerializer;
+
+impl FrameSerializer for DefaultSerializer {
+	fn serialize(&self, frame: &Frame) -> Vec<u8> {
+		let headers = frame.headers.iter().collect::<Vec<(&str, &str)>>();
+
+		frame::create_bytes(
+			&frame.command,
+			(!headers.is_empty()).then_some(headers),
+			frame.body.as_deref(),
+		)
+	}
+}
+
+/// Serializes frames with a space after each header colon, for human-readable logging.
+/// Most servers will include the space in the header value, so this is unsuitable for sending.
+pub struct PrettySerializer;
+
+impl FrameSerializer for PrettySerializer {
+	fn serialize(&self, frame: &Frame) -> Vec<u8> {
+		let header_lines = frame
+			.headers
+			.iter()
+			.map(|(name, value)| format!("{}: {}\n", name, value))
+			.collect::<String>();
+
+		let mut bytes = format!("{}\n{}\n", frame.command, header_lines).into_bytes();
+		bytes.extend_from_slice(frame.body.as_deref().unwrap_or_default());
+		bytes.push(0x00);
+
+		bytes
+	}
+}
@@ -0,0 +1,497 @@
+use std::{
+	borrow::Cow,
+	collections::HashMap,
+	io::{self, ErrorKind, Write},
+	net::{Shutdown, TcpStream},
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Condvar, Mutex, MutexGuard,
+	},
+	time::{Duration, Instant},
+};
+
+use crate::{
+	connected::ConnectedInfo,
+	error::{ServerError, StompError},
+	flow_control::{Backlog, FlowControl},
+	frame::{self, Frame, ParseOptions},
+	frame_log::{Direction, FrameObserver},
+	header::Headers,
+	heart_beat::HeartBeat,
+	rate_limit::{self, TokenBucket},
+	receipt::Receipts,
+	serializer::FrameSerializer,
+	stats::Stats,
+	subscription::Subscriptions,
+	version::StompVersion,
+};
+
+/// How many times a write is retried after being interrupted by a signal.
+const MAX_INTERRUPTED_RETRIES: u32 = 5;
+
+/// State shared between a connection, its receive thread & any handles given out to messages.
+pub(crate) struct Shared {
+	tcp_stream: Mutex<TcpStream>,
+	closed: AtomicBool,
+	disconnecting: AtomicBool, // A DISCONNECT frame has been sent, so the server closing the connection is expected
+	pub(crate) poisoned: Mutex<Option<ServerError>>,
+	serializer: Box<dyn FrameSerializer>,
+	pub(crate) auto_nack: bool,
+	pub(crate) max_outstanding: Option<usize>,
+	pub(crate) track_state: bool,
+	pub(crate) thread_name_prefix: String,
+	pub(crate) accept_version: String,
+	pub(crate) heart_beat: HeartBeat,
+	pub(crate) agreed_heart_beat: Mutex<Option<HeartBeat>>,
+	pub(crate) connected_info: Mutex<Option<ConnectedInfo>>,
+	last_written: Mutex<Instant>,
+	last_received: Mutex<Instant>,
+	pub(crate) frame_observer: Mutex<Option<FrameObserver>>,
+	pub(crate) rate_limiter: Option<Mutex<TokenBucket>>,
+	pub(crate) version: Mutex<Option<StompVersion>>,
+	pub(crate) parse_options: Mutex<ParseOptions>,
+	pub(crate) subscriptions: Mutex<Subscriptions>,
+	pub(crate) transactions: Mutex<HashMap<String, Vec<String>>>, // Identifier to messages settled within it
+	pub(crate) receipts: Mutex<Receipts>,
+	receipt_arrived: Condvar,
+	pub(crate) flow_control: Option<FlowControl>,
+	backlog: Mutex<Backlog>,
+	backlog_taken: Condvar,
+	settled: Condvar,
+	delivery_paused: AtomicBool,
+}
+
+impl Shared {
+	pub(crate) fn new(tcp_stream: TcpStream, serializer: Box<dyn FrameSerializer>) -> Shared {
+		Shared {
+			tcp_stream: Mutex::new(tcp_stream),
+			closed: AtomicBool::new(false),
+			disconnecting: AtomicBool::new(false),
+			poisoned: Mutex::new(None),
+			serializer,
+			auto_nack: false,
+			max_outstanding: None,
+			track_state: false,
+			thread_name_prefix: "stomp-recv".to_string(),
+			accept_version: "1.2".to_string(),
+			heart_beat: HeartBeat::default(),
+			agreed_heart_beat: Mutex::new(None),
+			connected_info: Mutex::new(None),
+			last_written: Mutex::new(Instant::now()),
+			last_received: Mutex::new(Instant::now()),
+			frame_observer: Mutex::new(None),
+			rate_limiter: None,
+			version: Mutex::new(None),
+			parse_options: Mutex::new(ParseOptions::default()),
+			subscriptions: Mutex::new(Subscriptions::default()),
+			transactions: Mutex::new(HashMap::new()),
+			receipts: Mutex::new(Receipts::default()),
+			receipt_arrived: Condvar::new(),
+			flow_control: None,
+			backlog: Mutex::new(Backlog::default()),
+			backlog_taken: Condvar::new(),
+			settled: Condvar::new(),
+			delivery_paused: AtomicBool::new(false),
+		}
+	}
+
+	/// Serializes a frame & writes it to the STOMP server.
+	pub(crate) fn send(&self, frame: &Frame) -> Result<(), StompError> {
+		if rate_limit::is_limited(&frame.command) {
+			self.wait_for_rate_limit()?;
+		}
+
+		let mut frame = Cow::Borrowed(frame);
+
+		// Stop a NUL in the body from ending the frame early, whichever serializer is used
+		let body = frame.body.as_deref().unwrap_or_default();
+		if frame::needs_content_length(body, frame.headers.iter().map(|(name, _)| name)) {
+			let content_length = body.len().to_string();
+			frame
+				.to_mut()
+				.headers
+				.append(Headers::ContentLength.as_str(), &content_length);
+		}
+
+		// Refuse anything that would corrupt the stream, before any of it is written
+		let escaped = frame::escapes_headers(&frame.command)
+			&& self
+				.version()
+				.is_some_and(|version| version != StompVersion::V1_0);
+		frame::validate_outgoing(&frame, escaped)?;
+		self.observe(Direction::Sent, Some(&frame));
+
+		// Escape header values once the server has agreed to a version that expects it
+		if escaped {
+			frame.to_mut().headers = frame.headers.escaped();
+		}
+
+		self.expect_close(&frame.command);
+		self.write(&self.serializer.serialize(&frame))
+	}
+
+	/// Takes a token from the rate limiter, waiting for one or failing if there aren't any.
+	fn wait_for_rate_limit(&self) -> Result<(), StompError> {
+		if self.rate_limiter.is_none() {
+			return Ok(());
+		}
+
+		TokenBucket::wait_for_token(self.rate_limiter.as_ref().unwrap(), self)
+	}
+
+	/// Writes a frame that has already been turned into bytes, without checking or escaping it.
+	/// The frame is only used for the rate limit & observing what is sent.
+	pub(crate) fn send_raw(&self, frame: &Frame, bytes: &[u8]) -> Result<(), StompError> {
+		if rate_limit::is_limited(&frame.command) {
+			self.wait_for_rate_limit()?;
+		}
+
+		self.observe(Direction::Sent, Some(frame));
+		self.expect_close(&frame.command);
+		self.write(bytes)
+	}
+
+	/// Writes bytes to the STOMP server, failing if the connection has been closed.
+	pub(crate) fn write(&self, bytes: &[u8]) -> Result<(), StompError> {
+		if self.is_closed() {
+			return Err(StompError::ConnectionClosed);
+		}
+
+		// The server is about to close the connection after sending an ERROR frame
+		if let Some(error) = lock(&self.poisoned).as_ref() {
+			return Err(StompError::Server(error.clone()));
+		}
+
+		// Retry a few times if a signal interrupts us, as nothing actually went wrong
+		let mut tcp_stream = lock(&self.tcp_stream);
+		let mut written_count = 0;
+		let mut interrupted_count = 0;
+		while written_count < bytes.len() {
+			match tcp_stream.write(&bytes[written_count..]) {
+				Ok(0) => return Err(io::Error::from(ErrorKind::WriteZero).into()),
+				Ok(count) => written_count += count,
+				Err(error)
+					if error.kind() == ErrorKind::Interrupted
+						&& interrupted_count < MAX_INTERRUPTED_RETRIES =>
+				{
+					interrupted_count += 1;
+				}
+				Err(error) => return Err(error.into()),
+			}
+		}
+
+		*lock(&self.last_written) = Instant::now();
+
+		Ok(())
+	}
+
+	/// Flushes anything written to the TCP stream that hasn't been sent yet.
+	pub(crate) fn flush(&self) -> Result<(), StompError> {
+		if self.is_closed() {
+			return Err(StompError::ConnectionClosed);
+		}
+
+		lock(&self.tcp_stream).flush()?;
+
+		Ok(())
+	}
+
+	/// Hands a frame, or a heart-beat if there isn't one, to whatever is watching them, such as a frame log.
+	pub(crate) fn observe(&self, direction: Direction, frame: Option<&Frame>) {
+		if let Some(frame_observer) = lock(&self.frame_observer).as_ref() {
+			frame_observer(direction, frame);
+		}
+	}
+
+	/// Gets when anything was last written to the STOMP server.
+	pub(crate) fn last_written(&self) -> Instant {
+		*lock(&self.last_written)
+	}
+
+	/// Records that something, even if only a heart-beat, has just been received from the STOMP server.
+	pub(crate) fn received(&self) {
+		*lock(&self.last_received) = Instant::now();
+	}
+
+	/// Gets when anything was last received from the STOMP server.
+	pub(crate) fn last_received(&self) -> Instant {
+		*lock(&self.last_received)
+	}
+
+	/// Makes reads wait no longer than the given time for data, so the receive thread checks in at least that often.
+	/// The read timeout belongs to the socket, so this applies to the receive thread's copy of the TCP stream too.
+	pub(crate) fn limit_read_timeout(&self, limit: Duration) -> Result<(), StompError> {
+		let tcp_stream = lock(&self.tcp_stream);
+		let read_timeout = tcp_stream
+			.read_timeout()?
+			.map_or(limit, |read_timeout| read_timeout.min(limit));
+		tcp_stream.set_read_timeout(Some(read_timeout))?;
+
+		Ok(())
+	}
+
+	/// Shuts down the TCP stream in both directions.
+	pub(crate) fn shutdown(&self) -> Result<(), StompError> {
+		self.closed.store(true, Ordering::SeqCst);
+		self.settled.notify_all(); // Don't leave the receive thread paused
+		self.receipt_arrived.notify_all(); // Don't leave anyone waiting for a receipt
+		self.backlog_taken.notify_all(); // Don't leave the receive thread waiting for frames to be taken
+
+		// The server may have already closed it, such as after confirming a DISCONNECT
+		match lock(&self.tcp_stream).shutdown(Shutdown::Both) {
+			Err(error) if error.kind() == ErrorKind::NotConnected => Ok(()),
+			result => Ok(result?),
+		}
+	}
+
+	/// Remembers that the server will close the connection once it has handled a DISCONNECT frame.
+	fn expect_close(&self, command: &str) {
+		if command == "DISCONNECT" {
+			self.disconnecting.store(true, Ordering::SeqCst);
+		}
+	}
+
+	/// Checks if a DISCONNECT frame has been sent, so the server closing the connection isn't a problem.
+	pub(crate) fn is_disconnecting(&self) -> bool {
+		self.disconnecting.load(Ordering::SeqCst)
+	}
+
+	/// Checks if the connection has been closed by us.
+	pub(crate) fn is_closed(&self) -> bool {
+		self.closed.load(Ordering::SeqCst)
+	}
+
+	/// Records that a message was acknowledged (or not), waking the receive thread if it is paused.
+	pub(crate) fn settle(&self, id: &str) -> bool {
+		let settled = lock(&self.subscriptions).settle(id);
+
+		self.settled.notify_all();
+
+		settled
+	}
+
+	/// Sends an ACK or NACK frame within a transaction, settling the message once the transaction is committed.
+	pub(crate) fn send_in_transaction(
+		&self,
+		frame: &Frame,
+		transaction: &str,
+		message_id: &str,
+	) -> Result<(), StompError> {
+		let mut transactions = lock(&self.transactions);
+
+		let settled = transactions
+			.get_mut(transaction)
+			.ok_or_else(|| StompError::UnknownTransaction(transaction.to_string()))?;
+
+		self.send(frame)?;
+		settled.push(message_id.to_string());
+
+		Ok(())
+	}
+
+	/// Records that a RECEIPT frame, or an ERROR frame rejecting the frame that asked for it, arrived.
+	/// Wakes whoever is waiting for it, returning false if nobody is.
+	pub(crate) fn receipt_arrived(&self, id: &str, outcome: Result<(), ServerError>) -> bool {
+		let arrived = lock(&self.receipts).settle(id, outcome);
+
+		self.receipt_arrived.notify_all();
+
+		arrived
+	}
+
+	/// Waits for a receipt to arrive, forgetting about it if it does.
+	/// The receipt is still waited for after timing out, so a later attempt can pick it up.
+	pub(crate) fn wait_for_receipt(&self, id: &str, timeout: Duration) -> Result<(), StompError> {
+		let deadline = Instant::now() + timeout;
+
+		let mut receipts = lock(&self.receipts);
+		loop {
+			match receipts.take_settled(id) {
+				Some(Ok(())) => return Ok(()),
+				Some(Err(error)) => return Err(StompError::Server(error)),
+				None => {}
+			}
+
+			if self.is_closed() {
+				return Err(StompError::ConnectionClosed);
+			}
+
+			// The server closes the connection after an ERROR frame, so the receipt will never arrive
+			if let Some(error) = lock(&self.poisoned).as_ref() {
+				return Err(StompError::Server(error.clone()));
+			}
+
+			let now = Instant::now();
+			if now >= deadline {
+				return Err(StompError::Timeout);
+			}
+
+			receipts = self
+				.receipt_arrived
+				.wait_timeout(receipts, deadline - now)
+				.unwrap_or_else(|poisoned| poisoned.into_inner())
+				.0;
+		}
+	}
+
+	/// Blocks while there are too many messages waiting to be acknowledged.
+	/// Nothing else is received meanwhile, as this holds up the receive thread.
+	pub(crate) fn wait_for_capacity(&self) {
+		if self.max_outstanding.is_none() {
+			return;
+		}
+		let max_outstanding = self.max_outstanding.unwrap();
+
+		let mut subscriptions = lock(&self.subscriptions);
+		while subscriptions.outstanding_count() >= max_outstanding && !self.is_closed() {
+			self.delivery_paused.store(true, Ordering::SeqCst);
+
+			subscriptions = self
+				.settled
+				.wait(subscriptions)
+				.unwrap_or_else(|poisoned| poisoned.into_inner());
+		}
+
+		self.delivery_paused.store(false, Ordering::SeqCst);
+	}
+
+	/// Records that a frame is waiting to be taken from the channel.
+	pub(crate) fn queued(&self, size: usize) {
+		let mut backlog = lock(&self.backlog);
+		backlog.frames += 1;
+		backlog.bytes += size;
+	}
+
+	/// Records that a frame was taken from the channel, waking the receive thread if it is paused.
+	pub(crate) fn dequeued(&self, size: usize) {
+		let mut backlog = lock(&self.backlog);
+		backlog.frames = backlog.frames.saturating_sub(1);
+		backlog.bytes = backlog.bytes.saturating_sub(size);
+
+		self.backlog_taken.notify_all();
+	}
+
+	/// Blocks once too many frames are waiting to be taken, until enough of them have been.
+	pub(crate) fn wait_for_backlog(&self) {
+		if self.flow_control.is_none() {
+			return;
+		}
+		let flow_control = self.flow_control.as_ref().unwrap();
+
+		let mut backlog = lock(&self.backlog);
+		if !flow_control.is_above_high_water(&backlog) {
+			return;
+		}
+
+		backlog.paused = true;
+		backlog.pauses += 1;
+
+		while !flow_control.is_at_low_water(&backlog) && !self.is_closed() {
+			backlog = self
+				.backlog_taken
+				.wait(backlog)
+				.unwrap_or_else(|poisoned| poisoned.into_inner());
+		}
+
+		backlog.paused = false;
+	}
+
+	/// Gets a snapshot of the connection's counters.
+	pub(crate) fn stats(&self) -> Stats {
+		let subscriptions = lock(&self.subscriptions);
+		let backlog = lock(&self.backlog);
+
+		Stats {
+			outstanding_acknowledgements: subscriptions.outstanding_count(),
+			unknown_acknowledgements: subscriptions.unknown_acknowledgements,
+			delivery_paused: self.delivery_paused.load(Ordering::SeqCst),
+			queued_frames: backlog.frames,
+			queued_bytes: backlog.bytes,
+			reading_paused: backlog.paused,
+			reading_pauses: backlog.pauses,
+		}
+	}
+
+	/// Gets the protocol versions offered to the STOMP server in the CONNECT frame.
+	pub(crate) fn offered_versions(&self) -> Vec<String> {
+		split_versions(&self.accept_version)
+	}
+
+	/// Gets the protocol version agreed with the STOMP server, if the handshake has finished.
+	pub(crate) fn version(&self) -> Option<StompVersion> {
+		*lock(&self.version)
+	}
+}
+
+/// Locks a mutex, carrying on even if another thread panicked whilst holding it.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+	mutex
+		.lock()
+		.unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Splits a comma separated list of protocol versions, such as from an accept-version header.
+pub(crate) fn split_versions(versions: &str) -> Vec<String> {
+	versions
+		.split(',')
+		.map(str::trim)
+		.filter(|version| !version.is_empty())
+		.map(str::to_string)
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use crate::{
+		message::Delivery,
+		subscription::AckMode,
+		test_support::{frame_bytes, FakeServer},
+	};
+
+	#[test]
+	fn pauses_delivery_at_max_outstanding_acknowledgements() {
+		let server = FakeServer::silent();
+		let mut connection = server
+			.builder()
+			.max_outstanding_acknowledgements(2)
+			.open()
+			.unwrap();
+		connection
+			.subscribe_with_ack(1, "/queue/a", AckMode::ClientIndividual)
+			.unwrap();
+
+		for message_id in ["1", "2", "3"] {
+			server.write(&frame_bytes(
+				"MESSAGE",
+				&[
+					("destination", "/queue/a"),
+					("message-id", message_id),
+					("subscription", "1"),
+					("ack", message_id),
+				],
+				"",
+			));
+		}
+
+		let mut messages = Vec::new();
+		for _ in 0..2 {
+			match connection.receive().unwrap() {
+				Delivery::Message(message) => messages.push(message),
+				Delivery::Frame(frame) => panic!("Expected a message, got {}", frame.command),
+			}
+		}
+
+		// The third is held back until one is acknowledged
+		assert!(connection.recv_timeout(Duration::from_millis(200)).is_err());
+		let stats = connection.stats();
+		assert!(stats.delivery_paused);
+		assert_eq!(stats.outstanding_acknowledgements, 2);
+
+		messages[0].ack().unwrap();
+
+		let frame = connection.recv_timeout(Duration::from_secs(1)).unwrap();
+		assert_eq!(frame.message_id(), Some("3"));
+	}
+}
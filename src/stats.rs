@@ -0,0 +1,24 @@
+/// Represents a snapshot of a connection's counters.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+	/// The number of received messages that are waiting to be acknowledged.
+	pub outstanding_acknowledgements: usize,
+
+	/// The number of acknowledgements sent for messages that weren't waiting to be acknowledged.
+	pub unknown_acknowledgements: u64,
+
+	/// Whether delivery is paused because too many messages are waiting to be acknowledged.
+	pub delivery_paused: bool,
+
+	/// The number of frames waiting to be taken from the connection.
+	pub queued_frames: usize,
+
+	/// The approximate size of the frames waiting to be taken from the connection.
+	pub queued_bytes: usize,
+
+	/// Whether reading from the server is paused because too many frames are waiting to be taken.
+	pub reading_paused: bool,
+
+	/// The number of times reading from the server has been paused because too many frames were waiting to be taken.
+	pub reading_pauses: u64,
+}
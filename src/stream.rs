@@ -0,0 +1,84 @@
+use std::io::{ErrorKind, Read};
+
+use crate::{
+	error::StompError,
+	frame::{self, Frame, ParseOptions},
+};
+
+/// Reads frames straight from a stream, for iterator-based composition instead of the connection's channel.
+///
+/// Nothing is sent by this, so it suits read-only uses such as replaying a capture, or a stream that
+/// something else writes to. The iterator finishes at the end of the stream or after the first error.
+pub struct FrameStream<R: Read> {
+	reader: R,
+	options: ParseOptions,
+	pending_data: Vec<u8>,
+	finished: bool,
+}
+
+impl<R: Read> FrameStream<R> {
+	/// Wraps a stream, such as a `TcpStream`.
+	pub fn new(reader: R) -> FrameStream<R> {
+		FrameStream::with_options(reader, ParseOptions::default())
+	}
+
+	/// Wraps a stream, parsing frames with the given options.
+	pub fn with_options(reader: R, options: ParseOptions) -> FrameStream<R> {
+		FrameStream {
+			reader,
+			options,
+			pending_data: Vec::new(),
+			finished: false,
+		}
+	}
+
+	/// Gets the wrapped stream back, along with any bytes that were read but not yet parsed.
+	pub fn into_inner(self) -> (R, Vec<u8>) {
+		(self.reader, self.pending_data)
+	}
+
+	/// Reads until the next complete frame, or the end of the stream.
+	fn read_frame(&mut self) -> Result<Option<Frame>, StompError> {
+		let mut receive_buffer = [0; 4096]; // 4 KiB
+
+		loop {
+			// Use up what we already have first
+			if let Some((frame, end_position)) =
+				frame::parse_with_options(&self.pending_data, &self.options)?
+			{
+				self.pending_data.drain(..end_position + 1);
+
+				return Ok(Some(frame));
+			}
+
+			let received_byte_count = match self.reader.read(&mut receive_buffer) {
+				Ok(count) => count,
+				Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+				Err(error) => return Err(error.into()),
+			};
+			if received_byte_count == 0 {
+				return Ok(None); // There's nothing left to read
+			}
+
+			self.pending_data
+				.extend_from_slice(&receive_buffer[..received_byte_count]);
+		}
+	}
+}
+
+impl<R: Read> Iterator for FrameStream<R> {
+	type Item = Result<Frame, StompError>;
+
+	fn next(&mut self) -> Option<Result<Frame, StompError>> {
+		if self.finished {
+			return None;
+		}
+
+		let result = self.read_frame().transpose();
+		if !matches!(result, Some(Ok(_))) {
+			self.finished = true;
+		}
+
+		result
+	}
+}
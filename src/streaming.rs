@@ -0,0 +1,51 @@
+use std::io::{self, Write};
+
+use crate::frame::Frame;
+
+/// Opens somewhere to write the body of a frame, given its command & headers.
+pub type SinkFactory = Box<dyn FnMut(&Frame) -> io::Result<Box<dyn Write + Send>> + Send>;
+
+/// Decides which bodies are streamed to a sink instead of being buffered.
+pub(crate) struct BodyStreaming {
+	pub(crate) min_length: usize,
+	pub(crate) sink_factory: SinkFactory,
+}
+
+/// A body that is partway through being written to its sink.
+pub(crate) struct StreamingBody {
+	pub(crate) frame: Frame,
+	sink: Box<dyn Write + Send>,
+	remaining: usize,
+}
+
+impl StreamingBody {
+	pub(crate) fn new(frame: Frame, sink: Box<dyn Write + Send>, length: usize) -> StreamingBody {
+		StreamingBody {
+			frame,
+			sink,
+			remaining: length,
+		}
+	}
+
+	/// Writes as much of the body as the buffer holds, returning how many bytes were used.
+	pub(crate) fn feed(&mut self, buffer: &[u8]) -> io::Result<usize> {
+		let count = self.remaining.min(buffer.len());
+
+		self.sink.write_all(&buffer[..count])?;
+		self.remaining -= count;
+
+		Ok(count)
+	}
+
+	/// Checks if the whole body has been written.
+	pub(crate) fn is_complete(&self) -> bool {
+		self.remaining == 0
+	}
+
+	/// Flushes the sink, returning the frame the body belonged to.
+	pub(crate) fn finish(mut self) -> io::Result<Frame> {
+		self.sink.flush()?;
+
+		Ok(self.frame)
+	}
+}
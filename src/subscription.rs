@@ -0,0 +1,310 @@
+use std::collections::BTreeMap;
+
+use crate::{destination::Destination, error::StompError, Connection};
+
+/// Represents how messages on a subscription are acknowledged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AckMode {
+	/// The server considers messages acknowledged as soon as they are sent.
+	Auto,
+
+	/// Acknowledging a message also acknowledges every message received before it on the same subscription.
+	Client,
+
+	/// Each message must be acknowledged on its own.
+	ClientIndividual,
+}
+
+impl AckMode {
+	/// Converts the mode to the value of the ack header.
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			AckMode::Auto => "auto",
+			AckMode::Client => "client",
+			AckMode::ClientIndividual => "client-individual",
+		}
+	}
+}
+
+/// Represents a subscription that is currently active on a connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubscriptionInfo {
+	pub id: String,
+	pub destination: String,
+	pub ack_mode: AckMode,
+
+	/// The identifiers of messages that have been received but not yet acknowledged, oldest first.
+	pub outstanding: Vec<String>,
+}
+
+/// Configures a subscription before subscribing to it.
+pub struct SubscriptionBuilder {
+	id: u32,
+	destination: Destination,
+	ack_mode: AckMode,
+	selector: Option<String>,
+	prefetch: Option<u32>,
+	extra_headers: Vec<(String, String)>,
+}
+
+impl SubscriptionBuilder {
+	/// Starts configuring a subscription to a destination, which acknowledges messages automatically.
+	pub fn new(id: u32, destination: impl Into<Destination>) -> SubscriptionBuilder {
+		SubscriptionBuilder {
+			id,
+			destination: destination.into(),
+			ack_mode: AckMode::Auto,
+			selector: None,
+			prefetch: None,
+			extra_headers: Vec::new(),
+		}
+	}
+
+	/// Sets how messages are acknowledged.
+	pub fn ack(mut self, ack_mode: AckMode) -> SubscriptionBuilder {
+		self.ack_mode = ack_mode;
+		self
+	}
+
+	/// Only receives messages matching an SQL-like condition on their headers, such as `priority > 5`.
+	/// ActiveMQ & Artemis support this, whereas other servers ignore it.
+	pub fn selector(mut self, selector: &str) -> SubscriptionBuilder {
+		self.selector = Some(selector.to_string());
+		self
+	}
+
+	/// Asks the server to send at most this many messages before waiting for them to be acknowledged.
+	/// Uses the connection's broker's prefetch header, or ActiveMQ's if there isn't one.
+	/// Subscribing fails with `StompError::UnsupportedByBroker` for brokers without one, such as HornetQ.
+	pub fn prefetch(mut self, count: u32) -> SubscriptionBuilder {
+		self.prefetch = Some(count);
+		self
+	}
+
+	/// Adds any other header to the SUBSCRIBE frame.
+	pub fn header(mut self, name: &str, value: &str) -> SubscriptionBuilder {
+		self.extra_headers
+			.push((name.to_string(), value.to_string()));
+		self
+	}
+
+	/// Subscribes on a connection.
+	pub fn subscribe(self, connection: &mut Connection) -> Result<(), StompError> {
+		let mut headers = Vec::new();
+		if let Some(selector) = self.selector {
+			headers.push(("selector", selector));
+		}
+		if let Some(count) = self.prefetch {
+			headers.push((connection.prefetch_header()?, count.to_string()));
+		}
+		for (name, value) in &self.extra_headers {
+			headers.push((name.as_str(), value.clone()));
+		}
+
+		connection.subscribe_with_headers(self.id, self.destination, self.ack_mode, headers)
+	}
+}
+
+/// A message that is waiting to be acknowledged.
+struct Outstanding {
+	message_id: String,
+	ack_id: Option<String>,
+}
+
+/// A subscription & the messages received on it that are waiting to be acknowledged.
+struct Subscription {
+	destination: String,
+	ack_mode: AckMode,
+	outstanding: Vec<Outstanding>,
+}
+
+/// Keeps track of the active subscriptions on a connection.
+#[derive(Default)]
+pub(crate) struct Subscriptions {
+	subscriptions: BTreeMap<String, Subscription>,
+	pub(crate) unknown_acknowledgements: u64,
+}
+
+impl Subscriptions {
+	/// Starts tracking a new subscription, replacing any previous one with the same identifier.
+	pub(crate) fn add(&mut self, id: &str, destination: &str, ack_mode: AckMode) {
+		self.subscriptions.insert(
+			id.to_string(),
+			Subscription {
+				destination: destination.to_string(),
+				ack_mode,
+				outstanding: Vec::new(),
+			},
+		);
+	}
+
+	/// Stops tracking a subscription, returning whether it existed.
+	pub(crate) fn remove(&mut self, id: &str) -> bool {
+		self.subscriptions.remove(id).is_some()
+	}
+
+	/// Stops tracking every subscription, returning their identifiers.
+	pub(crate) fn clear(&mut self) -> Vec<String> {
+		std::mem::take(&mut self.subscriptions)
+			.into_keys()
+			.collect()
+	}
+
+	/// Gets how a subscription acknowledges messages, if it is being tracked.
+	pub(crate) fn ack_mode(&self, id: &str) -> Option<AckMode> {
+		self.subscriptions
+			.get(id)
+			.map(|subscription| subscription.ack_mode)
+	}
+
+	/// Records that a message was received, if its subscription requires it to be acknowledged.
+	pub(crate) fn received(&mut self, id: &str, message_id: &str, ack_id: Option<&str>) {
+		if let Some(subscription) = self.subscriptions.get_mut(id) {
+			if subscription.ack_mode != AckMode::Auto {
+				subscription.outstanding.push(Outstanding {
+					message_id: message_id.to_string(),
+					ack_id: ack_id.map(str::to_string),
+				});
+			}
+		}
+	}
+
+	/// Records that a message was acknowledged (or not), by either its message or ack identifier.
+	/// In client mode, every message received before it on the same subscription is settled too.
+	/// Returns false, & counts it, if the message isn't waiting to be acknowledged.
+	pub(crate) fn settle(&mut self, id: &str) -> bool {
+		for subscription in self.subscriptions.values_mut() {
+			let position = subscription.outstanding.iter().position(|outstanding| {
+				outstanding.message_id == id || outstanding.ack_id.as_deref() == Some(id)
+			});
+
+			if let Some(position) = position {
+				if subscription.ack_mode == AckMode::Client {
+					subscription.outstanding.drain(..=position);
+				} else {
+					subscription.outstanding.remove(position);
+				}
+
+				return true;
+			}
+		}
+
+		self.unknown_acknowledgements += 1;
+
+		false
+	}
+
+	/// Finds which subscription a message waiting to be acknowledged was received on, along with its ack identifier.
+	pub(crate) fn find(&self, message_id: &str) -> Option<(String, Option<String>)> {
+		self.subscriptions.iter().find_map(|(id, subscription)| {
+			subscription
+				.outstanding
+				.iter()
+				.find(|outstanding| outstanding.message_id == message_id)
+				.map(|outstanding| (id.clone(), outstanding.ack_id.clone()))
+		})
+	}
+
+	/// Gets the identifiers of messages waiting to be acknowledged on a subscription, oldest first.
+	pub(crate) fn outstanding(&self, id: &str) -> Vec<String> {
+		self.subscriptions
+			.get(id)
+			.map(|subscription| {
+				subscription
+					.outstanding
+					.iter()
+					.map(|outstanding| outstanding.message_id.clone())
+					.collect()
+			})
+			.unwrap_or_default()
+	}
+
+	/// Gets the number of messages waiting to be acknowledged, across all subscriptions.
+	pub(crate) fn outstanding_count(&self) -> usize {
+		self.subscriptions
+			.values()
+			.map(|subscription| subscription.outstanding.len())
+			.sum()
+	}
+
+	/// Gets a snapshot of every active subscription.
+	pub(crate) fn list(&self) -> Vec<SubscriptionInfo> {
+		self.subscriptions
+			.iter()
+			.map(|(id, subscription)| SubscriptionInfo {
+				id: id.clone(),
+				destination: subscription.destination.clone(),
+				ack_mode: subscription.ack_mode,
+				outstanding: subscription
+					.outstanding
+					.iter()
+					.map(|outstanding| outstanding.message_id.clone())
+					.collect(),
+			})
+			.collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use super::*;
+	use crate::{broker::Broker, test_support::FakeServer};
+
+	#[test]
+	fn prefetch_uses_the_brokers_header() {
+		let server = FakeServer::silent();
+		let mut connection = server.connect().with_broker(Broker::RabbitMQ);
+
+		SubscriptionBuilder::new(1, "/queue/a")
+			.ack(AckMode::Client)
+			.prefetch(10)
+			.subscribe(&mut connection)
+			.unwrap();
+
+		let subscribe = server.next_frame(Duration::from_secs(1)).unwrap();
+		assert_eq!(subscribe.get_header("prefetch-count"), Some("10"));
+		assert_eq!(subscribe.get_header("ack"), Some("client"));
+	}
+
+	#[test]
+	fn prefetch_defaults_to_activemqs_header() {
+		let server = FakeServer::silent();
+		let mut connection = server.connect();
+
+		connection
+			.subscribe_prefetch_n(1, "/queue/a", AckMode::Client, 5)
+			.unwrap();
+
+		let subscribe = server.next_frame(Duration::from_secs(1)).unwrap();
+		assert_eq!(subscribe.get_header("activemq.prefetchSize"), Some("5"));
+	}
+
+	#[test]
+	fn prefetch_fails_for_brokers_without_a_header() {
+		let server = FakeServer::silent();
+		let mut connection = server.connect().with_broker(Broker::HornetQ);
+
+		let built = SubscriptionBuilder::new(1, "/queue/a")
+			.prefetch(10)
+			.subscribe(&mut connection);
+		let direct = connection.subscribe_prefetch_n(2, "/queue/a", AckMode::Client, 10);
+
+		for result in [built, direct] {
+			assert!(
+				matches!(
+					&result,
+					Err(StompError::UnsupportedByBroker {
+						broker: Broker::HornetQ,
+						..
+					})
+				),
+				"{:?}",
+				result
+			);
+		}
+		assert!(server.next_frame(Duration::from_millis(100)).is_none());
+		assert!(connection.subscriptions().is_empty());
+	}
+}
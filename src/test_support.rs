@@ -0,0 +1,217 @@
+use std::{
+	io::{Read, Write},
+	net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+	sync::{
+		atomic::{AtomicBool, AtomicUsize, Ordering},
+		mpsc::{channel, Receiver},
+		Arc, Condvar, Mutex,
+	},
+	thread::{sleep, spawn},
+	time::{Duration, Instant},
+};
+
+use crate::{builder::ConnectionBuilder, frame, Connection};
+
+/// What a fake server does in reply to a frame it receives.
+pub(crate) enum Reply {
+	/// Writes bytes, such as a whole frame.
+	Bytes(Vec<u8>),
+
+	/// Closes the connection.
+	Close,
+}
+
+/// Decides the replies to each frame a fake server receives.
+pub(crate) type Script = Box<dyn FnMut(&frame::Frame) -> Vec<Reply> + Send>;
+
+/// A STOMP server on the loopback interface that accepts one connection & replies to it from a script.
+/// Header values of the frames it receives are left as they were sent, so tests can check how they were escaped.
+pub(crate) struct FakeServer {
+	address: SocketAddr,
+	received: Receiver<frame::Frame>,
+	stream: Arc<(Mutex<Option<TcpStream>>, Condvar)>,
+	heart_beats: Arc<AtomicUsize>,
+	byte_count: Arc<AtomicUsize>,
+	closed: Arc<AtomicBool>,
+}
+
+impl FakeServer {
+	/// Starts listening, replying to every frame with the script.
+	pub(crate) fn start(script: Script) -> FakeServer {
+		FakeServer::start_on(TcpListener::bind("127.0.0.1:0").unwrap(), script)
+	}
+
+	/// Starts listening on the IPv6 loopback interface, or None if IPv6 is unavailable.
+	pub(crate) fn start_ipv6(script: Script) -> Option<FakeServer> {
+		let listener = TcpListener::bind("[::1]:0").ok()?;
+
+		Some(FakeServer::start_on(listener, script))
+	}
+
+	/// Starts replying to every frame with the script, on the first connection the listener accepts.
+	fn start_on(listener: TcpListener, mut script: Script) -> FakeServer {
+		let address = listener.local_addr().unwrap();
+		let (frame_sender, received) = channel();
+		let stream = Arc::new((Mutex::new(None), Condvar::new()));
+		let heart_beats = Arc::new(AtomicUsize::new(0));
+		let byte_count = Arc::new(AtomicUsize::new(0));
+		let closed = Arc::new(AtomicBool::new(false));
+
+		let shared_stream = stream.clone();
+		let shared_heart_beats = heart_beats.clone();
+		let shared_byte_count = byte_count.clone();
+		let shared_closed = closed.clone();
+		let options = frame::ParseOptions {
+			literal_header_values: true,
+			..frame::ParseOptions::default()
+		};
+		spawn(move || {
+			let (mut tcp_stream, _) = listener.accept().unwrap();
+			tcp_stream.set_nodelay(true).unwrap(); // Replies are written a frame at a time
+			*shared_stream.0.lock().unwrap() = Some(tcp_stream.try_clone().unwrap());
+			shared_stream.1.notify_all();
+
+			let mut receive_buffer = [0; 4096];
+			let mut pending_data = Vec::new();
+			loop {
+				let count = tcp_stream.read(&mut receive_buffer).unwrap_or(0);
+				if count == 0 {
+					shared_closed.store(true, Ordering::SeqCst);
+					return;
+				}
+				shared_byte_count.fetch_add(count, Ordering::SeqCst);
+				pending_data.extend_from_slice(&receive_buffer[..count]);
+
+				loop {
+					// Count heart-beats before the next frame
+					let heart_beat_length = frame::heart_beat_length(&pending_data);
+					let heart_beat_count = pending_data[..heart_beat_length]
+						.iter()
+						.filter(|&&byte| byte == b'\n')
+						.count();
+					shared_heart_beats.fetch_add(heart_beat_count, Ordering::SeqCst);
+					pending_data.drain(..heart_beat_length);
+
+					let parsed = frame::parse_with_options(&pending_data, &options).unwrap();
+					if parsed.is_none() {
+						break;
+					}
+					let (frame, end_position) = parsed.unwrap();
+					pending_data.drain(..end_position + 1);
+
+					for reply in script(&frame) {
+						match reply {
+							Reply::Bytes(bytes) => tcp_stream.write_all(&bytes).unwrap(),
+							Reply::Close => {
+								let _ = tcp_stream.shutdown(Shutdown::Both); // Another copy is kept for writing
+								let _ = frame_sender.send(frame);
+								return;
+							}
+						}
+					}
+					let _ = frame_sender.send(frame);
+				}
+			}
+		});
+
+		FakeServer {
+			address,
+			received,
+			stream,
+			heart_beats,
+			byte_count,
+			closed,
+		}
+	}
+
+	/// Starts listening, never replying to anything.
+	pub(crate) fn silent() -> FakeServer {
+		FakeServer::start(Box::new(|_| Vec::new()))
+	}
+
+	/// The address the server is listening on.
+	pub(crate) fn address(&self) -> SocketAddr {
+		self.address
+	}
+
+	/// Starts configuring a connection to this server.
+	pub(crate) fn builder(&self) -> ConnectionBuilder {
+		ConnectionBuilder::new(&self.address.ip().to_string(), self.address.port())
+			.operation_timeout(Duration::from_secs(2))
+	}
+
+	/// Opens a connection to this server, without sending anything.
+	pub(crate) fn connect(&self) -> Connection {
+		self.builder().open().unwrap()
+	}
+
+	/// Waits for the next frame the server received, or None if none arrives in time.
+	pub(crate) fn next_frame(&self, timeout: Duration) -> Option<frame::Frame> {
+		self.received.recv_timeout(timeout).ok()
+	}
+
+	/// Writes bytes to the connection without being prompted by a frame, once something has connected.
+	pub(crate) fn write(&self, bytes: &[u8]) {
+		let (stream, connected) = &*self.stream;
+		let (mut stream, _) = connected
+			.wait_timeout_while(stream.lock().unwrap(), Duration::from_secs(5), |stream| {
+				stream.is_none()
+			})
+			.unwrap();
+
+		stream
+			.as_mut()
+			.expect("Nothing has connected yet")
+			.write_all(bytes)
+			.unwrap();
+	}
+
+	/// Waits for the connection to be closed from the other end, returning false if it isn't in time.
+	pub(crate) fn wait_for_eof(&self, timeout: Duration) -> bool {
+		let deadline = Instant::now() + timeout;
+		while !self.closed.load(Ordering::SeqCst) {
+			if Instant::now() >= deadline {
+				return false;
+			}
+			sleep(Duration::from_millis(10));
+		}
+
+		true
+	}
+
+	/// Gets how many bytes have been received, including heart-beats.
+	pub(crate) fn byte_count(&self) -> usize {
+		self.byte_count.load(Ordering::SeqCst)
+	}
+
+	/// Gets how many heart-beats have been received.
+	pub(crate) fn heart_beat_count(&self) -> usize {
+		self.heart_beats.load(Ordering::SeqCst)
+	}
+}
+
+/// Creates the bytes of a frame with the given command, headers & body.
+pub(crate) fn frame_bytes(command: &str, headers: &[(&str, &str)], body: &str) -> Vec<u8> {
+	frame::create_bytes(
+		command,
+		Some(headers.to_vec()),
+		(!body.is_empty()).then_some(body.as_bytes()),
+	)
+}
+
+/// Replies to a frame with its receipt, if it asked for one.
+pub(crate) fn receipt_for(frame: &frame::Frame) -> Vec<Reply> {
+	match frame.get_header("receipt") {
+		Some(receipt) => vec![Reply::Bytes(frame_bytes(
+			"RECEIPT",
+			&[("receipt-id", receipt)],
+			"",
+		))],
+		None => Vec::new(),
+	}
+}
+
+/// The CONNECTED frame for STOMP 1.2.
+pub(crate) fn connected() -> Reply {
+	Reply::Bytes(frame_bytes("CONNECTED", &[("version", "1.2")], ""))
+}
@@ -0,0 +1,41 @@
+use std::{
+	collections::hash_map::RandomState,
+	hash::{BuildHasher, Hasher},
+	sync::atomic::{AtomicU64, Ordering},
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Makes every identifier unique, even if the clock doesn't move between them.
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Generates a random (version 4) UUID, such as `0b9ad5f4-7a1c-4e2b-9c3d-5f6e7a8b9c0d`.
+pub(crate) fn new_v4() -> String {
+	let high = random_u64();
+	let low = random_u64();
+
+	// Mark it as version 4, variant 1
+	let high = (high & 0xFFFF_FFFF_FFFF_0FFF) | 0x0000_0000_0000_4000;
+	let low = (low & 0x3FFF_FFFF_FFFF_FFFF) | 0x8000_0000_0000_0000;
+
+	format!(
+		"{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+		high >> 32,
+		(high >> 16) & 0xFFFF,
+		high & 0xFFFF,
+		low >> 48,
+		low & 0xFFFF_FFFF_FFFF
+	)
+}
+
+/// Gets 64 random bits, using the randomly keyed hasher from the standard library.
+fn random_u64() -> u64 {
+	let nanoseconds = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map_or(0, |duration| duration.as_nanos());
+
+	let mut hasher = RandomState::new().build_hasher();
+	hasher.write_u128(nanoseconds);
+	hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+
+	hasher.finish()
+}
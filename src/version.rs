@@ -0,0 +1,52 @@
+use std::{fmt, str::FromStr};
+
+use crate::error::StompError;
+
+/// Represents a version of the STOMP protocol, ordered from oldest to newest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum StompVersion {
+	V1_0,
+	V1_1,
+	V1_2,
+}
+
+impl StompVersion {
+	/// Converts the version to how it appears in headers.
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			StompVersion::V1_0 => "1.0",
+			StompVersion::V1_1 => "1.1",
+			StompVersion::V1_2 => "1.2",
+		}
+	}
+
+	/// Gets the version agreed in a CONNECTED frame's version header, which is absent for STOMP 1.0.
+	pub fn from_header(value: Option<&str>) -> Option<StompVersion> {
+		match value {
+			None | Some("1.0") => Some(StompVersion::V1_0),
+			Some("1.1") => Some(StompVersion::V1_1),
+			Some("1.2") => Some(StompVersion::V1_2),
+			Some(_) => None,
+		}
+	}
+}
+
+impl fmt::Display for StompVersion {
+	fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+		write!(formatter, "{}", self.as_str())
+	}
+}
+
+impl FromStr for StompVersion {
+	type Err = StompError;
+
+	/// Parses a version such as `1.2`, ignoring surrounding whitespace.
+	fn from_str(value: &str) -> Result<StompVersion, StompError> {
+		match value.trim() {
+			"1.0" => Ok(StompVersion::V1_0),
+			"1.1" => Ok(StompVersion::V1_1),
+			"1.2" => Ok(StompVersion::V1_2),
+			_ => Err(StompError::UnknownVersion(value.to_string())),
+		}
+	}
+}